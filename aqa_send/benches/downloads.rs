@@ -0,0 +1,163 @@
+//! Throughput and memory use of many concurrent downloads of a large file.
+//!
+//! Run with `cargo bench --bench downloads -- [downloads] [file size in MiB]`, which defaults to
+//! 100 downloads of a 256 MiB file. Memory use is read from `/proc/self/status`, so it's only
+//! reported on Linux.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{ensure, Result};
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use hyper::{Body, Method, Request, StatusCode};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use aqa_send::db_stuff::FileEntry;
+use aqa_send::files::{init_app_directory_structure, DB_DIR};
+use aqa_send::headers::{DownloadCount, Lifetime, Visibility};
+use aqa_send::{db, AqaService};
+
+const DEFAULT_DOWNLOADS: usize = 100;
+const DEFAULT_FILE_SIZE_MIB: u64 = 256;
+
+const MIB: u64 = 1024 * 1024;
+
+fn main() -> Result<()> {
+	// cargo passes `--bench` to benchmarks without a harness
+	let mut args = std::env::args().skip(1).filter(|arg| arg != "--bench");
+	let downloads = match args.next() {
+		Some(downloads) => downloads.parse()?,
+		None => DEFAULT_DOWNLOADS,
+	};
+	let file_size = match args.next() {
+		Some(file_size) => file_size.parse::<u64>()? * MIB,
+		None => DEFAULT_FILE_SIZE_MIB * MIB,
+	};
+
+	tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.build()?
+		.block_on(run(downloads, file_size))
+}
+
+async fn run(downloads: usize, file_size: u64) -> Result<()> {
+	let db_dir = tempfile::tempdir()?;
+	init_app_directory_structure(db_dir.path())?;
+	let db = db::init(db_dir.path())?;
+
+	let uuid = Uuid::new_v4();
+	let file_entry = FileEntry {
+		filename: String::from("large_file"),
+		content_type: String::from("application/octet-stream"),
+		uploader_uuid: None,
+		download_count_type: DownloadCount::Infinite,
+		download_count: 0,
+		size: Some(file_size),
+		visibility: Visibility::Public,
+		password: None,
+		shared_with: Vec::new(),
+		shared_with_groups: Vec::new(),
+		bandwidth_limit: None,
+		lifetime: Lifetime::Infinite,
+		upload_date: SystemTime::now(),
+	};
+	let file_path = db_dir
+		.path()
+		.join(DB_DIR)
+		.join(file_entry.download_count_type.to_string())
+		.join(uuid.to_string());
+	let mut file = tokio::fs::File::create(&file_path).await?;
+	let chunk: Vec<u8> = (0..MIB).map(|idx| idx as u8).collect();
+	for _ in 0..file_size / MIB {
+		file.write_all(&chunk).await?;
+	}
+	file.flush().await?;
+	drop(file);
+	db.put(uuid, file_entry).await;
+
+	let peak_rss = Arc::new(AtomicU64::new(0));
+	let baseline_rss = rss().unwrap_or_default();
+	let sampler = tokio::spawn({
+		let peak_rss = Arc::clone(&peak_rss);
+		async move {
+			loop {
+				if let Some(rss) = rss() {
+					peak_rss.fetch_max(rss, Ordering::Relaxed);
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		}
+	});
+
+	println!(
+		"Downloading a {} MiB file {downloads} times concurrently",
+		file_size / MIB
+	);
+	let start = Instant::now();
+	let tasks = (0..downloads)
+		.map(|_| {
+			let mut aqa_service = AqaService::new(db.clone());
+			tokio::spawn(async move {
+				let request = Request::builder()
+					.uri(format!("/api/download/{uuid}"))
+					.method(Method::GET)
+					.body(Body::empty())?;
+				let mut response = aqa_service.call(request).await?;
+				ensure!(response.status() == StatusCode::OK, "{}", response.status());
+
+				let mut received = 0;
+				while let Some(chunk) = response.body_mut().data().await {
+					received += chunk?.len() as u64;
+				}
+				Ok(received)
+			})
+		})
+		.collect::<Vec<_>>();
+
+	for task in tasks {
+		let received = task.await??;
+		ensure!(
+			received == file_size,
+			"received {received} of {file_size} bytes"
+		);
+	}
+	let elapsed = start.elapsed();
+	sampler.abort();
+
+	let total = file_size * downloads as u64;
+	println!(
+		"Sent {} MiB in {:.2?} ({:.0} MiB/s)",
+		total / MIB,
+		elapsed,
+		total as f64 / MIB as f64 / elapsed.as_secs_f64()
+	);
+	if baseline_rss > 0 {
+		let peak_rss = peak_rss.load(Ordering::Relaxed);
+		let used = peak_rss.saturating_sub(baseline_rss);
+		println!(
+			"Peak RSS {:.1} MiB, {:.1} MiB over the baseline ({:.0} KiB per download)",
+			peak_rss as f64 / MIB as f64,
+			used as f64 / MIB as f64,
+			used as f64 / 1024.0 / downloads as f64
+		);
+	}
+
+	Ok(())
+}
+
+/// Resident set size of the process in bytes
+fn rss() -> Option<u64> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	let rss_kib: u64 = status
+		.lines()
+		.find_map(|line| line.strip_prefix("VmRSS:"))?
+		.trim()
+		.strip_suffix("kB")?
+		.trim()
+		.parse()
+		.ok()?;
+	Some(rss_kib * 1024)
+}
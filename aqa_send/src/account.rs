@@ -1,13 +1,14 @@
-use crate::cookie::parse_cookie;
+use crate::audit::user_agent;
+use crate::context::RequestContext;
 use crate::db_stuff::AccountType;
 use crate::error::{ErrorContentType, Field, IntoHandlerError};
 use crate::multipart::{self, Multipart, MultipartError};
-use crate::{Account, AuthorizedUsers, Db, HandlerError, HttpHandlerError};
+use crate::sessions::{session_cookie, CLEAR_SESSION_COOKIE};
+use crate::{Account, Db, HandlerError, HttpHandlerError};
 
 use crate::db::RegistrationCode;
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use hyper::http::HeaderValue;
-use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use hyper::{Body, Request, Response, StatusCode};
 use log::debug;
 use serde::{Deserialize, Serialize};
 use std::iter::Iterator;
@@ -74,9 +75,10 @@ const MAX_REQUEST_BODY_SIZE: usize = 1024 * 5; // 5 KB
 pub async fn login(
 	req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<LoginError>> {
 	let (parts, body): (_, Body) = req.into_parts();
+	let user_agent = user_agent(&parts.headers);
 
 	let boundary = multipart::get_boundary_from_req(parts).map_err(LoginError::from)?;
 	debug!("Boundary: {}", boundary);
@@ -126,8 +128,10 @@ pub async fn login(
 		.map_err(|_| LoginError::LoginFail)?;
 
 	debug!("Generating session token");
-	let session_token = Uuid::new_v4();
-	authorized_users.insert(session_token, account.uuid);
+	let settings = &db.config.settings;
+	let session_token =
+		db.authorized_users()
+			.create_session(account.uuid, ctx.client_ip, user_agent, settings);
 
 	drop(accounts_guard);
 
@@ -135,43 +139,57 @@ pub async fn login(
 		.status(StatusCode::CREATED)
 		.header(
 			"Set-Cookie",
-			format!("session={}; Secure; HttpOnly; SameSite=None", session_token),
+			session_cookie(&session_token, settings.session_cookie_max_age()),
 		)
 		.body(Body::empty())?)
 }
 
 #[derive(Debug, Error)]
 pub enum LogoutError {
-	#[error("Unsupported payload format")]
-	ExpectedJson,
-
-	#[error("Username is missing from request body")]
-	ExpectedUsername,
-
-	#[error("Password is missing from request body")]
-	ExpectedPassword,
+	#[error("You're not logged in")]
+	NotLoggedIn,
+}
 
-	#[error("Request body is too big")]
-	BodyTooBig,
+impl HttpHandlerError for LogoutError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::NotLoggedIn => StatusCode::UNAUTHORIZED,
+		}
+	}
 
-	#[error("Invalid username or password")]
-	LoginFail,
+	fn user_presentable(&self) -> bool {
+		true
+	}
 
-	#[error("Hash problem")]
-	PasswordHash,
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
 }
 
-impl HttpHandlerError for LogoutError {}
-
+/// Ends the current session and removes the session cookie. A session that already expired
+/// only gets its cookie removed.
 pub async fn logout(
 	_req: Request<Body>,
-	_db: Db,
-	_authorized_users: AuthorizedUsers,
+	db: Db,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<LogoutError>> {
-	todo!()
+	match ctx.session() {
+		Ok(Some(session)) => {
+			debug!("Logging out session of {}", session.account.uuid);
+			db.authorized_users().log_out(&session.token);
+			db.save_sessions().await;
+		}
+		Ok(None) => return Err(LogoutError::NotLoggedIn.into()),
+		Err(err) => debug!("Logging out with an invalid session: {err}"),
+	}
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.header("Set-Cookie", CLEAR_SESSION_COOKIE)
+		.body(Body::empty())?)
 }
 
-#[derive(Debug, Error)]
+#[derive(Clone, Debug, Error)]
 pub enum AuthError {
 	#[error("{0:?} must only contain visible ascii characters")]
 	AsciiOnly(Field),
@@ -205,40 +223,6 @@ impl HttpHandlerError for AuthError {
 	}
 }
 
-pub async fn get_logged_in_user(
-	headers: &HeaderMap<HeaderValue>,
-	db: Db,
-	authorized_users: AuthorizedUsers,
-) -> Result<Option<Account>, AuthError> {
-	match headers.get("Cookie") {
-		Some(cookie) => {
-			let cookie_header = cookie
-				.to_str()
-				.map_err(|_| AuthError::AsciiOnly(Field::Cookie))?;
-			let (_, cookies) =
-				parse_cookie(cookie_header).map_err(|_| AuthError::Malformed(Field::Cookie))?;
-			debug!("Cookies: {cookies:?}");
-			match cookies.get("session") {
-				Some(session_cookie) => {
-					let session_cookie: Uuid = session_cookie.parse()?;
-
-					let user_uuid = authorized_users
-						.get_user_uuid(&session_cookie)
-						.ok_or(AuthError::SessionExpired)?;
-					debug!("Getting user with uuid {user_uuid}");
-					Ok(Some(
-						db.get_account(&user_uuid)
-							.await
-							.ok_or(AuthError::UnknownUser)?,
-					))
-				}
-				None => Ok(None),
-			}
-		}
-		None => Ok(None),
-	}
-}
-
 #[derive(Debug, Error)]
 pub enum CreateRegistrationCodeError {
 	#[error(transparent)]
@@ -274,13 +258,13 @@ impl HttpHandlerError for CreateRegistrationCodeError {
 }
 
 pub async fn create_registration_code(
-	req: Request<Body>,
+	_req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 	account_kind: AccountType,
 ) -> Result<Response<Body>, HandlerError<CreateRegistrationCodeError>> {
-	let current_user = get_logged_in_user(req.headers(), db.clone(), authorized_users.clone())
-		.await
+	let current_user = ctx
+		.user()
 		.into_handler_error()?
 		.ok_or(CreateRegistrationCodeError::Unauthorized)?;
 
@@ -358,9 +342,10 @@ pub struct CreateAccountResponse {
 pub async fn create_account_from_registration_code(
 	req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<CreateAccountFromRegistrationCodeError>> {
 	let (parts, body) = req.into_parts();
+	let user_agent = user_agent(&parts.headers);
 
 	let boundary = multipart::get_boundary_from_req(parts)
 		.map_err(CreateAccountFromRegistrationCodeError::from)?;
@@ -429,14 +414,16 @@ pub async fn create_account_from_registration_code(
 	let response_body = serde_json::to_string_pretty(&new_account).unwrap();
 
 	debug!("Generating session token");
-	let session_token = Uuid::new_v4();
-	authorized_users.insert(session_token, new_account.uuid);
+	let settings = &db.config.settings;
+	let session_token =
+		db.authorized_users()
+			.create_session(new_account.uuid, ctx.client_ip, user_agent, settings);
 
 	Ok(Response::builder()
 		.status(StatusCode::CREATED)
 		.header(
 			"Set-Cookie",
-			format!("session={}; Secure; HttpOnly", session_token),
+			session_cookie(&session_token, settings.session_cookie_max_age()),
 		)
 		.header("Content-Type", "application/json")
 		.body(Body::from(response_body))?)
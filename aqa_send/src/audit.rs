@@ -0,0 +1,288 @@
+//! Log of downloads of every entry, readable by its uploader
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::Stream;
+use hyper::header::USER_AGENT;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::{Account, AccountType};
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+
+/// Longer user agents are cut, so that clients can't blow up the log
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Download events by entry uuid. Events are kept after the entry is removed, until they're older
+/// than [crate::settings::Settings::download_log_retention_secs].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DownloadLog {
+	pub entries: HashMap<Uuid, EntryDownloads>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EntryDownloads {
+	/// Uploader of the entry, who can read the events even after the entry is gone
+	pub uploader: Option<Uuid>,
+	pub events: Vec<DownloadEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadEvent {
+	pub date: SystemTime,
+	pub client_ip: Option<IpAddr>,
+	pub user_agent: Option<String>,
+	/// Logged in account of the downloader
+	pub account: Option<Uuid>,
+	pub username: Option<String>,
+	pub bytes_sent: u64,
+	pub status: DownloadStatus,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStatus {
+	/// The whole response was sent
+	Completed,
+	/// The client went away before the response was sent
+	Interrupted,
+	/// Reading the file failed
+	Failed,
+}
+
+impl DownloadLog {
+	pub fn record(&mut self, file_uuid: Uuid, uploader: Option<Uuid>, event: DownloadEvent) {
+		let entry = self.entries.entry(file_uuid).or_default();
+		entry.uploader = uploader;
+		entry.events.push(event);
+	}
+
+	/// Removes events older than `retention`
+	pub fn remove_stale(&mut self, retention: Duration, now: SystemTime) {
+		self.entries.retain(|_file_uuid, entry| {
+			entry.events.retain(|event| {
+				now.duration_since(event.date)
+					.map(|age| age <= retention)
+					.unwrap_or(true)
+			});
+			!entry.events.is_empty()
+		});
+	}
+}
+
+/// `User-Agent` of the request, cut to [MAX_USER_AGENT_LEN] characters
+pub fn user_agent(headers: &HeaderMap) -> Option<String> {
+	headers
+		.get(USER_AGENT)
+		.map(|user_agent| String::from_utf8_lossy(user_agent.as_bytes()))
+		.map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect())
+}
+
+impl DownloadEvent {
+	/// Event of a download that's about to start
+	pub fn new(headers: &HeaderMap, client_ip: Option<IpAddr>, account: Option<&Account>) -> Self {
+		DownloadEvent {
+			date: SystemTime::now(),
+			client_ip,
+			user_agent: user_agent(headers),
+			account: account.map(|account| account.uuid),
+			username: account.map(|account| account.username.clone()),
+			bytes_sent: 0,
+			status: DownloadStatus::Interrupted,
+		}
+	}
+}
+
+/// Counts bytes of a download and records it in the log once the stream ends or is dropped
+pub struct Audited<S> {
+	inner: S,
+	record: Option<DownloadRecord>,
+}
+
+struct DownloadRecord {
+	db: Db,
+	file_uuid: Uuid,
+	uploader: Option<Uuid>,
+	event: DownloadEvent,
+}
+
+impl<S> Audited<S> {
+	pub fn new(
+		inner: S,
+		db: Db,
+		file_uuid: Uuid,
+		uploader: Option<Uuid>,
+		event: DownloadEvent,
+	) -> Self {
+		Audited {
+			inner,
+			record: Some(DownloadRecord {
+				db,
+				file_uuid,
+				uploader,
+				event,
+			}),
+		}
+	}
+
+	fn finish(&mut self, status: DownloadStatus) {
+		if let Some(mut record) = self.record.take() {
+			record.event.status = status;
+			record
+				.db
+				.record_download(record.file_uuid, record.uploader, record.event);
+		}
+	}
+}
+
+impl<S, E> Stream for Audited<S>
+where
+	S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+	type Item = Result<Bytes, E>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+		let item = ready!(Pin::new(&mut this.inner).poll_next(cx));
+		match item {
+			Some(Ok(ref chunk)) => {
+				if let Some(record) = this.record.as_mut() {
+					record.event.bytes_sent += chunk.len() as u64;
+				}
+			}
+			Some(Err(_)) => this.finish(DownloadStatus::Failed),
+			None => this.finish(DownloadStatus::Completed),
+		}
+		Poll::Ready(item)
+	}
+}
+
+impl<S> Drop for Audited<S> {
+	fn drop(&mut self) {
+		self.finish(DownloadStatus::Interrupted);
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+	#[error("File id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("You can only see downloads of your own files")]
+	NotAuthorized,
+
+	#[error("File id not found or not present")]
+	NotFound,
+}
+
+impl HttpHandlerError for AuditError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotLoggedIn => StatusCode::FORBIDDEN,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+/// Lists download events of the entry, oldest first. Uploader and admins only. Works for removed
+/// entries as long as their events are retained.
+pub async fn list_downloads(
+	uuid: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<AuditError>> {
+	let file_uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(AuditError::NotLoggedIn)?;
+
+	let entry_downloads = db.download_events(&file_uuid);
+	let can_read = match db.get(&file_uuid).await {
+		Some(file_entry) => file_entry.can_manage(&current_user),
+		None => {
+			let Some(ref entry_downloads) = entry_downloads else {
+				return Err(AuditError::NotFound.into());
+			};
+			matches!(current_user.acc_type, AccountType::Admin)
+				|| entry_downloads.uploader == Some(current_user.uuid)
+		}
+	};
+	if !can_read {
+		return Err(AuditError::NotAuthorized.into());
+	}
+
+	let events = entry_downloads
+		.map(|entry_downloads| entry_downloads.events)
+		.unwrap_or_default();
+	json_response(StatusCode::OK, &events)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stale_events_are_removed() {
+		let now = SystemTime::now();
+		let event = |age: u64| DownloadEvent {
+			date: now - Duration::from_secs(age),
+			client_ip: None,
+			user_agent: None,
+			account: None,
+			username: None,
+			bytes_sent: 0,
+			status: DownloadStatus::Completed,
+		};
+		let old_entry = Uuid::new_v4();
+		let new_entry = Uuid::new_v4();
+
+		let mut log = DownloadLog::default();
+		log.record(old_entry, None, event(200));
+		log.record(new_entry, None, event(200));
+		log.record(new_entry, None, event(50));
+
+		log.remove_stale(Duration::from_secs(100), now);
+		assert!(!log.entries.contains_key(&old_entry));
+		assert_eq!(log.entries[&new_entry].events.len(), 1);
+	}
+}
@@ -0,0 +1,180 @@
+//! State every request needs, resolved once by [`AqaService`](crate::AqaService) before the
+//! handler runs
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use hyper::{Body, HeaderMap, Request, Response};
+use log::debug;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::cookie::parse_cookie;
+use crate::db::Db;
+use crate::db_stuff::Account;
+use crate::error::Field;
+use crate::headers::REQUEST_ID;
+use crate::resolve_client_ip;
+use crate::sessions::{session_cookie, CLEAR_SESSION_COOKIE};
+
+/// Longest request ID accepted from a trusted proxy
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+	/// Sent back in the `x-request-id` header and included in the logs
+	pub request_id: String,
+	pub client_ip: Option<IpAddr>,
+	/// `Origin` header of a cross origin request
+	pub origin: Option<HeaderValue>,
+	session: Result<Option<Session>, AuthError>,
+}
+
+/// Logged in account and the token of its session
+#[derive(Clone, Debug)]
+pub struct Session {
+	pub token: Uuid,
+	pub account: Account,
+	/// `Max-Age` of the session cookie, if it's due to be renewed
+	pub renew_cookie: Option<Duration>,
+}
+
+impl RequestContext {
+	pub async fn resolve(req: &Request<Body>, client_addr: Option<SocketAddr>, db: &Db) -> Self {
+		let headers = req.headers();
+		let trusted_proxies = &db.config.settings.trusted_proxies;
+		let peer_ip = client_addr.map(|addr| addr.ip());
+
+		// Only proxies are trusted to name the request, so their logs can be matched with ours
+		let request_id = peer_ip
+			.filter(|peer_ip| trusted_proxies.contains(peer_ip))
+			.and_then(|_| headers.get(REQUEST_ID))
+			.and_then(|hv| hv.to_str().ok())
+			.filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+			.map(ToOwned::to_owned)
+			.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+		let client_ip = resolve_client_ip(peer_ip, headers, trusted_proxies);
+		RequestContext {
+			request_id,
+			client_ip,
+			origin: headers.get("origin").cloned(),
+			session: resolve_session(headers, client_ip, db).await,
+		}
+	}
+
+	/// Account of the logged in user. Fails if the request carries an invalid or expired
+	/// session cookie.
+	pub fn user(&self) -> Result<Option<Account>, AuthError> {
+		self.session()
+			.map(|session| session.map(|session| session.account))
+	}
+
+	/// Account of the logged in user, for resources that are available to anonymous users too.
+	/// See [`Self::session_or_anonymous`].
+	pub fn user_or_anonymous(&self) -> Result<Option<Account>, AuthError> {
+		self.session_or_anonymous()
+			.map(|session| session.map(|session| session.account))
+	}
+
+	pub fn session(&self) -> Result<Option<Session>, AuthError> {
+		self.session.clone()
+	}
+
+	/// Session of the logged in user, for resources that are available to anonymous users too.
+	/// An expired or unknown session counts as no session, its cookie gets removed by
+	/// [`Self::finish`].
+	pub fn session_or_anonymous(&self) -> Result<Option<Session>, AuthError> {
+		if self.session_rejected() {
+			return Ok(None);
+		}
+		self.session()
+	}
+
+	fn session_rejected(&self) -> bool {
+		matches!(
+			self.session,
+			Err(AuthError::SessionExpired | AuthError::UnknownUser)
+		)
+	}
+
+	/// Headers added to every response: request ID, CORS, renewal of the session cookie and
+	/// removal of a stale one
+	pub fn finish(&self, resp: &mut Response<Body>) {
+		let headers = resp.headers_mut();
+		if let Ok(request_id) = HeaderValue::from_str(&self.request_id) {
+			headers.insert(REQUEST_ID, request_id);
+		}
+
+		if let Some(ref origin) = self.origin {
+			headers.insert("Access-Control-Allow-Origin", origin.clone());
+			headers.insert(
+				"Access-Control-Allow-Credentials",
+				HeaderValue::from_static("true"),
+			);
+			headers.append(
+				"Access-Control-Expose-Headers",
+				HeaderValue::from_static(REQUEST_ID),
+			);
+		}
+
+		// Cookies set by the handler, e.g. on login or logout, take precedence
+		if headers.contains_key(SET_COOKIE) {
+			return;
+		}
+		match self.session {
+			Ok(Some(Session {
+				token,
+				renew_cookie: Some(max_age),
+				..
+			})) => {
+				if let Ok(cookie) = HeaderValue::from_str(&session_cookie(&token, max_age)) {
+					headers.insert(SET_COOKIE, cookie);
+				}
+			}
+			// The browser drops the cookie, so the next request is anonymous instead of failing
+			// again
+			Err(AuthError::SessionExpired | AuthError::UnknownUser) => {
+				headers.insert(SET_COOKIE, HeaderValue::from_static(CLEAR_SESSION_COOKIE));
+			}
+			_ => (),
+		}
+	}
+}
+
+async fn resolve_session(
+	headers: &HeaderMap,
+	client_ip: Option<IpAddr>,
+	db: &Db,
+) -> Result<Option<Session>, AuthError> {
+	let cookie_header = match headers.get(COOKIE) {
+		Some(cookie) => cookie
+			.to_str()
+			.map_err(|_| AuthError::AsciiOnly(Field::Cookie))?,
+		None => return Ok(None),
+	};
+	let (_, cookies) =
+		parse_cookie(cookie_header).map_err(|_| AuthError::Malformed(Field::Cookie))?;
+	debug!("Cookies: {cookies:?}");
+
+	let token: Uuid = match cookies.get("session") {
+		Some(session_cookie) => session_cookie.parse()?,
+		None => return Ok(None),
+	};
+	let touched = db
+		.authorized_users()
+		.touch(&token, client_ip, &db.config.settings)
+		.ok_or(AuthError::SessionExpired)?;
+	debug!("Getting user with uuid {}", touched.account);
+	let account = db
+		.get_account(&touched.account)
+		.await
+		.ok_or(AuthError::UnknownUser)?;
+
+	Ok(Some(Session {
+		token,
+		account,
+		renew_cookie: touched.renew_cookie,
+	}))
+}
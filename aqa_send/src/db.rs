@@ -1,24 +1,46 @@
-use log::{debug, info};
+use dashmap::DashMap;
+use log::{debug, error, info};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+use tokio::sync::{broadcast, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
 use tokio::task::JoinError;
 use uuid::Uuid;
 
-use crate::db_stuff::Account;
+use crate::audit::{DownloadEvent, DownloadLog, EntryDownloads};
+use crate::db_stuff::{Account, Group};
+use crate::events::{Event, EventBus, EventKind, Subscription};
 use crate::files::InitAppFolderStructureError;
-use crate::{files, AccountType, FileEntry, DB_DIR};
+use crate::headers::{BandwidthLimit, Password};
+use crate::notifications::Notification;
+use crate::password_guard::PasswordFailures;
+use crate::sessions::{hash_token, AuthorizedUsers, SessionInfo};
+use crate::settings::Settings;
+use crate::signed_links::SignedLinks;
+use crate::throttle::{Bandwidth, TokenBucket};
+use crate::webhooks::Webhooks;
+use crate::{files, AccountType, DownloadCount, FileEntry, DB_DIR};
 
 const DB_FILE: &str = "index";
 const ACCOUNTS_FILE: &str = "accounts";
 const REGISTRATION_CODES_PATH: &str = "registration_codes";
+const SETTINGS_FILE: &str = "settings.json";
+const PASSWORD_FAILURES_FILE: &str = "password_failures";
+const NOTIFICATIONS_FILE: &str = "notifications";
+const GROUPS_FILE: &str = "groups";
+const SIGNED_LINKS_FILE: &str = "signed_links";
+const DOWNLOAD_LOG_FILE: &str = "download_log";
+const WEBHOOKS_FILE: &str = "webhooks";
+const SESSIONS_FILE: &str = "sessions";
 
 pub fn init(working_dir: &Path) -> Result<Db, DbError> {
 	files::init_app_directory_structure(working_dir)?;
@@ -27,16 +49,47 @@ pub fn init(working_dir: &Path) -> Result<Db, DbError> {
 	let db_file_path = db_path.join(DB_FILE);
 	let accounts_path = db_path.join(ACCOUNTS_FILE);
 	let registration_codes_path = db_path.join(REGISTRATION_CODES_PATH);
+	let password_failures_path = db_path.join(PASSWORD_FAILURES_FILE);
+	let notifications_path = db_path.join(NOTIFICATIONS_FILE);
+	let groups_path = db_path.join(GROUPS_FILE);
+	let signed_links_path = db_path.join(SIGNED_LINKS_FILE);
+	let download_log_path = db_path.join(DOWNLOAD_LOG_FILE);
+	let webhooks_path = db_path.join(WEBHOOKS_FILE);
+	let sessions_path = db_path.join(SESSIONS_FILE);
+
+	let settings: Settings = read_file(&db_path.join(SETTINGS_FILE), SETTINGS_FILE)?;
+	let password_failures: PasswordFailures =
+		read_file(&password_failures_path, PASSWORD_FAILURES_FILE)?;
+	let notifications: NotificationsHM = read_file(&notifications_path, NOTIFICATIONS_FILE)?;
+	let groups: GroupsHM = read_file(&groups_path, GROUPS_FILE)?;
+	let download_log: DownloadLog = read_file(&download_log_path, DOWNLOAD_LOG_FILE)?;
+	let webhooks: Webhooks = read_file(&webhooks_path, WEBHOOKS_FILE)?;
+	let mut sessions: SessionsHM = read_file(&sessions_path, SESSIONS_FILE)?;
+	migrate_plaintext_session_tokens(&mut sessions, &sessions_path)?;
+	let mut signed_links: SignedLinks = read_file(&signed_links_path, SIGNED_LINKS_FILE)?;
+	if signed_links.ensure_secret() {
+		info!("Generated a new key for signing download links");
+		// Saved right away, links signed before the next save would be invalid after a restart
+		write_file(&signed_links_path, SIGNED_LINKS_FILE, &signed_links)?;
+	}
 
 	let db_config = Box::leak(Box::new(DbConfig {
 		db_path,
 		db_file_path: db_file_path.clone(),
 		accounts_path: accounts_path.clone(),
 		registration_codes_path: registration_codes_path.clone(),
+		password_failures_path,
+		notifications_path,
+		groups_path,
+		signed_links_path,
+		download_log_path,
+		webhooks_path,
+		sessions_path,
+		settings,
 	}));
 
 	debug!("Reading {DB_FILE} file");
-	let db: HashMap<Uuid, FileEntry> = match File::open(&db_file_path) {
+	let mut db: HashMap<Uuid, FileEntry> = match File::open(&db_file_path) {
 		Ok(mut file) => serde_json::from_reader(&mut file).map_err(|err| DbError::Io {
 			error: err.into(),
 			file: DB_FILE,
@@ -50,6 +103,9 @@ pub fn init(working_dir: &Path) -> Result<Db, DbError> {
 		}
 	};
 
+	migrate_plaintext_passwords(&mut db, &db_file_path)?;
+	backfill_sizes(&mut db, &db_config.db_path);
+
 	debug!("Reading {ACCOUNTS_FILE} file");
 	let accounts: HashMap<Uuid, Account> = match File::open(&accounts_path) {
 		Ok(mut file) => serde_json::from_reader(&mut file).map_err(|err| DbError::Io {
@@ -94,10 +150,149 @@ pub fn init(working_dir: &Path) -> Result<Db, DbError> {
 		accounts: Arc::new(RwLock::new(accounts)),
 		account_uuids: Arc::new(RwLock::new(account_uuids)),
 		registration_codes: Arc::new(RwLock::new(registration_codes)),
+		download_reservations: Default::default(),
+		signed_link_reservations: Default::default(),
+		unlock_tokens: Default::default(),
+		ranged_downloads: Default::default(),
+		password_failures: Arc::new(RwLock::new(password_failures)),
+		notifications: Arc::new(RwLock::new(notifications)),
+		groups: Arc::new(RwLock::new(groups)),
+		signed_links: Arc::new(RwLock::new(signed_links)),
+		download_log: Arc::new(Mutex::new(download_log)),
+		webhooks: Arc::new(RwLock::new(webhooks)),
+		authorized_users: AuthorizedUsers::from(sessions),
+		sessions_file_lock: Default::default(),
+		bandwidth: Arc::new(Bandwidth::new(&db_config.settings)),
+		events: Arc::default(),
 		config: db_config,
 	})
 }
 
+/// Reads a json file from the DB directory. Missing file results in a default value.
+fn read_file<T>(path: &Path, file: &'static str) -> Result<T, DbError>
+where
+	T: DeserializeOwned + Default,
+{
+	debug!("Reading {file} file");
+	match File::open(path) {
+		Ok(mut file_handle) => {
+			serde_json::from_reader(&mut file_handle).map_err(|err| DbError::Io {
+				error: err.into(),
+				file,
+			})
+		}
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(Default::default()),
+		Err(err) => Err(DbError::Io {
+			error: DbIoError::DbFileOperation(err),
+			file,
+		}),
+	}
+}
+
+fn write_file<T: Serialize>(path: &Path, file: &'static str, value: &T) -> Result<(), DbError> {
+	let mut file_handle = File::create(path).map_err(|err| DbError::Io {
+		error: err.into(),
+		file,
+	})?;
+	serde_json::to_writer(&mut file_handle, value).map_err(|err| DbError::Io {
+		error: err.into(),
+		file,
+	})
+}
+
+/// Copies the sessions under the lock, so that concurrent writes land in the order of their
+/// copies
+fn write_sessions(
+	authorized_users: &AuthorizedUsers,
+	sessions_file_lock: &Mutex<()>,
+	config: &DbConfig,
+) -> Result<(), DbError> {
+	let _guard = sessions_file_lock.lock().unwrap();
+	let sessions: SessionsHM = authorized_users.to_hash_map();
+	write_file(&config.sessions_path, SESSIONS_FILE, &sessions)
+}
+
+/// Hashes file passwords stored by older versions in plaintext and writes the migrated index
+/// back to disk, so that the plaintext passwords don't linger there.
+fn migrate_plaintext_passwords(db: &mut DbDataHM, db_file_path: &Path) -> Result<(), DbError> {
+	let mut migrated_count = 0_usize;
+	for (uuid, file_entry) in db.iter_mut() {
+		let Some(password) = file_entry.password.as_mut() else {
+			continue;
+		};
+		if !password.is_plaintext() {
+			continue;
+		}
+		debug!("Hashing plaintext password of {uuid}");
+		*password = Password(std::mem::take(&mut password.0))
+			.hash()
+			.map_err(DbError::PasswordHash)?;
+		migrated_count += 1;
+	}
+
+	if migrated_count == 0 {
+		return Ok(());
+	}
+	info!("Hashed {migrated_count} plaintext file passwords");
+
+	let mut file = File::create(db_file_path).map_err(|err| DbError::Io {
+		error: err.into(),
+		file: DB_FILE,
+	})?;
+	serde_json::to_writer(&mut file, db).map_err(|err| DbError::Io {
+		error: err.into(),
+		file: DB_FILE,
+	})?;
+
+	Ok(())
+}
+
+/// Hashes session tokens stored by older versions in plaintext and writes the migrated sessions
+/// back to disk, so that the sessions stay valid and the tokens don't linger there.
+fn migrate_plaintext_session_tokens(
+	sessions: &mut SessionsHM,
+	sessions_path: &Path,
+) -> Result<(), DbError> {
+	let plaintext_tokens: Vec<(String, Uuid)> = sessions
+		.keys()
+		.filter_map(|key| Some((key.clone(), Uuid::parse_str(key).ok()?)))
+		.collect();
+	if plaintext_tokens.is_empty() {
+		return Ok(());
+	}
+
+	for (key, token) in &plaintext_tokens {
+		if let Some(session) = sessions.remove(key) {
+			sessions.insert(hash_token(token), session);
+		}
+	}
+	info!("Hashed {} plaintext session tokens", plaintext_tokens.len());
+
+	write_file(sessions_path, SESSIONS_FILE, sessions)
+}
+
+/// Reads sizes of files uploaded before sizes were recorded from disk. Entries whose file is
+/// missing are left without a size.
+fn backfill_sizes(db: &mut DbDataHM, db_path: &Path) {
+	let mut backfilled_count = 0_usize;
+	for (uuid, file_entry) in db.iter_mut().filter(|(_, entry)| entry.size.is_none()) {
+		let mut file_path = db_path.to_owned();
+		file_path.push(file_entry.download_count_type.to_string());
+		file_path.push(uuid.to_string());
+		match std::fs::metadata(&file_path) {
+			Ok(metadata) => {
+				file_entry.size = Some(metadata.len());
+				backfilled_count += 1;
+			}
+			Err(err) => debug!("Failed to read size of {uuid}: {err}"),
+		}
+	}
+
+	if backfilled_count != 0 {
+		info!("Read sizes of {backfilled_count} files from disk");
+	}
+}
+
 #[derive(Debug, Error)]
 pub enum DbError {
 	#[error(transparent)]
@@ -118,6 +313,15 @@ pub enum DbError {
 
 	#[error("Tried to add account with username that already exists")]
 	AccountAlreadyExists,
+
+	#[error("Download limit reached")]
+	DownloadLimitReached,
+
+	#[error("Single use link is already used by another download")]
+	SignedLinkInUse,
+
+	#[error("Failed to hash a file password")]
+	PasswordHash(argon2::password_hash::Error),
 }
 
 #[derive(Debug, Error)]
@@ -133,6 +337,31 @@ pub type DbDataHM = HashMap<Uuid, FileEntry>;
 pub type AccountsHM = HashMap<Uuid, Account>;
 pub type AccountUuidsHM = HashMap<String, Uuid>;
 pub type RegistrationCodesVec = Vec<RegistrationCode>;
+pub type DownloadReservationsHM = HashMap<Uuid, u64>;
+pub type UnlockTokensHM = DashMap<Uuid, UnlockToken>;
+/// Key: (file uuid, client ip)
+pub type RangedDownloadsHM = DashMap<(Uuid, IpAddr), RangedDownload>;
+pub type NotificationsHM = HashMap<Uuid, Vec<Notification>>;
+pub type GroupsHM = HashMap<Uuid, Group>;
+/// Key: [hash](hash_token) of the session token
+pub type SessionsHM = HashMap<String, SessionInfo>;
+
+/// Counted ranged download of a client, that its next ranged requests can continue for free
+#[derive(Debug, Clone)]
+pub struct RangedDownload {
+	/// When the download was counted
+	pub counted_at: Instant,
+	/// Bytes of the counted request and of the requests continuing it, including those still being
+	/// sent
+	pub bytes_served: u64,
+}
+
+/// Grants access to a password protected entry for a limited time
+#[derive(Debug, Clone)]
+pub struct UnlockToken {
+	pub file_uuid: Uuid,
+	pub valid_until: Instant,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegistrationCode {
@@ -148,6 +377,48 @@ pub struct Db {
 
 	account_uuids: Arc<RwLock<AccountUuidsHM>>,
 
+	/// Downloads that are in progress, but haven't finished yet. Not persisted.
+	/// Uses a blocking mutex so that a reservation can be released from `Drop`.
+	download_reservations: Arc<Mutex<DownloadReservationsHM>>,
+
+	/// Single use links with a download in progress. Not persisted.
+	/// Uses a blocking mutex so that a reservation can be released from `Drop`.
+	signed_link_reservations: Arc<Mutex<HashSet<Uuid>>>,
+
+	/// Tokens handed out after a successful password check. Not persisted.
+	unlock_tokens: Arc<UnlockTokensHM>,
+
+	/// Clients whose ranged download of an entry was already counted. Not persisted.
+	ranged_downloads: Arc<RangedDownloadsHM>,
+
+	password_failures: Arc<RwLock<PasswordFailures>>,
+
+	/// Key: account uuid
+	notifications: Arc<RwLock<NotificationsHM>>,
+
+	/// Account groups, persisted next to accounts
+	groups: Arc<RwLock<GroupsHM>>,
+
+	signed_links: Arc<RwLock<SignedLinks>>,
+
+	/// Uses a blocking mutex so that a download can be recorded from `Drop`
+	download_log: Arc<Mutex<DownloadLog>>,
+
+	/// Registered webhooks and their dead letters
+	webhooks: Arc<RwLock<Webhooks>>,
+
+	/// Sessions of logged in users
+	authorized_users: AuthorizedUsers,
+
+	/// Held while the sessions are written to disk, so that an older copy can't overwrite a
+	/// newer one
+	sessions_file_lock: Arc<Mutex<()>>,
+
+	/// Token buckets shared between transfers. Not persisted.
+	bandwidth: Arc<Bandwidth>,
+
+	events: Arc<EventBus>,
+
 	pub config: &'static DbConfig,
 }
 
@@ -158,6 +429,20 @@ impl Clone for Db {
 			accounts: Arc::clone(&self.accounts),
 			account_uuids: Arc::clone(&self.account_uuids),
 			registration_codes: Arc::clone(&self.registration_codes),
+			download_reservations: Arc::clone(&self.download_reservations),
+			signed_link_reservations: Arc::clone(&self.signed_link_reservations),
+			unlock_tokens: Arc::clone(&self.unlock_tokens),
+			ranged_downloads: Arc::clone(&self.ranged_downloads),
+			password_failures: Arc::clone(&self.password_failures),
+			notifications: Arc::clone(&self.notifications),
+			groups: Arc::clone(&self.groups),
+			signed_links: Arc::clone(&self.signed_links),
+			download_log: Arc::clone(&self.download_log),
+			webhooks: Arc::clone(&self.webhooks),
+			authorized_users: self.authorized_users.clone(),
+			sessions_file_lock: Arc::clone(&self.sessions_file_lock),
+			bandwidth: Arc::clone(&self.bandwidth),
+			events: Arc::clone(&self.events),
 			config: self.config,
 		}
 	}
@@ -188,6 +473,20 @@ impl Db {
 		self.accounts.read().await.get(uuid).map(ToOwned::to_owned)
 	}
 
+	/// Looks up an account by its uuid or, failing that, by its username
+	pub async fn find_account_uuid(&self, uuid_or_username: &str) -> Option<Uuid> {
+		if let Ok(uuid) = uuid_or_username.parse::<Uuid>() {
+			if self.accounts.read().await.contains_key(&uuid) {
+				return Some(uuid);
+			}
+		}
+		self.account_uuids
+			.read()
+			.await
+			.get(uuid_or_username)
+			.copied()
+	}
+
 	pub async fn accounts_reader(&self) -> OwnedRwLockReadGuard<AccountsHM> {
 		self.accounts.clone().read_owned().await
 	}
@@ -219,6 +518,320 @@ impl Db {
 		Ok(())
 	}
 
+	/// Reserves a download slot for the given entry.
+	///
+	/// Downloads that are currently in progress count towards the download limit, so concurrent
+	/// requests can't download the file more times than allowed. The reservation has to be
+	/// committed with [DownloadSlot::commit] once the whole file was sent. Dropping the slot
+	/// without committing releases it.
+	pub async fn reserve_download(&self, uuid: &Uuid) -> Result<DownloadSlot, DbError> {
+		// Holding the read lock prevents `download_count` from changing while we check it
+		let file_entries = self.file_entries.read().await;
+		let file_entry = file_entries.get(uuid).ok_or(DbError::UpdateFail)?;
+
+		let mut reservations = self.download_reservations.lock().unwrap();
+		let reserved = reservations.entry(*uuid).or_default();
+		if let DownloadCount::Count(max_count) = file_entry.download_count_type {
+			if file_entry.download_count + *reserved >= max_count {
+				return Err(DbError::DownloadLimitReached);
+			}
+		}
+		*reserved += 1;
+
+		Ok(DownloadSlot {
+			db: self.clone(),
+			uuid: *uuid,
+			committed: false,
+			counted: true,
+			ranged_client: None,
+			single_use_link: None,
+		})
+	}
+
+	/// Starts a ranged request of `bytes` that continues a download of the entry by the client
+	/// that was already counted, so it doesn't count as another download. It does within
+	/// `ranged_download_window_secs` of the counted request, as long as the requests together
+	/// don't ask for more than the `size` of the file. Requests of clients with an unknown
+	/// address are always counted.
+	///
+	/// The bytes of the request are given back if the returned slot is dropped without being
+	/// committed.
+	pub fn continue_ranged_download(
+		&self,
+		uuid: &Uuid,
+		client: Option<IpAddr>,
+		bytes: u64,
+		size: u64,
+	) -> Option<DownloadSlot> {
+		let client = client?;
+		let window = Duration::from_secs(self.config.settings.ranged_download_window_secs);
+		let mut download = self.ranged_downloads.get_mut(&(*uuid, client))?;
+		if download.counted_at.elapsed() >= window || download.bytes_served + bytes > size {
+			return None;
+		}
+		download.bytes_served += bytes;
+
+		Some(DownloadSlot {
+			db: self.clone(),
+			uuid: *uuid,
+			committed: false,
+			counted: false,
+			ranged_client: Some((client, bytes)),
+			single_use_link: None,
+		})
+	}
+
+	pub fn remove_stale_ranged_downloads(&self) {
+		let window = Duration::from_secs(self.config.settings.ranged_download_window_secs);
+		self.ranged_downloads
+			.retain(|_key, download| download.counted_at.elapsed() < window);
+	}
+
+	fn release_signed_link(&self, nonce: &Uuid) {
+		self.signed_link_reservations.lock().unwrap().remove(nonce);
+	}
+
+	fn release_ranged_bytes(&self, uuid: &Uuid, client: IpAddr, bytes: u64) {
+		if let Some(mut download) = self.ranged_downloads.get_mut(&(*uuid, client)) {
+			download.bytes_served = download.bytes_served.saturating_sub(bytes);
+		}
+	}
+
+	/// Token buckets a download or an upload has to take tokens from
+	pub fn bandwidth_buckets(
+		&self,
+		account: Option<&Account>,
+		client_ip: Option<IpAddr>,
+		entry_limit: Option<BandwidthLimit>,
+	) -> Vec<Arc<TokenBucket>> {
+		self.bandwidth
+			.buckets(&self.config.settings, account, client_ip, entry_limit)
+	}
+
+	pub fn remove_idle_bandwidth_buckets(&self) {
+		self.bandwidth.remove_idle();
+	}
+
+	fn release_download(&self, uuid: &Uuid) {
+		let mut reservations = self.download_reservations.lock().unwrap();
+		if let Some(reserved) = reservations.get_mut(uuid) {
+			*reserved = reserved.saturating_sub(1);
+			if *reserved == 0 {
+				reservations.remove(uuid);
+			}
+		}
+	}
+
+	pub fn create_unlock_token(&self, file_uuid: Uuid, lifetime: Duration) -> Uuid {
+		let token = Uuid::new_v4();
+		self.unlock_tokens.insert(
+			token,
+			UnlockToken {
+				file_uuid,
+				valid_until: Instant::now() + lifetime,
+			},
+		);
+		token
+	}
+
+	/// Checks whether the token is valid and was issued for the given entry
+	pub fn check_unlock_token(&self, token: &Uuid, file_uuid: &Uuid) -> bool {
+		let now = Instant::now();
+		self.unlock_tokens
+			.remove_if(token, |_, unlock_token| unlock_token.valid_until <= now);
+		self.unlock_tokens
+			.get(token)
+			.map(|unlock_token| unlock_token.file_uuid == *file_uuid)
+			.unwrap_or_default()
+	}
+
+	pub fn remove_expired_unlock_tokens(&self) {
+		let now = Instant::now();
+		self.unlock_tokens
+			.retain(|_, unlock_token| unlock_token.valid_until > now);
+	}
+
+	/// See [PasswordFailures::retry_after]
+	/// See [PasswordFailures::begin_attempt]
+	pub async fn begin_password_attempt(
+		&self,
+		entry: &Uuid,
+		client: Option<IpAddr>,
+	) -> Result<bool, Duration> {
+		self.password_failures.write().await.begin_attempt(
+			entry,
+			client,
+			&self.config.settings,
+			SystemTime::now(),
+		)
+	}
+
+	pub async fn record_password_success(&self, entry: &Uuid, client: Option<IpAddr>) {
+		self.password_failures
+			.write()
+			.await
+			.record_success(entry, client);
+	}
+
+	pub async fn remove_stale_password_failures(&self, removed_entries: &[Uuid]) {
+		let mut password_failures = self.password_failures.write().await;
+		for entry in removed_entries {
+			password_failures.remove_entry(entry);
+		}
+		password_failures.remove_stale(&self.config.settings, SystemTime::now());
+	}
+
+	pub async fn notify(&self, account: Uuid, notification: Notification) {
+		self.notifications
+			.write()
+			.await
+			.entry(account)
+			.or_default()
+			.push(notification);
+	}
+
+	pub async fn notifications(&self, account: &Uuid) -> Vec<Notification> {
+		self.notifications
+			.read()
+			.await
+			.get(account)
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	pub async fn clear_notifications(&self, account: &Uuid) {
+		self.notifications.write().await.remove(account);
+	}
+
+	pub async fn groups_reader(&self) -> OwnedRwLockReadGuard<GroupsHM> {
+		self.groups.clone().read_owned().await
+	}
+
+	pub async fn groups_writer(&self) -> OwnedRwLockWriteGuard<GroupsHM> {
+		self.groups.clone().write_owned().await
+	}
+
+	/// Looks up a group by its uuid or, failing that, by its name
+	pub async fn find_group_uuid(&self, uuid_or_name: &str) -> Option<Uuid> {
+		let groups = self.groups.read().await;
+		if let Ok(uuid) = uuid_or_name.parse::<Uuid>() {
+			if groups.contains_key(&uuid) {
+				return Some(uuid);
+			}
+		}
+		groups
+			.values()
+			.find(|group| group.name == uuid_or_name)
+			.map(|group| group.uuid)
+	}
+
+	/// Removes the group and drops it from the entries it was shared with
+	pub async fn remove_group(&self, uuid: &Uuid) -> Option<Group> {
+		let group = self.groups.write().await.remove(uuid)?;
+		for file_entry in self.file_entries.write().await.values_mut() {
+			file_entry.shared_with_groups.retain(|group| group != uuid);
+		}
+		Some(group)
+	}
+
+	pub async fn signed_links_reader(&self) -> OwnedRwLockReadGuard<SignedLinks> {
+		self.signed_links.clone().read_owned().await
+	}
+
+	pub async fn signed_links_writer(&self) -> OwnedRwLockWriteGuard<SignedLinks> {
+		self.signed_links.clone().write_owned().await
+	}
+
+	pub async fn remove_stale_signed_links(&self, removed_entries: &[Uuid]) {
+		self.signed_links
+			.write()
+			.await
+			.remove_stale(removed_entries, SystemTime::now());
+	}
+
+	/// Adds the event to the download log, unless the log is disabled
+	pub fn record_download(&self, file_uuid: Uuid, uploader: Option<Uuid>, event: DownloadEvent) {
+		if self.config.settings.download_log_retention_secs == 0 {
+			return;
+		}
+		debug!(
+			"Download of {file_uuid} {:?} after {} bytes",
+			event.status, event.bytes_sent
+		);
+		self.download_log
+			.lock()
+			.unwrap()
+			.record(file_uuid, uploader, event);
+	}
+
+	pub fn download_events(&self, file_uuid: &Uuid) -> Option<EntryDownloads> {
+		self.download_log
+			.lock()
+			.unwrap()
+			.entries
+			.get(file_uuid)
+			.cloned()
+	}
+
+	pub fn remove_stale_download_events(&self) {
+		let retention = Duration::from_secs(self.config.settings.download_log_retention_secs);
+		self.download_log
+			.lock()
+			.unwrap()
+			.remove_stale(retention, SystemTime::now());
+	}
+
+	pub async fn webhooks_reader(&self) -> OwnedRwLockReadGuard<Webhooks> {
+		self.webhooks.clone().read_owned().await
+	}
+
+	pub async fn webhooks_writer(&self) -> OwnedRwLockWriteGuard<Webhooks> {
+		self.webhooks.clone().write_owned().await
+	}
+
+	pub fn authorized_users(&self) -> &AuthorizedUsers {
+		&self.authorized_users
+	}
+
+	pub fn remove_expired_sessions(&self) {
+		let removed = self.authorized_users.remove_expired(&self.config.settings);
+		if removed > 0 {
+			debug!("Removed {removed} expired sessions");
+		}
+	}
+
+	/// Writes the sessions to disk right away, so that ended sessions don't come back if the
+	/// server stops before the next save. Failures are only logged, the next save retries.
+	pub async fn save_sessions(&self) {
+		let authorized_users = self.authorized_users.clone();
+		let sessions_file_lock = Arc::clone(&self.sessions_file_lock);
+		let config = self.config;
+		let result = tokio::task::spawn_blocking(move || {
+			write_sessions(&authorized_users, &sessions_file_lock, config)
+		})
+		.await;
+		match result {
+			Ok(Ok(())) => debug!("Saved sessions"),
+			Ok(Err(err)) => error!("Failed to save sessions: {err}"),
+			Err(err) => error!("Failed to save sessions: {err}"),
+		}
+	}
+
+	/// Sends the event to subscribers of [Db::subscribe]
+	pub fn publish(&self, kind: EventKind, uuid: Uuid, entry: FileEntry) {
+		debug!("Publishing {kind:?} event of {uuid}");
+		self.events.publish(kind, uuid, entry);
+	}
+
+	pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+		self.events.subscribe()
+	}
+
+	/// See [EventBus::subscribe_since]
+	pub fn subscribe_since(&self, last_id: u64) -> Subscription {
+		self.events.subscribe_since(last_id)
+	}
+
 	pub async fn save(&self) -> Result<(), DbError> {
 		info!("Serializing db to disk");
 
@@ -237,6 +850,15 @@ impl Db {
 			registration_codes_guard.clone()
 		};
 
+		let password_failures: PasswordFailures = self.password_failures.read().await.clone();
+		let notifications_hm: NotificationsHM = self.notifications.read().await.clone();
+		let groups_hm: GroupsHM = self.groups.read().await.clone();
+		let signed_links: SignedLinks = self.signed_links.read().await.clone();
+		let download_log: DownloadLog = self.download_log.lock().unwrap().clone();
+		let webhooks: Webhooks = self.webhooks.read().await.clone();
+		let authorized_users = self.authorized_users.clone();
+		let sessions_file_lock = Arc::clone(&self.sessions_file_lock);
+
 		let config: &'static DbConfig = self.config;
 
 		tokio::task::spawn_blocking(move || {
@@ -270,6 +892,22 @@ impl Db {
 				}
 			})?;
 
+			write_file(
+				&config.password_failures_path,
+				PASSWORD_FAILURES_FILE,
+				&password_failures,
+			)?;
+			write_file(
+				&config.notifications_path,
+				NOTIFICATIONS_FILE,
+				&notifications_hm,
+			)?;
+			write_file(&config.groups_path, GROUPS_FILE, &groups_hm)?;
+			write_file(&config.signed_links_path, SIGNED_LINKS_FILE, &signed_links)?;
+			write_file(&config.download_log_path, DOWNLOAD_LOG_FILE, &download_log)?;
+			write_file(&config.webhooks_path, WEBHOOKS_FILE, &webhooks)?;
+			write_sessions(&authorized_users, &sessions_file_lock, config)?;
+
 			Result::<(), DbError>::Ok(())
 		})
 		.await??;
@@ -293,10 +931,116 @@ impl Db {
 	}
 }
 
+/// A reserved download, see [Db::reserve_download].
+#[derive(Debug)]
+pub struct DownloadSlot {
+	db: Db,
+	uuid: Uuid,
+	committed: bool,
+	/// Whether the download counts towards the download limit, see
+	/// [Db::continue_ranged_download]
+	counted: bool,
+	/// Client and bytes of a ranged download
+	ranged_client: Option<(IpAddr, u64)>,
+	/// Nonce of the single use link the download was made with
+	single_use_link: Option<Uuid>,
+}
+
+impl DownloadSlot {
+	/// Once committed, next ranged requests of the client don't count as separate downloads,
+	/// until they ask for more bytes than the file has. See [Db::continue_ranged_download].
+	pub fn ranged(mut self, client: IpAddr, bytes: u64) -> Self {
+		self.ranged_client = Some((client, bytes));
+		self
+	}
+
+	/// Reserves the single use link for this download. It's used up when the download is
+	/// committed and can be used again if the download doesn't finish.
+	pub fn single_use_link(mut self, nonce: Uuid) -> Result<Self, DbError> {
+		if !self
+			.db
+			.signed_link_reservations
+			.lock()
+			.unwrap()
+			.insert(nonce)
+		{
+			return Err(DbError::SignedLinkInUse);
+		}
+		self.single_use_link = Some(nonce);
+		Ok(self)
+	}
+
+	/// Marks the download as completed and increases the download count of the entry. Uses up
+	/// the single use link of the download. A continued ranged download only keeps its bytes
+	/// counted.
+	pub async fn commit(mut self) -> Result<u64, DbError> {
+		if !self.counted {
+			self.committed = true;
+			let file_entries = self.db.file_entries.read().await;
+			let file_entry = file_entries.get(&self.uuid).ok_or(DbError::UpdateFail)?;
+			return Ok(file_entry.download_count);
+		}
+
+		if let Some(nonce) = self.single_use_link {
+			if !self.db.signed_links.write().await.consume(&nonce) {
+				debug!("Single use link {nonce} was revoked during the download");
+			}
+			self.db.release_signed_link(&nonce);
+		}
+
+		let mut file_entries = self.db.file_entries.write().await;
+		self.db.release_download(&self.uuid);
+		self.committed = true;
+		if let Some((client, bytes)) = self.ranged_client {
+			self.db.ranged_downloads.insert(
+				(self.uuid, client),
+				RangedDownload {
+					counted_at: Instant::now(),
+					bytes_served: bytes,
+				},
+			);
+		}
+
+		let file_entry = file_entries
+			.get_mut(&self.uuid)
+			.ok_or(DbError::UpdateFail)?;
+		file_entry.download_count += 1;
+		self.db
+			.publish(EventKind::Downloaded, self.uuid, file_entry.clone());
+		Ok(file_entry.download_count)
+	}
+}
+
+impl Drop for DownloadSlot {
+	fn drop(&mut self) {
+		if !self.committed {
+			debug!("Releasing unfinished download of {}", self.uuid);
+			if !self.counted {
+				if let Some((client, bytes)) = self.ranged_client {
+					self.db.release_ranged_bytes(&self.uuid, client, bytes);
+				}
+				return;
+			}
+			self.db.release_download(&self.uuid);
+			if let Some(nonce) = self.single_use_link {
+				self.db.release_signed_link(&nonce);
+			}
+		}
+	}
+}
+
 #[derive(Debug)]
 pub struct DbConfig {
 	pub db_path: PathBuf,
 	pub db_file_path: PathBuf,
 	pub accounts_path: PathBuf,
 	pub registration_codes_path: PathBuf,
+	pub password_failures_path: PathBuf,
+	pub notifications_path: PathBuf,
+	pub groups_path: PathBuf,
+	pub signed_links_path: PathBuf,
+	pub download_log_path: PathBuf,
+	pub webhooks_path: PathBuf,
+	pub sessions_path: PathBuf,
+	pub settings: Settings,
 }
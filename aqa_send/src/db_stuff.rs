@@ -5,7 +5,8 @@ use thiserror::Error;
 use uuid::Uuid;
 // use uuid::Uuid;
 
-use crate::headers::{DownloadCount, Lifetime, Password, Visibility};
+use crate::db::GroupsHM;
+use crate::headers::{BandwidthLimit, DownloadCount, Lifetime, PasswordHash, Visibility};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileEntry {
@@ -17,13 +18,97 @@ pub struct FileEntry {
 	pub download_count_type: DownloadCount,
 	pub download_count: u64,
 
+	/// Size in bytes. `None` while the file is being uploaded.
+	#[serde(default)]
+	pub size: Option<u64>,
+
 	pub visibility: Visibility,
-	pub password: Option<Password>,
+	pub password: Option<PasswordHash>,
+	/// Accounts, other than the uploader, allowed to access an unlisted or private entry
+	#[serde(default)]
+	pub shared_with: Vec<Uuid>,
+	/// Groups whose members are allowed to access an unlisted or private entry
+	#[serde(default)]
+	pub shared_with_groups: Vec<Uuid>,
+	/// Set by an admin in place of the per connection and per account limits
+	#[serde(default)]
+	pub bandwidth_limit: Option<BandwidthLimit>,
 
 	pub lifetime: Lifetime,
 	pub upload_date: SystemTime,
 }
 
+impl FileEntry {
+	pub fn is_uploader(&self, account: Option<&Account>) -> bool {
+		match (account, self.uploader_uuid) {
+			(Some(account), Some(uploader)) => account.uuid == uploader,
+			_ => false,
+		}
+	}
+
+	/// Whether the entry is shared with `account` directly or through one of its groups
+	pub fn is_shared_with(&self, account: Option<&Account>, groups: &GroupsHM) -> bool {
+		let Some(account) = account else {
+			return false;
+		};
+		self.shared_with.contains(&account.uuid)
+			|| self
+				.shared_with_groups
+				.iter()
+				.filter_map(|group| groups.get(group))
+				.any(|group| group.is_member(&account.uuid))
+	}
+
+	/// Whether `account` is allowed to download the entry
+	pub fn can_download(&self, account: Option<&Account>, groups: &GroupsHM) -> bool {
+		match self.visibility {
+			Visibility::Public | Visibility::Unlisted => true,
+			Visibility::Private => {
+				self.is_uploader(account) || self.is_shared_with(account, groups)
+			}
+		}
+	}
+
+	/// Whether the entry shows up in the file list of `account`
+	pub fn is_listed_for(&self, account: Option<&Account>, groups: &GroupsHM) -> bool {
+		match self.visibility {
+			Visibility::Public => true,
+			Visibility::Unlisted | Visibility::Private => {
+				self.is_uploader(account) || self.is_shared_with(account, groups)
+			}
+		}
+	}
+
+	/// When the lifetime of the entry runs out. `None` if it's unlimited.
+	pub fn expires_at(&self) -> Option<SystemTime> {
+		match self.lifetime {
+			Lifetime::Infinite => None,
+			Lifetime::Duration(lifetime) => self.upload_date.checked_add(lifetime),
+		}
+	}
+
+	/// Downloads left before the entry gets removed. `None` if their number is unlimited.
+	pub fn downloads_remaining(&self) -> Option<u64> {
+		match self.download_count_type {
+			DownloadCount::Infinite => None,
+			DownloadCount::Count(max_count) => Some(max_count.saturating_sub(self.download_count)),
+		}
+	}
+
+	/// Whether the entry neither expired nor ran out of downloads. Such entries are still in the
+	/// database until the cleanup task removes them.
+	pub fn is_available(&self) -> bool {
+		self.expires_at()
+			.is_none_or(|expires_at| SystemTime::now() < expires_at)
+			&& self.downloads_remaining() != Some(0)
+	}
+
+	/// Whether `account` is allowed to delete the entry or change who it's shared with
+	pub fn can_manage(&self, account: &Account) -> bool {
+		matches!(account.acc_type, AccountType::Admin) || self.is_uploader(Some(account))
+	}
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Account {
 	pub uuid: Uuid,
@@ -32,6 +117,26 @@ pub struct Account {
 	pub acc_type: AccountType,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+	pub uuid: Uuid,
+	pub name: String,
+	pub owner: Uuid,
+	pub members: Vec<Uuid>,
+}
+
+impl Group {
+	/// The owner is always considered a member
+	pub fn is_member(&self, account: &Uuid) -> bool {
+		self.owner == *account || self.members.contains(account)
+	}
+
+	/// Whether `account` is allowed to manage the group's members or remove it
+	pub fn can_manage(&self, account: &Account) -> bool {
+		matches!(account.acc_type, AccountType::Admin) || self.owner == account.uuid
+	}
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 pub enum AccountType {
 	Admin,
@@ -1,8 +1,10 @@
-use crate::account::{get_logged_in_user, AuthError};
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db;
 use crate::db::Db;
-use crate::db_stuff::{AccountType, FileEntry};
+use crate::db_stuff::FileEntry;
 use crate::error::{ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError};
-use crate::{db, AuthorizedUsers};
+use crate::events::EventKind;
 use hyper::{Body, Request, Response, StatusCode};
 use log::{debug, error};
 use thiserror::Error;
@@ -59,30 +61,20 @@ impl HttpHandlerError for DeleteError {
 
 pub async fn delete(
 	uuid: String,
-	req: Request<Body>,
+	_req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<DeleteError>> {
 	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
 	debug!("Deleting {}", uuid);
 
 	let file_entry: FileEntry = db.get(&uuid).await.ok_or(DeleteError::NotFound)?.to_owned();
 
-	let Some(current_user) =
-		get_logged_in_user(req.headers(), db.clone(), authorized_users.clone())
-			.await
-			.into_handler_error()?
-	else {
+	let Some(current_user) = ctx.user().into_handler_error()? else {
 		return Err(DeleteError::NotLoggedIn.into());
 	};
 
-	let authorized = match (current_user.acc_type, file_entry.uploader_uuid) {
-		(AccountType::Admin, _) => true,
-		(_, Some(uploader)) => current_user.uuid == uploader,
-		_ => false,
-	};
-
-	if !authorized {
+	if !file_entry.can_manage(&current_user) {
 		return Err(DeleteError::NotAuthorized.into());
 	}
 
@@ -90,8 +82,9 @@ pub async fn delete(
 
 	{
 		let mut file_entries_writer = db.writer().await;
-		if file_entries_writer.remove(&uuid).is_none() {
-			error!("Tried to delete entry {uuid} that doesn't exist in DbDataHM");
+		match file_entries_writer.remove(&uuid) {
+			Some(file_entry) => db.publish(EventKind::Deleted, uuid, file_entry),
+			None => error!("Tried to delete entry {uuid} that doesn't exist in DbDataHM"),
 		}
 	}
 
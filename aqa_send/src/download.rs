@@ -1,21 +1,40 @@
+use std::collections::VecDeque;
 use std::future::Future;
+use std::io::SeekFrom;
+use std::net::IpAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::Stream;
-use hyper::{Body, Request, Response};
+use hyper::header::{
+	ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+	IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER,
+	X_CONTENT_TYPE_OPTIONS,
+};
+use hyper::http::HeaderValue;
+use hyper::{Body, HeaderMap, Method, Request, Response};
 use log::*;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeek;
+use tokio_util::io::poll_read_buf;
 use uuid::Uuid;
 
-use crate::account::{get_logged_in_user, AuthError};
-use crate::db::{self, Db};
+use crate::account::AuthError;
+use crate::audit::{Audited, DownloadEvent};
+use crate::context::RequestContext;
+use crate::db::{self, Db, DownloadSlot};
 use crate::db_stuff::FileEntry;
-use crate::error::{ErrorContentType, IntoHandlerError};
-use crate::headers::{DownloadCount, Password, Visibility};
-use crate::{uri_query_iter, AuthorizedUsers, HandlerError, HttpHandlerError, StatusCode};
+use crate::error::{json_response, ErrorContentType, IntoHandlerError};
+use crate::headers::{Password, PasswordHash, DOWNLOADS_REMAINING, EXPIRES_AT, PASSWORD};
+use crate::multipart::{self, Multipart, MultipartError};
+use crate::notifications::Notification;
+use crate::range::{parse_range, ByteRange, Unsatisfiable};
+use crate::signed_links::{check_signed_link, SignedLinkError};
+use crate::throttle::Throttled;
+use crate::{uri_query_iter, HandlerError, HttpHandlerError, StatusCode};
 
 #[derive(Debug, Error)]
 pub enum DownloadError {
@@ -32,10 +51,23 @@ pub enum DownloadError {
 	#[error(transparent)]
 	AuthError(#[from] AuthError),
 
+	#[error(transparent)]
+	Boundary(#[from] multipart::GetBoundaryError),
+	#[error(transparent)]
+	Multipart(#[from] MultipartError),
+
 	#[error("File id not found or not present")]
 	NotFound,
 	#[error("Invalid password")]
 	InvalidPassword,
+	#[error("Password is missing from request body")]
+	ExpectedPassword,
+	#[error("Too many invalid password attempts. Try again in {} seconds", .0.as_secs())]
+	TooManyAttempts(Duration),
+	#[error(transparent)]
+	SignedLink(#[from] SignedLinkError),
+	#[error("Disposition must be either `inline` or `attachment`")]
+	InvalidDisposition,
 }
 
 impl HttpHandlerError for DownloadError {
@@ -46,8 +78,14 @@ impl HttpHandlerError for DownloadError {
 			DownloadError::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			DownloadError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			DownloadError::AuthError(err) => err.code(),
+			DownloadError::Boundary(err) => err.code(),
+			DownloadError::Multipart(_) => StatusCode::BAD_REQUEST,
 			DownloadError::NotFound => StatusCode::NOT_FOUND,
 			DownloadError::InvalidPassword => StatusCode::UNAUTHORIZED,
+			DownloadError::ExpectedPassword => StatusCode::BAD_REQUEST,
+			DownloadError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+			DownloadError::SignedLink(err) => err.code(),
+			DownloadError::InvalidDisposition => StatusCode::BAD_REQUEST,
 		}
 	}
 
@@ -58,125 +96,661 @@ impl HttpHandlerError for DownloadError {
 			DownloadError::Serialization(_) => false,
 			DownloadError::Db(_) => false,
 			DownloadError::AuthError(err) => err.user_presentable(),
+			DownloadError::Boundary(err) => err.user_presentable(),
+			DownloadError::Multipart(_) => true,
 			DownloadError::NotFound => true,
 			DownloadError::InvalidPassword => true,
+			DownloadError::ExpectedPassword => true,
+			DownloadError::TooManyAttempts(_) => true,
+			DownloadError::SignedLink(err) => err.user_presentable(),
+			DownloadError::InvalidDisposition => true,
 		}
 	}
 
 	fn content_type() -> ErrorContentType {
 		ErrorContentType::Json
 	}
+
+	fn headers(&self) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		if let DownloadError::TooManyAttempts(retry_after) = self {
+			// Round up, so that clients don't retry too early
+			let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+			headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+		}
+		headers
+	}
 }
 
 pub async fn download(
 	uuid: String,
 	req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<DownloadError>> {
 	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
 	debug!("Downloading {}", uuid);
 
-	let mut file_entry: FileEntry = db
+	let disposition = Disposition::from_query(req.uri().query())?;
+
+	let file_entry: FileEntry = db
 		.get(&uuid)
 		.await
 		.ok_or(DownloadError::NotFound)?
 		.to_owned();
 
-	let current_user = get_logged_in_user(req.headers(), db.clone(), authorized_users.clone())
+	let current_user = ctx.user_or_anonymous().into_handler_error()?;
+
+	// A valid signed link is accepted in place of a session
+	let signed_link = check_signed_link(&uuid, req.uri().query(), &db)
 		.await
 		.into_handler_error()?;
-	if matches!(file_entry.visibility, Visibility::Private) {
-		let authorized = match (current_user, file_entry.uploader_uuid) {
-			(Some(current_user), Some(uploader)) => current_user.uuid == uploader,
-			_ => false,
-		};
-		if !authorized {
+	if signed_link.is_none()
+		&& !file_entry.can_download(current_user.as_ref(), &*db.groups_reader().await)
+	{
+		return Ok(Response::builder()
+			.status(StatusCode::UNAUTHORIZED)
+			.body(Body::empty())?);
+	}
+
+	if let Some(ref password_hash) = file_entry.password {
+		check_password(&uuid, &file_entry, password_hash, &req, &db, ctx.client_ip).await?;
+	}
+
+	let mut file_path = db.config.db_path.clone();
+	file_path.push(file_entry.download_count_type.to_string());
+	file_path.push(uuid.to_string());
+
+	if !file_path.exists() {
+		return Err(DownloadError::NotFound.into());
+	}
+
+	let file = tokio::fs::File::open(&file_path)
+		.await
+		.map_err(DownloadError::FileSendIo)?;
+
+	let size = file
+		.metadata()
+		.await
+		.map_err(DownloadError::FileSendIo)?
+		.len();
+	let etag = entity_tag(&uuid, &file_entry);
+	let last_modified = httpdate::fmt_http_date(file_entry.upload_date);
+
+	// Checked before reserving a download, so it doesn't count towards the limit
+	if not_modified(req.headers(), &etag, file_entry.upload_date) {
+		return Ok(Response::builder()
+			.status(StatusCode::NOT_MODIFIED)
+			.header(ETAG, etag)
+			.header("Access-Control-Expose-Headers", "ETag")
+			.header(LAST_MODIFIED, last_modified)
+			.body(Body::empty())?);
+	}
+
+	let ranges = match requested_ranges(req.headers(), size, &etag, file_entry.upload_date) {
+		Some(Ok(ranges)) => Some(ranges),
+		Some(Err(Unsatisfiable)) => {
 			return Ok(Response::builder()
-				.status(StatusCode::UNAUTHORIZED)
+				.status(StatusCode::RANGE_NOT_SATISFIABLE)
+				.header(CONTENT_RANGE, format!("bytes */{size}"))
 				.body(Body::empty())?);
 		}
-	}
+		None => None,
+	};
 
-	if let Some(Password(ref password)) = file_entry.password {
-		let query = req.uri().query().ok_or(DownloadError::InvalidPassword)?;
-		let (_, provided_password) = uri_query_iter(query)
-			.find(|(key, _value)| *key == "password")
-			.ok_or(DownloadError::InvalidPassword)?;
+	let disposition = match disposition {
+		Disposition::Inline if db.config.settings.inline_allowed(&file_entry.content_type) => {
+			Disposition::Inline
+		}
+		_ => Disposition::Attachment,
+	};
+	// Stored content types come from the uploader, so fall back to a generic one if it's not a
+	// valid header value
+	let content_type = HeaderValue::from_str(&file_entry.content_type)
+		.unwrap_or(HeaderValue::from_static("application/octet-stream"));
 
-		let provided_password =
-			urlencoding::decode(provided_password).map_err(|_| DownloadError::InvalidPassword)?;
+	let response = Response::builder()
+		.header(
+			CONTENT_DISPOSITION,
+			content_disposition(disposition, &file_entry.filename),
+		)
+		.header(X_CONTENT_TYPE_OPTIONS, "nosniff")
+		.header(ACCEPT_RANGES, "bytes")
+		.header(ETAG, etag)
+		.header(LAST_MODIFIED, last_modified)
+		.header(
+			"Access-Control-Expose-Headers",
+			format!(
+				"Content-Range, ETag, Content-Disposition, {DOWNLOADS_REMAINING}, {EXPIRES_AT}"
+			),
+		);
+	let response = match file_entry.downloads_remaining() {
+		Some(downloads_remaining) => response.header(DOWNLOADS_REMAINING, downloads_remaining),
+		None => response,
+	};
+	let response = match file_entry.expires_at() {
+		Some(expires_at) => response.header(EXPIRES_AT, httpdate::fmt_http_date(expires_at)),
+		None => response,
+	};
 
-		if &provided_password != password {
-			return Err(DownloadError::InvalidPassword.into());
+	let (response, segments, content_length) = match ranges.as_deref() {
+		None => (
+			response
+				.status(StatusCode::OK)
+				.header(CONTENT_TYPE, content_type),
+			VecDeque::from([Segment::File {
+				start: 0,
+				len: size,
+			}]),
+			size,
+		),
+		Some([range]) => (
+			response
+				.status(StatusCode::PARTIAL_CONTENT)
+				.header(CONTENT_TYPE, content_type)
+				.header(CONTENT_RANGE, range.content_range(size)),
+			VecDeque::from([Segment::File {
+				start: range.start,
+				len: range.length(),
+			}]),
+			range.length(),
+		),
+		Some(ranges) => {
+			let boundary = Uuid::new_v4().simple().to_string();
+			let (segments, content_length) =
+				multipart_byteranges(ranges, size, &file_entry.content_type, &boundary);
+			(
+				response.status(StatusCode::PARTIAL_CONTENT).header(
+					CONTENT_TYPE,
+					format!("multipart/byteranges; boundary={boundary}"),
+				),
+				segments,
+				content_length,
+			)
 		}
-	}
+	};
 
-	if let DownloadCount::Count(max_count) = file_entry.download_count_type {
-		if file_entry.download_count >= max_count {
+	let response = response.header(CONTENT_LENGTH, content_length);
+
+	// Headers only, so neither a download nor a single use link is consumed
+	if req.method() == Method::HEAD {
+		// GET would fail to reserve a download
+		if file_entry.downloads_remaining() == Some(0) {
 			return Err(DownloadError::NotFound.into());
 		}
+		return Ok(response.body(Body::empty())?);
 	}
 
-	debug!(
-		"Increasing download count to {}",
-		file_entry.download_count + 1
+	let single_use_link = match signed_link {
+		Some(ref signed_link)
+			if db
+				.signed_links_reader()
+				.await
+				.is_single_use(&signed_link.nonce) =>
+		{
+			Some(signed_link.nonce)
+		}
+		_ => None,
+	};
+
+	// The slot is committed once the whole response is sent. Failed or interrupted downloads
+	// don't count towards the limit, nor do they use up a single use link. Ranged requests of a
+	// client count once per file size worth of bytes.
+	let ranged_bytes: Option<u64> = ranges
+		.as_ref()
+		.map(|ranges| ranges.iter().map(ByteRange::length).sum());
+	let continued_download = match ranged_bytes {
+		Some(bytes) if single_use_link.is_none() => {
+			db.continue_ranged_download(&uuid, ctx.client_ip, bytes, size)
+		}
+		_ => None,
+	};
+	let download_slot = match continued_download {
+		Some(download_slot) => download_slot,
+		None => {
+			let download_slot = match db.reserve_download(&uuid).await {
+				Ok(slot) => slot,
+				Err(db::DbError::DownloadLimitReached | db::DbError::UpdateFail) => {
+					return Err(DownloadError::NotFound.into())
+				}
+				Err(err) => return Err(err).into_handler_error(),
+			};
+			let download_slot = match single_use_link {
+				Some(nonce) => match download_slot.single_use_link(nonce) {
+					Ok(slot) => slot,
+					Err(_) => return Err(DownloadError::SignedLink(SignedLinkError::InUse).into()),
+				},
+				None => download_slot,
+			};
+			match (ranged_bytes, ctx.client_ip) {
+				(Some(bytes), Some(client)) => download_slot.ranged(client, bytes),
+				_ => download_slot,
+			}
+		}
+	};
+
+	let buckets = db.bandwidth_buckets(
+		current_user.as_ref(),
+		ctx.client_ip,
+		file_entry.bandwidth_limit,
 	);
-	file_entry.download_count += 1;
-	db.update(&uuid, file_entry.clone())
-		.await
-		.into_handler_error()?;
+	let download_event = DownloadEvent::new(req.headers(), ctx.client_ip, current_user.as_ref());
 
-	// Make it immutable to prevent unsaved changes
-	let file_entry = file_entry;
+	Ok(response.body(Body::wrap_stream(Audited::new(
+		Throttled::new(FileStream::new(file, segments, download_slot), buckets),
+		db.clone(),
+		uuid,
+		file_entry.uploader_uuid,
+		download_event,
+	)))?)
+}
 
-	let mut file_path = db.config.db_path.clone();
-	file_path.push(file_entry.download_count_type.to_string());
-	file_path.push(uuid.to_string());
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Disposition {
+	Inline,
+	Attachment,
+}
 
-	if !file_path.exists() {
-		return Err(DownloadError::NotFound.into());
+impl Disposition {
+	/// Reads the `disposition` query parameter. Defaults to [Disposition::Attachment].
+	fn from_query(query: Option<&str>) -> Result<Self, DownloadError> {
+		let disposition = uri_query_iter(query.unwrap_or_default())
+			.find(|(key, _value)| *key == "disposition")
+			.map(|(_key, value)| value);
+		match disposition {
+			None | Some("attachment") => Ok(Disposition::Attachment),
+			Some("inline") => Ok(Disposition::Inline),
+			Some(_) => Err(DownloadError::InvalidDisposition),
+		}
 	}
+}
 
-	let file = tokio::fs::File::open(&file_path)
+/// `Content-Disposition` header value as described in
+/// [RFC 6266](https://www.rfc-editor.org/rfc/rfc6266). The exact filename goes into the
+/// percent-encoded `filename*` parameter, `filename` gets an ASCII-only fallback for old clients.
+fn content_disposition(disposition: Disposition, filename: &str) -> String {
+	let disposition = match disposition {
+		Disposition::Inline => "inline",
+		Disposition::Attachment => "attachment",
+	};
+	let fallback: String = filename
+		.chars()
+		.map(|c| match c {
+			'"' | '\\' | '%' => '_',
+			' ' => ' ',
+			c if c.is_ascii_graphic() => c,
+			_ => '_',
+		})
+		.collect();
+	format!(
+		"{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+		urlencoding::encode(filename)
+	)
+}
+
+/// Strong validator of the entry. Uploaded files never change, so it's derived from the uuid and
+/// the upload date.
+pub fn entity_tag(uuid: &Uuid, file_entry: &FileEntry) -> String {
+	let upload_secs = file_entry
+		.upload_date
+		.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_secs())
+		.unwrap_or_default();
+	format!("\"{}-{upload_secs:x}\"", uuid.simple())
+}
+
+/// Evaluates `If-None-Match` or, if it's not present, `If-Modified-Since`
+fn not_modified(headers: &HeaderMap, etag: &str, upload_date: SystemTime) -> bool {
+	if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+		let Ok(if_none_match) = if_none_match.to_str() else {
+			return false;
+		};
+		// If-None-Match uses the weak comparison
+		return if_none_match
+			.split(',')
+			.map(str::trim)
+			.any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+	}
+
+	let Some(if_modified_since) = headers
+		.get(IF_MODIFIED_SINCE)
+		.and_then(|if_modified_since| if_modified_since.to_str().ok())
+		.and_then(|if_modified_since| httpdate::parse_http_date(if_modified_since).ok())
+	else {
+		return false;
+	};
+	// Dates in headers have a one second resolution
+	let as_secs = |date: SystemTime| {
+		date.duration_since(UNIX_EPOCH)
+			.map(|duration| duration.as_secs())
+			.unwrap_or_default()
+	};
+	as_secs(upload_date) <= as_secs(if_modified_since)
+}
+
+/// Ranges from the `Range` header, unless `If-Range` says the client has a different version
+fn requested_ranges(
+	headers: &HeaderMap,
+	size: u64,
+	etag: &str,
+	upload_date: SystemTime,
+) -> Option<Result<Vec<ByteRange>, Unsatisfiable>> {
+	let range = headers.get(RANGE)?.to_str().ok()?;
+
+	if let Some(if_range) = headers.get(IF_RANGE) {
+		let if_range = if_range.to_str().ok()?.trim();
+		let matches = if if_range.starts_with('"') {
+			if_range == etag
+		} else if if_range.starts_with("W/") {
+			// Weak validators never match
+			false
+		} else {
+			httpdate::parse_http_date(if_range).is_ok_and(|date| {
+				httpdate::fmt_http_date(date) == httpdate::fmt_http_date(upload_date)
+			})
+		};
+		if !matches {
+			return None;
+		}
+	}
+
+	parse_range(range, size)
+}
+
+/// Body of a `multipart/byteranges` response and its length
+fn multipart_byteranges(
+	ranges: &[ByteRange],
+	size: u64,
+	content_type: &str,
+	boundary: &str,
+) -> (VecDeque<Segment>, u64) {
+	let mut segments = VecDeque::with_capacity(ranges.len() * 2 + 1);
+	let mut content_length = 0;
+	for range in ranges {
+		let part_header = format!(
+			"\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+			range.content_range(size)
+		);
+		content_length += part_header.len() as u64 + range.length();
+		segments.push_back(Segment::Bytes(Bytes::from(part_header)));
+		segments.push_back(Segment::File {
+			start: range.start,
+			len: range.length(),
+		});
+	}
+	let end = format!("\r\n--{boundary}--\r\n");
+	content_length += end.len() as u64;
+	segments.push_back(Segment::Bytes(Bytes::from(end)));
+	(segments, content_length)
+}
+
+/// Accepts, in order: the `aqa-password` header, an unlock token obtained from [unlock] or, if
+/// enabled in settings, the deprecated `?password=` query parameter.
+async fn check_password(
+	uuid: &Uuid,
+	file_entry: &FileEntry,
+	password_hash: &PasswordHash,
+	req: &Request<Body>,
+	db: &Db,
+	client_ip: Option<IpAddr>,
+) -> Result<(), DownloadError> {
+	if let Some(password) = req.headers().get(PASSWORD) {
+		let Password(password) = password
+			.try_into()
+			.map_err(|_| DownloadError::InvalidPassword)?;
+		return verify_password(uuid, file_entry, password_hash, &password, db, client_ip).await;
+	}
+
+	let query = req.uri().query().unwrap_or_default();
+
+	if let Some((_, token)) = uri_query_iter(query).find(|(key, _value)| *key == "unlock_token") {
+		let token: Uuid = token.parse().map_err(|_| DownloadError::InvalidPassword)?;
+		if !db.check_unlock_token(&token, uuid) {
+			return Err(DownloadError::InvalidPassword);
+		}
+		return Ok(());
+	}
+
+	if let Some((_, password)) = uri_query_iter(query).find(|(key, _value)| *key == "password") {
+		if !db.config.settings.allow_password_in_query {
+			return Err(DownloadError::InvalidPassword);
+		}
+		warn!("Password for {uuid} passed in the query string, which is deprecated");
+		let password = urlencoding::decode(password).map_err(|_| DownloadError::InvalidPassword)?;
+		return verify_password(uuid, file_entry, password_hash, &password, db, client_ip).await;
+	}
+
+	Err(DownloadError::InvalidPassword)
+}
+
+/// Verifies the password, counting failed attempts for brute-force protection. The attempt is
+/// counted before the hash is checked and given back if the password is correct.
+pub(crate) async fn verify_password(
+	uuid: &Uuid,
+	file_entry: &FileEntry,
+	password_hash: &PasswordHash,
+	password: &str,
+	db: &Db,
+	client_ip: Option<IpAddr>,
+) -> Result<(), DownloadError> {
+	let locked_out = db
+		.begin_password_attempt(uuid, client_ip)
 		.await
-		.map_err(DownloadError::FileSendIo)?;
+		.map_err(DownloadError::TooManyAttempts)?;
 
-	Ok(Response::builder()
-		.header(
-			"Content-Disposition",
-			format!("filename=\"{}\"", file_entry.filename),
+	if tokio::task::block_in_place(|| password_hash.verify(password)) {
+		db.record_password_success(uuid, client_ip).await;
+		return Ok(());
+	}
+
+	if locked_out {
+		warn!("Too many invalid password attempts for {uuid}, locking it");
+		if let Some(uploader) = file_entry.uploader_uuid {
+			db.notify(
+				uploader,
+				Notification {
+					date: SystemTime::now(),
+					message: format!(
+						"Download of \"{}\" was locked due to too many invalid password attempts",
+						file_entry.filename
+					),
+					file_uuid: Some(*uuid),
+				},
+			)
+			.await;
+		}
+	}
+	Err(DownloadError::InvalidPassword)
+}
+
+/// How long an unlock token grants access to a password protected file
+pub const UNLOCK_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 5);
+
+const MAX_UNLOCK_REQUEST_BODY_SIZE: usize = 1024 * 5; // 5 KB
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnlockResponse {
+	pub unlock_token: Uuid,
+	/// Seconds until the token expires
+	pub valid_for: u64,
+}
+
+/// Checks the password sent in a `multipart/form-data` POST request and returns an unlock token,
+/// that can be passed to [download] as the `unlock_token` query parameter.
+pub async fn unlock(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<DownloadError>> {
+	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+	debug!("Unlocking {}", uuid);
+
+	let file_entry: FileEntry = db.get(&uuid).await.ok_or(DownloadError::NotFound)?;
+
+	let (parts, body) = req.into_parts();
+
+	let signed_link = check_signed_link(&uuid, parts.uri.query(), &db)
+		.await
+		.into_handler_error()?;
+	if signed_link.is_none() {
+		let current_user = ctx.user_or_anonymous().into_handler_error()?;
+		if !file_entry.can_download(current_user.as_ref(), &*db.groups_reader().await) {
+			return Ok(Response::builder()
+				.status(StatusCode::UNAUTHORIZED)
+				.body(Body::empty())?);
+		}
+	}
+
+	let boundary = multipart::get_boundary_from_req(parts).map_err(DownloadError::from)?;
+	let mut multipart = Multipart::new(body, boundary, MAX_UNLOCK_REQUEST_BODY_SIZE);
+	let chunks = multipart.read_all_chunks().await.into_handler_error()?;
+
+	let password = chunks
+		.iter()
+		.find(|(header, _data)| header.name == "password")
+		.map(|(_header, data)| std::str::from_utf8(data))
+		.ok_or(DownloadError::ExpectedPassword)?
+		.map_err(|_| DownloadError::InvalidPassword)?;
+
+	if let Some(ref password_hash) = file_entry.password {
+		verify_password(
+			&uuid,
+			&file_entry,
+			password_hash,
+			password,
+			&db,
+			ctx.client_ip,
 		)
-		.status(StatusCode::OK)
-		.body(Body::wrap_stream(FileStream::new(file)))?)
+		.await?;
+	}
+
+	let unlock_token = db.create_unlock_token(uuid, UNLOCK_TOKEN_LIFETIME);
+	json_response(
+		StatusCode::CREATED,
+		&UnlockResponse {
+			unlock_token,
+			valid_for: UNLOCK_TOKEN_LIFETIME.as_secs(),
+		},
+	)
+}
+
+enum Segment {
+	Bytes(Bytes),
+	File { start: u64, len: u64 },
 }
 
+/// Size of a single read from the file.
+///
+/// hyper asks for the next chunk only once the connection can take more data, so this also bounds
+/// the memory used by a download, no matter how slow the client is.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams segments of a file. Response bodies of hyper are sent from user space buffers, so
+/// there's no way to use `sendfile(2)` here.
 struct FileStream {
 	file: tokio::fs::File,
 	buffer: BytesMut,
+	segments: VecDeque<Segment>,
+	/// Bytes left to send from the current file segment
+	remaining: u64,
+	seeking: bool,
+	download_slot: Option<DownloadSlot>,
+	commit: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
 }
 
 impl FileStream {
-	fn new(file: tokio::fs::File) -> Self {
+	fn new(
+		file: tokio::fs::File,
+		segments: VecDeque<Segment>,
+		download_slot: DownloadSlot,
+	) -> Self {
 		FileStream {
 			file,
-			buffer: BytesMut::with_capacity(1024 * 1024 * 10),
+			buffer: BytesMut::new(),
+			segments,
+			remaining: 0,
+			seeking: false,
+			download_slot: Some(download_slot),
+			commit: None,
 		}
 	}
+
+	fn fail(&mut self, err: std::io::Error) -> Poll<Option<Result<Bytes, std::io::Error>>> {
+		// Dropping the slot releases it
+		self.download_slot = None;
+		self.segments.clear();
+		self.remaining = 0;
+		Poll::Ready(Some(Err(err)))
+	}
 }
 
 impl Stream for FileStream {
 	type Item = Result<Bytes, std::io::Error>;
 
 	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-		let mut fut = async move {
-			let this = self.get_mut();
-			match this.file.read_buf(&mut this.buffer).await {
-				Ok(0) => None,
-				Ok(count) => Some(Ok(this.buffer.split_to(count).freeze())),
-				Err(err) => Some(Err(err)),
+		let this = self.get_mut();
+
+		if let Some(commit) = this.commit.as_mut() {
+			ready!(commit.as_mut().poll(cx));
+			this.commit = None;
+			return Poll::Ready(None);
+		}
+
+		loop {
+			if this.seeking {
+				if let Err(err) = ready!(Pin::new(&mut this.file).poll_complete(cx)) {
+					return this.fail(err);
+				}
+				this.seeking = false;
 			}
-		};
-		(unsafe { Pin::new_unchecked(&mut fut) }).poll(cx)
+
+			if this.remaining > 0 {
+				break;
+			}
+
+			match this.segments.pop_front() {
+				Some(Segment::Bytes(bytes)) => return Poll::Ready(Some(Ok(bytes))),
+				Some(Segment::File { start, len }) => {
+					if let Err(err) = Pin::new(&mut this.file).start_seek(SeekFrom::Start(start)) {
+						return this.fail(err);
+					}
+					this.seeking = true;
+					this.remaining = len;
+				}
+				None => {
+					return match this.download_slot.take() {
+						Some(download_slot) => {
+							this.commit = Some(Box::pin(async move {
+								match download_slot.commit().await {
+									Ok(count) => debug!("Download count increased to {count}"),
+									Err(err) => error!("Failed to commit download: {err:?}"),
+								}
+							}));
+							Pin::new(this).poll_next(cx)
+						}
+						None => Poll::Ready(None),
+					};
+				}
+			}
+		}
+
+		// Chunks handed out earlier may still be in use, in which case a new buffer is allocated
+		this.buffer.reserve(CHUNK_SIZE);
+		let limit = this.remaining.min(CHUNK_SIZE as u64) as usize;
+		let read = ready!(poll_read_buf(
+			Pin::new(&mut this.file),
+			cx,
+			&mut (&mut this.buffer).limit(limit),
+		));
+
+		match read {
+			Ok(0) => this.fail(std::io::ErrorKind::UnexpectedEof.into()),
+			Err(err) => this.fail(err),
+			Ok(count) => {
+				this.remaining -= count as u64;
+				Poll::Ready(Some(Ok(this.buffer.split_to(count).freeze())))
+			}
+		}
 	}
 }
@@ -0,0 +1,93 @@
+use hyper::{Body, Request, Response, StatusCode};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::FileEntry;
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+use crate::list::FileModel;
+use crate::signed_links::{check_signed_link, SignedLinkError};
+
+#[derive(Debug, Error)]
+pub enum EntryError {
+	#[error("File id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	SignedLink(#[from] SignedLinkError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("File id not found or not present")]
+	NotFound,
+
+	#[error("You're not allowed to access this file")]
+	NotAuthorized,
+}
+
+impl HttpHandlerError for EntryError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::SignedLink(err) => err.code(),
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::SignedLink(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+/// Metadata of a single entry. Access rules are the same as for downloading it, but no download
+/// is consumed and the password isn't required.
+pub async fn entry(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<EntryError>> {
+	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let file_entry: FileEntry = db
+		.get(&uuid)
+		.await
+		.filter(FileEntry::is_available)
+		.ok_or(EntryError::NotFound)?;
+
+	let current_user = ctx.user_or_anonymous().into_handler_error()?;
+
+	// A valid signed link is accepted in place of a session
+	let signed_link = check_signed_link(&uuid, req.uri().query(), &db)
+		.await
+		.into_handler_error()?;
+	if signed_link.is_none()
+		&& !file_entry.can_download(current_user.as_ref(), &*db.groups_reader().await)
+	{
+		return Err(EntryError::NotAuthorized.into());
+	}
+
+	let file_model = FileModel::new(uuid, &file_entry, current_user.as_ref());
+
+	json_response(StatusCode::OK, &file_model)
+}
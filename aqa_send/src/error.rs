@@ -1,10 +1,10 @@
 use std::fmt::Display;
 
-use hyper::{Body, Response, StatusCode};
+use hyper::{Body, HeaderMap, Response, StatusCode};
 use serde::Serialize;
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Field {
 	Cookie,
 	ContentType,
@@ -26,6 +26,11 @@ where
 		ErrorContentType::PlainText
 	}
 
+	/// Additional headers to send with the error response
+	fn headers(&self) -> HeaderMap {
+		HeaderMap::new()
+	}
+
 	fn response(&self) -> Response<Body> {
 		let message = if self.user_presentable() {
 			self.to_string()
@@ -52,7 +57,9 @@ where
 			}
 		};
 
-		Response::builder().status(self.code()).body(body).unwrap()
+		let mut response = Response::builder().status(self.code()).body(body).unwrap();
+		response.headers_mut().extend(self.headers());
+		response
 	}
 }
 
@@ -70,6 +77,20 @@ where
 	}
 }
 
+/// Pretty printed JSON response of a handler
+pub fn json_response<T, E>(status: StatusCode, value: &T) -> Result<Response<Body>, HandlerError<E>>
+where
+	T: Serialize,
+	E: HttpHandlerError + From<serde_json::Error>,
+{
+	let resp_body = serde_json::to_vec_pretty(value).into_handler_error()?;
+
+	Ok(Response::builder()
+		.status(status)
+		.header("Content-Type", "application/json")
+		.body(Body::from(resp_body))?)
+}
+
 #[derive(Copy, Clone)]
 pub enum ErrorContentType {
 	PlainText,
@@ -117,14 +138,11 @@ where
 	fn content_type() -> ErrorContentType {
 		<Err as HttpHandlerError>::content_type()
 	}
-}
 
-#[derive(Debug, Error)]
-enum ServerLayerError {
-	#[error("")]
-	Http(#[from] hyper::http::Error),
-	#[error("")]
-	Hyper(#[from] hyper::Error),
+	fn headers(&self) -> HeaderMap {
+		match self {
+			HandlerError::Http(_) | HandlerError::Hyper(_) => HeaderMap::new(),
+			HandlerError::Handler(err) => err.headers(),
+		}
+	}
 }
-
-impl HttpHandlerError for ServerLayerError {}
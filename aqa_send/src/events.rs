@@ -0,0 +1,247 @@
+//! Changes of entries published to live subscribers
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::db::GroupsHM;
+use crate::db_stuff::{Account, FileEntry};
+use crate::list::FileModel;
+
+/// Events a subscriber may fall behind by before it's disconnected
+const EVENT_BUS_CAPACITY: usize = 256;
+
+/// Recent events kept for subscribers resuming after a disconnect
+const REPLAY_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+	Created,
+	Downloaded,
+	Updated,
+	Deleted,
+	/// Removed by the cleanup task after running out of downloads
+	UsedUp,
+	/// Removed by the cleanup task after running out of lifetime
+	Expired,
+}
+
+impl EventKind {
+	pub const ALL: [EventKind; 6] = [
+		EventKind::Created,
+		EventKind::Downloaded,
+		EventKind::Updated,
+		EventKind::Deleted,
+		EventKind::UsedUp,
+		EventKind::Expired,
+	];
+
+	/// Name used in JSON
+	pub fn name(self) -> &'static str {
+		match self {
+			EventKind::Created => "created",
+			EventKind::Downloaded => "downloaded",
+			EventKind::Updated => "updated",
+			EventKind::Deleted => "deleted",
+			EventKind::UsedUp => "used_up",
+			EventKind::Expired => "expired",
+		}
+	}
+}
+
+impl FromStr for EventKind {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		EventKind::ALL
+			.into_iter()
+			.find(|kind| kind.name() == s)
+			.ok_or(())
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+	/// Increases by one with every published event
+	pub id: u64,
+	pub kind: EventKind,
+	pub uuid: Uuid,
+	/// State of the entry after the change, or right before it was removed
+	pub entry: Arc<FileEntry>,
+}
+
+impl Event {
+	/// Subscribers get the events of entries they would see in `list.json`
+	pub fn is_visible_to(&self, viewer: Option<&Account>, groups: &GroupsHM) -> bool {
+		self.entry.is_listed_for(viewer, groups)
+	}
+
+	pub fn model(&self, viewer: Option<&Account>) -> EventModel<'_> {
+		let removed = matches!(
+			self.kind,
+			EventKind::Deleted | EventKind::UsedUp | EventKind::Expired
+		);
+		EventModel {
+			id: self.id,
+			kind: self.kind,
+			uuid: self.uuid,
+			entry: (!removed).then(|| FileModel::new(self.uuid, &self.entry, viewer)),
+		}
+	}
+}
+
+/// JSON representation of an [Event]
+#[derive(Serialize, Deserialize)]
+pub struct EventModel<'a> {
+	pub id: u64,
+	#[serde(rename = "type")]
+	pub kind: EventKind,
+	pub uuid: Uuid,
+	/// `None` for removed entries
+	pub entry: Option<FileModel<'a>>,
+}
+
+#[derive(Debug)]
+pub struct EventBus {
+	sender: broadcast::Sender<Event>,
+	/// Events are sent while holding the lock, so ids arrive in order and subscribers resuming
+	/// from the replay buffer don't miss or repeat any
+	log: Mutex<EventLog>,
+}
+
+#[derive(Debug)]
+struct EventLog {
+	next_id: u64,
+	replay: VecDeque<Event>,
+}
+
+/// Events published after the given id, followed by live events
+pub struct Subscription {
+	pub replay: Vec<Event>,
+	/// The events following the given id aren't available anymore, the subscriber has to
+	/// reload the entries
+	pub gap: bool,
+	pub receiver: broadcast::Receiver<Event>,
+}
+
+impl Default for EventBus {
+	fn default() -> Self {
+		let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+		EventBus {
+			sender,
+			log: Mutex::new(EventLog {
+				next_id: 1,
+				replay: VecDeque::with_capacity(REPLAY_CAPACITY),
+			}),
+		}
+	}
+}
+
+impl EventBus {
+	pub fn publish(&self, kind: EventKind, uuid: Uuid, entry: FileEntry) {
+		let mut log = self.log.lock().unwrap();
+		let event = Event {
+			id: log.next_id,
+			kind,
+			uuid,
+			entry: Arc::new(entry),
+		};
+		log.next_id += 1;
+		if log.replay.len() == REPLAY_CAPACITY {
+			log.replay.pop_front();
+		}
+		log.replay.push_back(event.clone());
+		// Fails only if nobody is subscribed
+		let _ = self.sender.send(event);
+	}
+
+	pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+		self.sender.subscribe()
+	}
+
+	/// Resumes from the event with `last_id`
+	pub fn subscribe_since(&self, last_id: u64) -> Subscription {
+		let log = self.log.lock().unwrap();
+		let receiver = self.sender.subscribe();
+
+		let oldest_id = log.replay.front().map_or(log.next_id, |event| event.id);
+		// Ids start over after a restart, so ids that weren't given out yet are unknown too
+		let gap = last_id.saturating_add(1) < oldest_id || last_id >= log.next_id;
+		let replay = if gap {
+			Vec::new()
+		} else {
+			log.replay
+				.iter()
+				.filter(|event| event.id > last_id)
+				.cloned()
+				.collect()
+		};
+
+		Subscription {
+			replay,
+			gap,
+			receiver,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::headers::{DownloadCount, Lifetime, Visibility};
+	use std::time::SystemTime;
+
+	fn entry() -> FileEntry {
+		FileEntry {
+			filename: String::from("a.txt"),
+			content_type: String::from("text/plain"),
+			uploader_uuid: None,
+			download_count_type: DownloadCount::Infinite,
+			download_count: 0,
+			size: Some(1),
+			visibility: Visibility::Public,
+			password: None,
+			shared_with: Vec::new(),
+			shared_with_groups: Vec::new(),
+			bandwidth_limit: None,
+			lifetime: Lifetime::Infinite,
+			upload_date: SystemTime::now(),
+		}
+	}
+
+	#[test]
+	fn resuming_from_replay_buffer() {
+		let bus = EventBus::default();
+		let ids = |subscription: &Subscription| -> Vec<u64> {
+			subscription.replay.iter().map(|event| event.id).collect()
+		};
+
+		let subscription = bus.subscribe_since(0);
+		assert!(!subscription.gap);
+		assert!(subscription.replay.is_empty());
+
+		for _ in 0..REPLAY_CAPACITY + 10 {
+			bus.publish(EventKind::Created, Uuid::new_v4(), entry());
+		}
+		let last_id = (REPLAY_CAPACITY + 10) as u64;
+
+		let subscription = bus.subscribe_since(last_id - 2);
+		assert!(!subscription.gap);
+		assert_eq!(ids(&subscription), [last_id - 1, last_id]);
+
+		let subscription = bus.subscribe_since(last_id);
+		assert!(!subscription.gap);
+		assert!(subscription.replay.is_empty());
+
+		// The oldest events were dropped from the buffer
+		assert!(!bus.subscribe_since(10).gap);
+		assert!(bus.subscribe_since(9).gap);
+		// Ids from before a restart
+		assert!(bus.subscribe_since(last_id + 1).gap);
+	}
+}
@@ -0,0 +1,200 @@
+//! Atom feeds of public uploads
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response, StatusCode};
+use thiserror::Error;
+use uuid::{uuid, Uuid};
+
+use crate::db::Db;
+use crate::db_stuff::FileEntry;
+use crate::error::{HandlerError, HttpHandlerError};
+use crate::landing::{escape_html, format_size};
+
+/// Number of the newest uploads included in a feed
+const FEED_LENGTH: usize = 50;
+
+/// Id of the public feed. Feeds of users are identified by the account uuid.
+const PUBLIC_FEED_ID: Uuid = uuid!("5b0f7e4c-3c1e-4d0a-9a53-0c6a8f3e2d71");
+
+#[derive(Debug, Error)]
+pub enum FeedError {
+	#[error("User not found")]
+	UserNotFound,
+}
+
+impl HttpHandlerError for FeedError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::UserNotFound => StatusCode::NOT_FOUND,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		true
+	}
+}
+
+/// Public uploads of everyone
+pub async fn public_feed(
+	_req: Request<Body>,
+	db: Db,
+) -> Result<Response<Body>, HandlerError<FeedError>> {
+	let feed = render(
+		&db,
+		PUBLIC_FEED_ID,
+		"aqaSend public uploads",
+		"/feeds/public.atom",
+		None,
+	)
+	.await;
+	feed_response(feed)
+}
+
+/// Public uploads of a single account. `file_name` is the last path segment, `<username>.atom`.
+pub async fn user_feed(
+	file_name: String,
+	_req: Request<Body>,
+	db: Db,
+) -> Result<Response<Body>, HandlerError<FeedError>> {
+	let username = file_name
+		.strip_suffix(".atom")
+		.and_then(|username| urlencoding::decode(username).ok())
+		.ok_or(FeedError::UserNotFound)?;
+	let account_uuid = db
+		.account_uuids_reader()
+		.await
+		.get(username.as_ref())
+		.copied()
+		.ok_or(FeedError::UserNotFound)?;
+
+	let feed = render(
+		&db,
+		account_uuid,
+		&format!("Uploads of {username}"),
+		&format!("/feeds/user/{}.atom", urlencoding::encode(&username)),
+		Some(account_uuid),
+	)
+	.await;
+	feed_response(feed)
+}
+
+fn feed_response(feed: String) -> Result<Response<Body>, HandlerError<FeedError>> {
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header(CONTENT_TYPE, "application/atom+xml; charset=utf-8")
+		.body(Body::from(feed))?)
+}
+
+/// Renders the newest entries visible to anyone who isn't logged in, i.e. the same entries
+/// `list.json` shows to them
+async fn render(
+	db: &Db,
+	feed_id: Uuid,
+	title: &str,
+	self_path: &str,
+	uploader: Option<Uuid>,
+) -> String {
+	// Without a configured address, links are relative to the feed
+	let base = escape_html(
+		db.config
+			.settings
+			.public_url
+			.as_deref()
+			.unwrap_or_default()
+			.trim_end_matches('/'),
+	);
+
+	let groups = db.groups_reader().await;
+	let accounts = db.accounts_reader().await;
+	let db_reader = db.reader().await;
+	let mut entries: Vec<(&Uuid, &FileEntry)> = db_reader
+		.iter()
+		.filter(|(_uuid, entry)| entry.is_listed_for(None, &groups))
+		.filter(|(_uuid, entry)| uploader.is_none() || entry.uploader_uuid == uploader)
+		.filter(|(_uuid, entry)| entry.is_available())
+		.collect();
+	entries.sort_unstable_by_key(|(_uuid, entry)| Reverse(entry.upload_date));
+	entries.truncate(FEED_LENGTH);
+
+	let updated = entries
+		.first()
+		.map(|(_uuid, entry)| entry.upload_date)
+		.unwrap_or(UNIX_EPOCH);
+
+	let mut feed = String::with_capacity(1024 + entries.len() * 1024);
+	let _ = write!(
+		feed,
+		r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<id>urn:uuid:{feed_id}</id>
+<title>{}</title>
+<updated>{}</updated>
+<author><name>aqaSend</name></author>
+<link rel="self" type="application/atom+xml" href="{base}{}"/>
+"#,
+		escape_html(title),
+		rfc3339(updated),
+		escape_html(self_path),
+	);
+
+	for (uuid, entry) in entries {
+		let uploader_name = entry
+			.uploader_uuid
+			.and_then(|uploader| accounts.get(&uploader))
+			.map(|account| account.username.as_str());
+		let mut summary = Vec::new();
+		if let Some(size) = entry.size {
+			summary.push(format_size(size));
+		}
+		summary.push(escape_html(&entry.content_type));
+		if entry.password.is_some() {
+			summary.push(String::from("password protected"));
+		}
+
+		let _ = write!(
+			feed,
+			r#"<entry>
+<id>urn:uuid:{uuid}</id>
+<title>{}</title>
+<published>{upload_date}</published>
+<updated>{upload_date}</updated>
+"#,
+			escape_html(&entry.filename),
+			upload_date = rfc3339(entry.upload_date),
+		);
+		if let Some(uploader_name) = uploader_name {
+			let _ = writeln!(
+				feed,
+				"<author><name>{}</name></author>",
+				escape_html(uploader_name)
+			);
+		}
+		let _ = writeln!(
+			feed,
+			r#"<link rel="alternate" type="text/html" href="{base}/d/{uuid}"/>"#
+		);
+		let _ = write!(
+			feed,
+			r#"<link rel="enclosure" type="{}" href="{base}/api/download/{uuid}""#,
+			escape_html(&entry.content_type),
+		);
+		if let Some(size) = entry.size {
+			let _ = write!(feed, r#" length="{size}""#);
+		}
+		let _ = write!(
+			feed,
+			"/>\n<summary>{}</summary>\n</entry>\n",
+			summary.join(" · ")
+		);
+	}
+	feed.push_str("</feed>\n");
+	feed
+}
+
+fn rfc3339(date: SystemTime) -> String {
+	humantime::format_rfc3339_seconds(date).to_string()
+}
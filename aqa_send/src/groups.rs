@@ -0,0 +1,263 @@
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::{AccountType, Group};
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+use crate::multipart::{self, Multipart, MultipartError};
+
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 5; // 5 KB
+
+#[derive(Debug, Error)]
+pub enum GroupsError {
+	#[error("Group id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Boundary(#[from] multipart::GetBoundaryError),
+
+	#[error(transparent)]
+	Multipart(#[from] MultipartError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("Only admins and the group owner can manage the group")]
+	NotAuthorized,
+
+	#[error("Group not found")]
+	NotFound,
+
+	#[error("Account {0:?} doesn't exist")]
+	UnknownAccount(String),
+
+	#[error("Group name is missing from request body")]
+	ExpectedName,
+
+	#[error("Group name must be valid utf-8 and can't be empty")]
+	InvalidName,
+
+	#[error("Group with this name already exists")]
+	NameTaken,
+
+	#[error("Group owner can't be removed from the group")]
+	RemovingOwner,
+}
+
+impl HttpHandlerError for GroupsError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::Boundary(err) => err.code(),
+			Self::Multipart(_) => StatusCode::BAD_REQUEST,
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotLoggedIn => StatusCode::FORBIDDEN,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::UnknownAccount(_) => StatusCode::BAD_REQUEST,
+			Self::ExpectedName => StatusCode::BAD_REQUEST,
+			Self::InvalidName => StatusCode::BAD_REQUEST,
+			Self::NameTaken => StatusCode::CONFLICT,
+			Self::RemovingOwner => StatusCode::BAD_REQUEST,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Boundary(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+/// Lists groups the current user is a member of. Admins see all groups.
+pub async fn list_groups(
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<GroupsError>> {
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(GroupsError::NotLoggedIn)?;
+
+	let groups = db.groups_reader().await;
+	let groups: Vec<&Group> = groups
+		.values()
+		.filter(|group| {
+			matches!(current_user.acc_type, AccountType::Admin)
+				|| group.is_member(&current_user.uuid)
+		})
+		.collect();
+
+	json_response(StatusCode::OK, &groups)
+}
+
+/// Creates a group from a `multipart/form-data` request with a `name` field. The creator becomes
+/// the group owner.
+pub async fn create_group(
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<GroupsError>> {
+	let (parts, body) = req.into_parts();
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(GroupsError::NotLoggedIn)?;
+
+	let boundary = multipart::get_boundary_from_req(parts).map_err(GroupsError::from)?;
+	let mut multipart = Multipart::new(body, boundary, MAX_REQUEST_BODY_SIZE);
+	let chunks = multipart.read_all_chunks().await.into_handler_error()?;
+
+	let name = chunks
+		.iter()
+		.find(|(header, _data)| header.name == "name")
+		.map(|(_header, data)| std::str::from_utf8(data))
+		.ok_or(GroupsError::ExpectedName)?
+		.map_err(|_| GroupsError::InvalidName)?
+		.trim();
+	if name.is_empty() {
+		return Err(GroupsError::InvalidName.into());
+	}
+
+	let mut groups = db.groups_writer().await;
+	if groups.values().any(|group| group.name == name) {
+		return Err(GroupsError::NameTaken.into());
+	}
+
+	let group = Group {
+		uuid: Uuid::new_v4(),
+		name: name.to_string(),
+		owner: current_user.uuid,
+		members: Vec::new(),
+	};
+	debug!("Creating group {} ({})", group.name, group.uuid);
+	groups.insert(group.uuid, group.clone());
+	drop(groups);
+
+	json_response(StatusCode::CREATED, &group)
+}
+
+pub async fn delete_group(
+	group_uuid: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<GroupsError>> {
+	let group_uuid = Uuid::parse_str(&group_uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(GroupsError::NotLoggedIn)?;
+
+	let can_manage = db
+		.groups_reader()
+		.await
+		.get(&group_uuid)
+		.ok_or(GroupsError::NotFound)?
+		.can_manage(&current_user);
+	if !can_manage {
+		return Err(GroupsError::NotAuthorized.into());
+	}
+
+	debug!("Removing group {group_uuid}");
+	db.remove_group(&group_uuid).await;
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.body(Body::empty())?)
+}
+
+/// Adds the account, given by its username or uuid, to the group
+pub async fn add_member(
+	group_uuid: String,
+	account: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<GroupsError>> {
+	update_members(group_uuid, account, db, ctx, |group, account| {
+		if !group.members.contains(&account) {
+			group.members.push(account);
+		}
+		Ok(())
+	})
+	.await
+}
+
+/// Removes the account, given by its username or uuid, from the group
+pub async fn remove_member(
+	group_uuid: String,
+	account: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<GroupsError>> {
+	update_members(group_uuid, account, db, ctx, |group, account| {
+		if group.owner == account {
+			return Err(GroupsError::RemovingOwner);
+		}
+		group.members.retain(|member| *member != account);
+		Ok(())
+	})
+	.await
+}
+
+async fn update_members(
+	group_uuid: String,
+	account: String,
+	db: Db,
+	ctx: RequestContext,
+	update: impl FnOnce(&mut Group, Uuid) -> Result<(), GroupsError>,
+) -> Result<Response<Body>, HandlerError<GroupsError>> {
+	let group_uuid = Uuid::parse_str(&group_uuid).into_handler_error()?;
+	let account = urlencoding::decode(&account)
+		.map_err(|_| GroupsError::UnknownAccount(account.clone()))?
+		.into_owned();
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(GroupsError::NotLoggedIn)?;
+
+	let account_uuid = db
+		.find_account_uuid(&account)
+		.await
+		.ok_or(GroupsError::UnknownAccount(account))?;
+
+	let mut groups = db.groups_writer().await;
+	let group = groups.get_mut(&group_uuid).ok_or(GroupsError::NotFound)?;
+	if !group.can_manage(&current_user) {
+		return Err(GroupsError::NotAuthorized.into());
+	}
+
+	update(group, account_uuid)?;
+	debug!("Group {group_uuid} members: {:?}", group.members);
+
+	let group = group.clone();
+	drop(groups);
+
+	json_response(StatusCode::OK, &group)
+}
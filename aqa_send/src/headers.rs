@@ -2,6 +2,9 @@
 use std::fmt::Formatter;
 use std::time::Duration;
 
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, PasswordHasher, PasswordVerifier};
 use hyper::http::HeaderValue;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -13,6 +16,23 @@ pub const VISIBILITY: &str = "aqa-visibility";
 pub const DOWNLOAD_COUNT: &str = "aqa-download-count";
 pub const PASSWORD: &str = "aqa-password";
 pub const LIFETIME: &str = "aqa-lifetime";
+pub const SHARE_WITH: &str = "aqa-share-with";
+pub const SHARE_WITH_GROUPS: &str = "aqa-share-with-groups";
+pub const BANDWIDTH_LIMIT: &str = "aqa-bandwidth-limit";
+/// Response header of downloads with a limited number of downloads
+pub const DOWNLOADS_REMAINING: &str = "aqa-downloads-remaining";
+/// Response header of downloads with a limited lifetime, in the HTTP date format
+pub const EXPIRES_AT: &str = "aqa-expires-at";
+/// Response header of `list.json` pages followed by more entries
+pub const NEXT_CURSOR: &str = "aqa-next-cursor";
+/// Identifies a request in the logs. Taken from trusted proxies, generated otherwise.
+pub const REQUEST_ID: &str = "x-request-id";
+/// Event type of a webhook request, see [crate::webhooks]
+pub const WEBHOOK_EVENT: &str = "aqa-event";
+/// Id of a webhook delivery, the same for all of its attempts
+pub const WEBHOOK_DELIVERY: &str = "aqa-delivery";
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of the body, keyed with the webhook secret
+pub const WEBHOOK_SIGNATURE: &str = "aqa-signature";
 
 #[derive(Debug, Error)]
 pub enum HeaderError {
@@ -33,6 +53,16 @@ pub enum HeaderError {
 	VisibilityParse,
 	#[error("Invalid aqa-lifetime header value")]
 	LifetimeParse,
+	#[error("Either aqa-share-with or aqa-share-with-groups header is required")]
+	ShareWithHeaderMissing,
+	#[error("Invalid aqa-share-with header value. Expected comma separated, url encoded usernames or account ids")]
+	ShareWithParse,
+	#[error("Invalid aqa-share-with-groups header value. Expected comma separated, url encoded group names or ids")]
+	ShareWithGroupsParse,
+	#[error("aqa-bandwidth-limit header missing")]
+	BandwidthLimitHeaderMissing,
+	#[error("Invalid aqa-bandwidth-limit header value. Possible values: [default|unlimited|<bytes per second>]")]
+	BandwidthLimitParse,
 }
 
 impl HttpHandlerError for HeaderError {
@@ -49,6 +79,8 @@ impl HttpHandlerError for HeaderError {
 pub enum Visibility {
 	#[default]
 	Public,
+	/// Downloadable by anyone with the link, but listed only to the uploader
+	Unlisted,
 	Private,
 }
 
@@ -67,6 +99,72 @@ impl Default for DownloadCount {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Password(pub String);
 
+impl Password {
+	pub fn hash(&self) -> Result<PasswordHash, argon2::password_hash::Error> {
+		let salt = SaltString::generate(&mut OsRng);
+		let hash = Argon2::default()
+			.hash_password(self.0.as_bytes(), &salt)?
+			.to_string();
+		Ok(PasswordHash(hash))
+	}
+}
+
+/// Argon2 hash of a file [Password] in the PHC string format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordHash(pub String);
+
+impl PasswordHash {
+	/// Checks the password in constant time
+	pub fn verify(&self, password: &str) -> bool {
+		match argon2::PasswordHash::new(&self.0) {
+			Ok(hash) => Argon2::default()
+				.verify_password(password.as_bytes(), &hash)
+				.is_ok(),
+			Err(_) => false,
+		}
+	}
+
+	/// Entries saved before passwords were hashed contain the password in plaintext. Anything
+	/// other than an argon2 hash counts as one, even if it looks like a PHC string.
+	pub fn is_plaintext(&self) -> bool {
+		match argon2::PasswordHash::new(&self.0) {
+			Ok(hash) => Algorithm::try_from(hash.algorithm).is_err(),
+			Err(_) => true,
+		}
+	}
+}
+
+/// Usernames or account uuids an entry is shared with
+#[derive(Debug, Clone, Default)]
+pub struct ShareWith(pub Vec<String>);
+
+/// Group names or uuids an entry is shared with
+#[derive(Debug, Clone, Default)]
+pub struct ShareWithGroups(pub Vec<String>);
+
+/// Bandwidth limit of an entry, set by an admin in place of the limits from settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BandwidthLimit {
+	Unlimited,
+	BytesPerSecond(u64),
+}
+
+/// Parses the `aqa-bandwidth-limit` header. `default` removes the override.
+pub fn parse_bandwidth_limit(
+	v: Option<&HeaderValue>,
+) -> Result<Option<BandwidthLimit>, HeaderError> {
+	let v = v.ok_or(HeaderError::BandwidthLimitHeaderMissing)?;
+	let v = v.to_str().map_err(|_| HeaderError::BandwidthLimitParse)?;
+	match v.trim() {
+		"default" => Ok(None),
+		"unlimited" => Ok(Some(BandwidthLimit::Unlimited)),
+		v => match v.parse() {
+			Ok(0) | Err(_) => Err(HeaderError::BandwidthLimitParse),
+			Ok(bytes_per_second) => Ok(Some(BandwidthLimit::BytesPerSecond(bytes_per_second))),
+		},
+	}
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Lifetime {
@@ -148,8 +246,63 @@ impl TryFrom<Option<&HeaderValue>> for Visibility {
 	fn try_from(v: Option<&HeaderValue>) -> Result<Self, Self::Error> {
 		match v.map(|x| x.to_str()) {
 			Some(Ok("public")) | None => Ok(Visibility::Public),
+			Some(Ok("unlisted")) => Ok(Visibility::Unlisted),
 			Some(Ok("private")) => Ok(Visibility::Private),
 			_ => Err(HeaderError::VisibilityParse),
 		}
 	}
 }
+
+impl TryFrom<&HeaderValue> for ShareWith {
+	type Error = HeaderError;
+
+	fn try_from(v: &HeaderValue) -> Result<Self, Self::Error> {
+		parse_name_list(v)
+			.map(ShareWith)
+			.ok_or(HeaderError::ShareWithParse)
+	}
+}
+
+impl TryFrom<&HeaderValue> for ShareWithGroups {
+	type Error = HeaderError;
+
+	fn try_from(v: &HeaderValue) -> Result<Self, Self::Error> {
+		parse_name_list(v)
+			.map(ShareWithGroups)
+			.ok_or(HeaderError::ShareWithGroupsParse)
+	}
+}
+
+/// Parses comma separated, url encoded values
+fn parse_name_list(v: &HeaderValue) -> Option<Vec<String>> {
+	v.to_str()
+		.ok()?
+		.split(',')
+		.map(str::trim)
+		.filter(|name| !name.is_empty())
+		.map(|name| urlencoding::decode(name).ok().map(|name| name.into_owned()))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_argon2_hashes_are_not_plaintext() {
+		let hash = Password(String::from("alamakota")).hash().unwrap();
+		assert!(!hash.is_plaintext());
+		assert!(hash.verify("alamakota"));
+
+		for plaintext in [
+			"alamakota",
+			"$foo$bar",
+			"$pbkdf2-sha256$i=1000$c2FsdA$aGFzaA",
+		] {
+			assert!(
+				PasswordHash(plaintext.to_string()).is_plaintext(),
+				"{plaintext}"
+			);
+		}
+	}
+}
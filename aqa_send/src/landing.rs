@@ -0,0 +1,365 @@
+//! Server rendered page shared in place of a raw download link
+
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use hyper::http::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use log::debug;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::FileEntry;
+use crate::download::{self, DownloadError, UNLOCK_TOKEN_LIFETIME};
+use crate::error::{ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError};
+use crate::multipart::{self, Multipart, MultipartError};
+use crate::signed_links::{check_signed_link, SignedLinkError};
+
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 5; // 5 KB
+
+/// Scripts aren't needed, the password form is a plain HTML form
+const CONTENT_SECURITY_POLICY: &str =
+	"default-src 'none'; style-src 'unsafe-inline'; form-action 'self'; frame-ancestors 'none'";
+
+#[derive(Debug, Error)]
+pub enum LandingError {
+	#[error("File id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	SignedLink(#[from] SignedLinkError),
+
+	#[error(transparent)]
+	Download(#[from] DownloadError),
+
+	#[error(transparent)]
+	Boundary(#[from] multipart::GetBoundaryError),
+
+	#[error(transparent)]
+	Multipart(#[from] MultipartError),
+
+	#[error("File not found. It may have expired or run out of downloads.")]
+	NotFound,
+
+	#[error("You're not allowed to access this file")]
+	NotAuthorized,
+}
+
+impl HttpHandlerError for LandingError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::SignedLink(err) => err.code(),
+			Self::Download(err) => err.code(),
+			Self::Boundary(err) => err.code(),
+			Self::Multipart(_) => StatusCode::BAD_REQUEST,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::SignedLink(err) => err.user_presentable(),
+			Self::Download(err) => err.user_presentable(),
+			Self::Boundary(err) => err.user_presentable(),
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Http
+	}
+
+	fn headers(&self) -> HeaderMap {
+		let mut headers = match self {
+			Self::Download(err) => err.headers(),
+			_ => HeaderMap::new(),
+		};
+		headers.insert(
+			CONTENT_TYPE,
+			HeaderValue::from_static("text/html; charset=utf-8"),
+		);
+		headers
+	}
+}
+
+/// Renders a page with details of the entry, a download button and, for password protected
+/// entries, a password form. Doesn't count as a download, so link previews don't use them up.
+pub async fn landing_page(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<LandingError>> {
+	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+	let file_entry = accessible_entry(&uuid, &req, &db, &ctx).await?;
+
+	render(&uuid, &file_entry, req.uri().query(), None).await
+}
+
+/// Checks the password sent from the landing page form and redirects to the download, with an
+/// unlock token in place of the password
+pub async fn unlock(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<LandingError>> {
+	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+	let file_entry = accessible_entry(&uuid, &req, &db, &ctx).await?;
+	let query = req.uri().query().map(ToOwned::to_owned);
+
+	let (parts, body) = req.into_parts();
+	let boundary = multipart::get_boundary_from_req(parts).map_err(LandingError::from)?;
+	let mut multipart = Multipart::new(body, boundary, MAX_REQUEST_BODY_SIZE);
+	let chunks = multipart.read_all_chunks().await.into_handler_error()?;
+	let password = chunks
+		.iter()
+		.find(|(header, _data)| header.name == "password")
+		.and_then(|(_header, data)| std::str::from_utf8(data).ok())
+		.unwrap_or_default();
+
+	if let Some(ref password_hash) = file_entry.password {
+		match download::verify_password(
+			&uuid,
+			&file_entry,
+			password_hash,
+			password,
+			&db,
+			ctx.client_ip,
+		)
+		.await
+		{
+			Ok(()) => (),
+			Err(DownloadError::InvalidPassword) => {
+				let query = query.as_deref();
+				let mut resp = render(&uuid, &file_entry, query, Some("Invalid password")).await?;
+				*resp.status_mut() = StatusCode::UNAUTHORIZED;
+				return Ok(resp);
+			}
+			Err(err) => return Err(err).into_handler_error(),
+		}
+	}
+
+	let unlock_token = db.create_unlock_token(uuid, UNLOCK_TOKEN_LIFETIME);
+	let download_url = download_url(&uuid, query.as_deref());
+	let separator = if download_url.contains('?') { '&' } else { '?' };
+	let location = format!("{download_url}{separator}unlock_token={unlock_token}");
+	debug!("Unlocked {uuid} from the landing page");
+
+	Ok(Response::builder()
+		.status(StatusCode::SEE_OTHER)
+		.header(LOCATION, location)
+		.body(Body::empty())?)
+}
+
+/// Returns the entry if it's available and the requester is allowed to download it
+async fn accessible_entry(
+	uuid: &Uuid,
+	req: &Request<Body>,
+	db: &Db,
+	ctx: &RequestContext,
+) -> Result<FileEntry, LandingError> {
+	let file_entry = db
+		.get(uuid)
+		.await
+		.filter(FileEntry::is_available)
+		.ok_or(LandingError::NotFound)?;
+
+	let current_user = ctx.user_or_anonymous()?;
+
+	// A valid signed link is accepted in place of a session
+	let signed_link = check_signed_link(uuid, req.uri().query(), db).await?;
+	if signed_link.is_none()
+		&& !file_entry.can_download(current_user.as_ref(), &*db.groups_reader().await)
+	{
+		return Err(LandingError::NotAuthorized);
+	}
+	Ok(file_entry)
+}
+
+async fn render(
+	uuid: &Uuid,
+	file_entry: &FileEntry,
+	query: Option<&str>,
+	error: Option<&str>,
+) -> Result<Response<Body>, HandlerError<LandingError>> {
+	// Files that are still being uploaded can't be downloaded yet
+	let size = file_entry.size.ok_or(LandingError::NotFound)?;
+
+	let filename = escape_html(&file_entry.filename);
+	let mut details = vec![format_size(size)];
+	if let Some(expires_at) = file_entry.expires_at() {
+		let remaining = expires_at
+			.duration_since(SystemTime::now())
+			.unwrap_or_default();
+		details.push(format!("expires in {}", format_duration(remaining)));
+	}
+	match file_entry.downloads_remaining() {
+		Some(1) => details.push(String::from("1 download left")),
+		Some(downloads_remaining) => details.push(format!("{downloads_remaining} downloads left")),
+		None => (),
+	}
+	let description = escape_html(&details.join(" · "));
+
+	let mut page = String::with_capacity(4096);
+	let _ = write!(
+		page,
+		r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex, nofollow">
+<meta name="referrer" content="no-referrer">
+<meta property="og:type" content="website">
+<meta property="og:site_name" content="aqaSend">
+<meta property="og:title" content="{filename}">
+<meta property="og:description" content="{description}">
+<title>{filename} - aqaSend</title>
+<style>
+body {{ font-family: sans-serif; max-width: 40em; margin: 4em auto; padding: 0 1em; }}
+h1 {{ overflow-wrap: anywhere; }}
+.error {{ color: #b00020; }}
+</style>
+</head>
+<body>
+<h1>{filename}</h1>
+<p>{description}</p>
+"#
+	);
+	if let Some(expires_at) = file_entry.expires_at() {
+		let _ = writeln!(
+			page,
+			"<p>Available until {}</p>",
+			httpdate::fmt_http_date(expires_at)
+		);
+	}
+	if let Some(error) = error {
+		let _ = writeln!(page, r#"<p class="error">{}</p>"#, escape_html(error));
+	}
+
+	let download_url = escape_html(&download_url(uuid, query));
+	if file_entry.password.is_some() {
+		let action = escape_html(&match query {
+			Some(query) => format!("/d/{uuid}?{query}"),
+			None => format!("/d/{uuid}"),
+		});
+		let _ = writeln!(
+			page,
+			r#"<form method="post" action="{action}" enctype="multipart/form-data">
+<label>Password <input type="password" name="password" required autofocus></label>
+<button type="submit">Download</button>
+</form>"#
+		);
+	} else {
+		let _ = writeln!(page, r#"<p><a href="{download_url}">Download</a></p>"#);
+	}
+	page.push_str("</body>\n</html>\n");
+
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header(CONTENT_TYPE, "text/html; charset=utf-8")
+		.header(CACHE_CONTROL, "no-store")
+		.header("Content-Security-Policy", CONTENT_SECURITY_POLICY)
+		.body(Body::from(page))?)
+}
+
+/// Download url of the entry. A signed link from `query` is passed along.
+fn download_url(uuid: &Uuid, query: Option<&str>) -> String {
+	let signed_link = query
+		.map(|query| {
+			crate::uri_query_iter(query)
+				.filter(|(key, _value)| matches!(*key, "expires" | "nonce" | "signature"))
+				.map(|(key, value)| format!("{key}={value}"))
+				.collect::<Vec<_>>()
+				.join("&")
+		})
+		.unwrap_or_default();
+	if signed_link.is_empty() {
+		format!("/api/download/{uuid}")
+	} else {
+		format!("/api/download/{uuid}?{signed_link}")
+	}
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+	if bytes < 1000 {
+		return format!("{bytes} B");
+	}
+	let mut size = bytes as f64;
+	let mut unit = UNITS[0];
+	for next_unit in UNITS {
+		size /= 1000.0;
+		unit = next_unit;
+		if size < 1000.0 {
+			break;
+		}
+	}
+	format!("{size:.1} {unit}")
+}
+
+fn format_duration(duration: Duration) -> String {
+	let plural = |count: u64, unit: &str| match count {
+		1 => format!("1 {unit}"),
+		count => format!("{count} {unit}s"),
+	};
+	let secs = duration.as_secs();
+	match secs {
+		0..=59 => String::from("less than a minute"),
+		60..=3599 => plural(secs / 60, "minute"),
+		3600..=86399 => plural(secs / 3600, "hour"),
+		_ => plural(secs / 86400, "day"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn html_is_escaped() {
+		assert_eq!(
+			escape_html(r#"<script>alert("x & 'y'")</script>"#),
+			"&lt;script&gt;alert(&quot;x &amp; &#39;y&#39;&quot;)&lt;/script&gt;"
+		);
+	}
+
+	#[test]
+	fn sizes_and_durations_are_human_readable() {
+		assert_eq!(format_size(999), "999 B");
+		assert_eq!(format_size(2_300_000_000), "2.3 GB");
+		assert_eq!(
+			format_duration(Duration::from_secs(30)),
+			"less than a minute"
+		);
+		assert_eq!(format_duration(Duration::from_secs(60 * 60)), "1 hour");
+		assert_eq!(format_duration(Duration::from_secs(60 * 60 * 50)), "2 days");
+	}
+}
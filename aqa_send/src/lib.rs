@@ -1,72 +1,76 @@
-use std::future::{ready, Future};
-use std::ops::Deref;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use account::{get_logged_in_user, AuthError};
-use dashmap::DashMap;
+use account::AuthError;
+use context::RequestContext;
 use error::{HandlerError, HttpHandlerError};
-use hyper::http::HeaderValue;
 use hyper::service::Service;
-use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
 use log::*;
 use thiserror::Error;
-use uuid::Uuid;
 
 use crate::db::{Db, DbError};
 use crate::db_stuff::{Account, AccountType, FileEntry};
 use crate::error::ErrorContentType;
 use crate::files::DB_DIR;
-use crate::headers::{DownloadCount, Lifetime, DOWNLOAD_COUNT, LIFETIME, PASSWORD, VISIBILITY};
+use crate::headers::{
+	DownloadCount, Lifetime, BANDWIDTH_LIMIT, DOWNLOAD_COUNT, LIFETIME, PASSWORD, SHARE_WITH,
+	SHARE_WITH_GROUPS, VISIBILITY,
+};
 
 pub mod account;
+pub mod audit;
 pub mod cli_commands;
+pub mod context;
 pub mod cookie;
 pub mod db;
 pub mod db_stuff;
 pub mod delete;
 pub mod download;
+pub mod entry;
 pub mod error;
+pub mod events;
+pub mod feeds;
 pub mod files;
+pub mod groups;
 pub mod headers;
+pub mod landing;
 pub mod list;
 pub mod multipart;
+pub mod notifications;
+pub mod password_guard;
+pub mod range;
+pub mod sessions;
+pub mod settings;
+pub mod share;
+pub mod signed_links;
+pub mod sse;
 pub mod tasks;
+pub mod throttle;
 pub mod upload;
+pub mod webhooks;
+pub mod websocket;
 
 pub struct AqaService {
 	db: Db,
-	authorized_users: AuthorizedUsers,
-}
-
-/// Concurrent hashmap containing logged in users
-/// key: Uuid of session cookie
-/// value: Uuid of an user
-#[derive(Default, Clone)]
-pub struct AuthorizedUsers(Arc<DashMap<Uuid, Uuid>>);
-
-impl AuthorizedUsers {
-	pub fn get_user_uuid(&self, session_uuid: &Uuid) -> Option<Uuid> {
-		self.get(session_uuid).map(|entry| *entry.value())
-	}
-}
-
-impl Deref for AuthorizedUsers {
-	type Target = Arc<DashMap<Uuid, Uuid>>;
-
-	fn deref(&self) -> &Self::Target {
-		&self.0
-	}
+	client_addr: Option<SocketAddr>,
 }
 
 impl AqaService {
-	pub fn new(db: Db, authorized_users: AuthorizedUsers) -> Self {
+	pub fn new(db: Db) -> Self {
 		AqaService {
 			db,
-			authorized_users,
+			client_addr: None,
 		}
 	}
+
+	/// Address of the connected client
+	pub fn with_client_addr(mut self, client_addr: SocketAddr) -> Self {
+		self.client_addr = Some(client_addr);
+		self
+	}
 }
 
 #[derive(Debug, Error)]
@@ -90,105 +94,316 @@ impl Service<Request<Body>> for AqaService {
 	}
 
 	fn call(&mut self, req: Request<Body>) -> Self::Future {
-		debug!("{:?}", req);
-		let uri_path = req.uri().path().to_owned();
-		let path: Vec<&str> = split_uri_path(&uri_path).collect();
-		let method = req.method().clone();
-		let origin_header = req
-			.headers()
-			.get("origin")
-			.map(|hv: &HeaderValue| hv.to_owned());
-		match (method, path.as_slice()) {
-			(Method::GET, ["api"]) => Box::pin(hello(req)),
-			(Method::GET, ["api", "whoami"]) => Box::pin(handle_response(
-				whoami(req, self.db.clone(), self.authorized_users.clone()),
-				origin_header,
-			)),
-			(Method::POST, ["api", "upload"]) => Box::pin(handle_response(
-				upload::upload(req, self.db.clone(), self.authorized_users.clone()),
-				origin_header,
-			)),
-			(Method::OPTIONS, ["api", "upload"]) => Box::pin(preflight_request(req)),
-			(Method::GET, ["api", "download", uuid]) => Box::pin(handle_response(
-				download::download(
+		let db = self.db.clone();
+		let client_addr = self.client_addr;
+		Box::pin(async move {
+			let ctx = RequestContext::resolve(&req, client_addr, &db).await;
+			debug!("{} {:?}", ctx.request_id, req);
+			let mut resp = route(req, &ctx, db).await?;
+			ctx.finish(&mut resp);
+			Ok(resp)
+		})
+	}
+}
+
+async fn route(
+	req: Request<Body>,
+	ctx: &RequestContext,
+	db: Db,
+) -> Result<Response<Body>, AqaServiceError> {
+	let uri_path = req.uri().path().to_owned();
+	let path: Vec<&str> = split_uri_path(&uri_path).collect();
+	let method = req.method().clone();
+	match (method, path.as_slice()) {
+		(Method::GET, ["api"]) => hello(req).await,
+		(Method::GET, ["d", uuid]) => {
+			handle_response(
+				landing::landing_page(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::POST, ["d", uuid]) => {
+			handle_response(
+				landing::unlock(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::GET, ["feeds", "public.atom"]) => {
+			handle_response(feeds::public_feed(req, db.clone()), ctx).await
+		}
+		(Method::GET, ["feeds", "user", file_name]) => {
+			handle_response(
+				feeds::user_feed(file_name.to_string(), req, db.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::GET, ["api", "events"]) => {
+			handle_response(sse::events(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::GET, ["api", "ws"]) => {
+			handle_response(websocket::ws(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::GET, ["api", "whoami"]) => {
+			handle_response(whoami(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::POST, ["api", "upload"]) => {
+			handle_response(upload::upload(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::OPTIONS, ["api", "upload"]) => preflight_request("OPTIONS, POST"),
+		(Method::GET, ["api", "download", uuid]) => {
+			handle_response(
+				download::download(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::HEAD, ["api", "download", uuid]) => {
+			handle_response(
+				download::download(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::POST, ["api", "download", uuid]) => {
+			handle_response(
+				download::unlock(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "download", _uuid]) => {
+			preflight_request("OPTIONS, GET, HEAD, POST")
+		}
+		(Method::DELETE, ["api", "delete", uuid]) => {
+			handle_response(
+				delete::delete(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::GET, ["api", "entry", uuid]) => {
+			handle_response(
+				entry::entry(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "entry", _uuid]) => preflight_request("OPTIONS, GET"),
+		(Method::PUT, ["api", "entry", uuid, "share"]) => {
+			handle_response(
+				share::share(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "entry", _uuid, "share"]) => preflight_request("OPTIONS, PUT"),
+		(Method::PUT, ["api", "entry", uuid, "bandwidth"]) => {
+			handle_response(
+				throttle::set_bandwidth_limit(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "entry", _uuid, "bandwidth"]) => {
+			preflight_request("OPTIONS, PUT")
+		}
+		(Method::GET, ["api", "entry", uuid, "downloads"]) => {
+			handle_response(
+				audit::list_downloads(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "entry", _uuid, "downloads"]) => {
+			preflight_request("OPTIONS, GET")
+		}
+		(Method::GET, ["api", "entry", uuid, "links"]) => {
+			handle_response(
+				signed_links::list_links(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::POST, ["api", "entry", uuid, "links"]) => {
+			handle_response(
+				signed_links::create_link(uuid.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "entry", _uuid, "links"]) => {
+			preflight_request("OPTIONS, GET, POST")
+		}
+		(Method::DELETE, ["api", "entry", uuid, "links", nonce]) => {
+			handle_response(
+				signed_links::revoke_link(
 					uuid.to_string(),
+					nonce.to_string(),
 					req,
-					self.db.clone(),
-					self.authorized_users.clone(),
+					db.clone(),
+					ctx.clone(),
 				),
-				origin_header,
-			)),
-			(Method::DELETE, ["api", "delete", uuid]) => Box::pin(handle_response(
-				delete::delete(
-					uuid.to_string(),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "entry", _uuid, "links", _nonce]) => {
+			preflight_request("OPTIONS, DELETE")
+		}
+		(Method::GET, ["api", "groups"]) => {
+			handle_response(groups::list_groups(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::POST, ["api", "groups"]) => {
+			handle_response(groups::create_group(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::OPTIONS, ["api", "groups"]) => preflight_request("OPTIONS, GET, POST"),
+		(Method::DELETE, ["api", "groups", group]) => {
+			handle_response(
+				groups::delete_group(group.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "groups", _group]) => preflight_request("OPTIONS, DELETE"),
+		(Method::PUT, ["api", "groups", group, "members", account]) => {
+			handle_response(
+				groups::add_member(
+					group.to_string(),
+					account.to_string(),
 					req,
-					self.db.clone(),
-					self.authorized_users.clone(),
+					db.clone(),
+					ctx.clone(),
 				),
-				origin_header,
-			)),
-			(Method::GET, ["api", "list.json"]) => Box::pin(handle_response(
-				list::list(req, self.db.clone(), self.authorized_users.clone()),
-				origin_header,
-			)),
-			(Method::POST, ["api", "login"]) => Box::pin(handle_response(
-				account::login(req, self.db.clone(), self.authorized_users.clone()),
-				origin_header,
-			)),
-			(Method::POST, ["api", "logout"]) => Box::pin(handle_response(
-				account::logout(req, self.db.clone(), self.authorized_users.clone()),
-				origin_header,
-			)),
-			(Method::GET, ["api", "registration_code", kind @ "admin" | kind @ "user"]) => {
-				let account_kind = match *kind {
-					"admin" => AccountType::Admin,
-					"user" => AccountType::User,
-					_ => unreachable!(),
-				};
-				Box::pin(handle_response(
-					account::create_registration_code(
-						req,
-						self.db.clone(),
-						self.authorized_users.clone(),
-						account_kind,
-					),
-					origin_header,
-				))
-			}
-			(Method::POST, ["api", "create_account"]) => Box::pin(handle_response(
-				account::create_account_from_registration_code(
+				ctx,
+			)
+			.await
+		}
+		(Method::DELETE, ["api", "groups", group, "members", account]) => {
+			handle_response(
+				groups::remove_member(
+					group.to_string(),
+					account.to_string(),
 					req,
-					self.db.clone(),
-					self.authorized_users.clone(),
+					db.clone(),
+					ctx.clone(),
 				),
-				origin_header,
-			)),
-			(Method::GET, ["api", "check_registration_code", registration_code]) => {
-				Box::pin(handle_response(
-					account::check_registration_code(
-						req,
-						registration_code.to_string(),
-						self.db.clone(),
-					),
-					origin_header,
-				))
-			}
-			_ => Box::pin(ready(Ok(Response::builder()
-				.status(StatusCode::NOT_FOUND)
-				.body("Not found\n".into())
-				.unwrap()))),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "groups", _group, "members", _account]) => {
+			preflight_request("OPTIONS, PUT, DELETE")
+		}
+		(Method::GET, ["api", "webhooks"]) => {
+			handle_response(webhooks::list_webhooks(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::POST, ["api", "webhooks"]) => {
+			handle_response(webhooks::create_webhook(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::OPTIONS, ["api", "webhooks"]) => preflight_request("OPTIONS, GET, POST"),
+		(Method::DELETE, ["api", "webhooks", webhook]) => {
+			handle_response(
+				webhooks::delete_webhook(webhook.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "webhooks", _webhook]) => preflight_request("OPTIONS, DELETE"),
+		(Method::GET, ["api", "webhooks", webhook, "dead_letters"]) => {
+			handle_response(
+				webhooks::list_dead_letters(webhook.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "webhooks", _webhook, "dead_letters"]) => {
+			preflight_request("OPTIONS, GET")
+		}
+		(Method::GET, ["api", "list.json"]) => {
+			handle_response(list::list(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::GET, ["api", "notifications"]) => {
+			handle_response(
+				notifications::list_notifications(req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::DELETE, ["api", "notifications"]) => {
+			handle_response(
+				notifications::clear_notifications(req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::POST, ["api", "login"]) => {
+			handle_response(account::login(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::POST, ["api", "logout"]) => {
+			handle_response(account::logout(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::GET, ["api", "sessions"]) => {
+			handle_response(sessions::list_sessions(req, db.clone(), ctx.clone()), ctx).await
+		}
+		(Method::DELETE, ["api", "sessions"]) => {
+			handle_response(
+				sessions::revoke_all_sessions(req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "sessions"]) => preflight_request("OPTIONS, GET, DELETE"),
+		(Method::DELETE, ["api", "sessions", session]) => {
+			handle_response(
+				sessions::revoke_session(session.to_string(), req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::OPTIONS, ["api", "sessions", _session]) => preflight_request("OPTIONS, DELETE"),
+		(Method::GET, ["api", "registration_code", kind @ "admin" | kind @ "user"]) => {
+			let account_kind = match *kind {
+				"admin" => AccountType::Admin,
+				"user" => AccountType::User,
+				_ => unreachable!(),
+			};
+			handle_response(
+				account::create_registration_code(req, db.clone(), ctx.clone(), account_kind),
+				ctx,
+			)
+			.await
 		}
+		(Method::POST, ["api", "create_account"]) => {
+			handle_response(
+				account::create_account_from_registration_code(req, db.clone(), ctx.clone()),
+				ctx,
+			)
+			.await
+		}
+		(Method::GET, ["api", "check_registration_code", registration_code]) => {
+			handle_response(
+				account::check_registration_code(req, registration_code.to_string(), db.clone()),
+				ctx,
+			)
+			.await
+		}
+		_ => Ok(Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body("Not found\n".into())?),
 	}
 }
 
 async fn handle_response<E>(
 	resp: impl Future<Output = Result<Response<Body>, E>>,
-	origin_header: Option<HeaderValue>,
+	ctx: &RequestContext,
 ) -> Result<Response<Body>, AqaServiceError>
 where
 	E: HttpHandlerError,
 {
-	let mut resp = match resp.await {
+	let resp = match resp.await {
 		Ok(resp) => {
 			debug!("Response {}", resp.status());
 			resp
@@ -198,36 +413,33 @@ where
 			// error!("{:?}", backtrace);
 
 			let resp = err.response();
-			error!("{}; {err:?}", resp.status());
+			error!("{} {}; {err:?}", ctx.request_id, resp.status());
 			resp
 		}
 	};
-
-	if let Some(hv) = origin_header {
-		resp.headers_mut().append("Access-Control-Allow-Origin", hv);
-		resp.headers_mut()
-			.append("Access-Control-Allow-Credentials", "true".parse().unwrap());
-	}
 	Ok(resp)
 }
 
-async fn preflight_request(req: Request<Body>) -> Result<Response<Body>, AqaServiceError> {
+/// `Access-Control-Allow-Origin` is added along with the other CORS headers by
+/// [`RequestContext::finish`]
+fn preflight_request(allowed_methods: &'static str) -> Result<Response<Body>, AqaServiceError> {
 	Ok(Response::builder()
 		.status(StatusCode::NO_CONTENT)
-		.header(
-			"Access-Control-Allow-Origin",
-			req.headers().get("origin").unwrap(),
-		)
-		.header("Access-Control-Allow-Methods", "OPTIONS, POST")
+		.header("Access-Control-Allow-Methods", allowed_methods)
 		.header(
 			"Access-Control-Allow-Headers",
 			format!(
-				"Content-Type, {}, {}, {}, {}",
-				VISIBILITY, DOWNLOAD_COUNT, PASSWORD, LIFETIME
+				"Content-Type, Range, If-Range, If-None-Match, Last-Event-ID, {}, {}, {}, {}, {}, {}, {}",
+				VISIBILITY,
+				DOWNLOAD_COUNT,
+				PASSWORD,
+				LIFETIME,
+				SHARE_WITH,
+				SHARE_WITH_GROUPS,
+				BANDWIDTH_LIMIT
 			),
 		)
 		.header("Access-Control-Max-Age", (60 * 60).to_string())
-		.header("Access-Control-Allow-Credentials", "true")
 		.body(Body::from(""))?)
 }
 
@@ -267,14 +479,12 @@ impl HttpHandlerError for WhoamiError {
 }
 
 async fn whoami(
-	req: Request<Body>,
-	db: Db,
-	authorized_users: AuthorizedUsers,
+	_req: Request<Body>,
+	_db: Db,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<WhoamiError>> {
-	let (parts, _body) = req.into_parts();
-
-	let uploader = get_logged_in_user(&parts.headers, db, authorized_users)
-		.await
+	let uploader = ctx
+		.user()
 		.map_err(Into::<WhoamiError>::into)?
 		.ok_or(WhoamiError::NotLoggedIn)?;
 
@@ -283,6 +493,41 @@ async fn whoami(
 		.body(Body::from(uploader.username))?)
 }
 
+/// Address of the client. Requests coming from a trusted proxy are attributed to the last
+/// untrusted address in their `X-Forwarded-For` header.
+pub fn resolve_client_ip(
+	peer_ip: Option<IpAddr>,
+	headers: &HeaderMap,
+	trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+	let peer_ip = peer_ip?;
+	if !trusted_proxies.contains(&peer_ip) {
+		return Some(peer_ip);
+	}
+
+	// Every proxy appends the address it got the request from, so only the addresses added by
+	// trusted proxies can be relied on
+	let mut client_ip = peer_ip;
+	let forwarded_for = headers
+		.get_all("x-forwarded-for")
+		.iter()
+		.filter_map(|hv| hv.to_str().ok())
+		.flat_map(|hv| hv.split(','))
+		.collect::<Vec<_>>();
+	for forwarded_ip in forwarded_for.into_iter().rev() {
+		match forwarded_ip.trim().parse::<IpAddr>() {
+			Ok(forwarded_ip) => {
+				client_ip = forwarded_ip;
+				if !trusted_proxies.contains(&forwarded_ip) {
+					break;
+				}
+			}
+			Err(_) => break,
+		}
+	}
+	Some(client_ip)
+}
+
 pub fn split_uri_path(path: &str) -> impl Iterator<Item = &str> {
 	path.split('/').filter(|segment| !segment.is_empty())
 }
@@ -296,6 +541,34 @@ pub fn uri_query_iter(query: &str) -> impl Iterator<Item = (&str, &str)> {
 
 #[cfg(test)]
 mod tests {
+	use hyper::HeaderMap;
+	use std::net::IpAddr;
+
+	#[test]
+	fn client_ip_behind_trusted_proxy() {
+		let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+		let client: IpAddr = "203.0.113.7".parse().unwrap();
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			"x-forwarded-for",
+			"198.51.100.1, 203.0.113.7, 10.0.0.1".parse().unwrap(),
+		);
+
+		assert_eq!(
+			super::resolve_client_ip(Some(proxy), &headers, &[proxy]),
+			Some(client)
+		);
+		// Untrusted peers can't spoof their address
+		assert_eq!(
+			super::resolve_client_ip(Some(client), &headers, &[proxy]),
+			Some(client)
+		);
+		assert_eq!(
+			super::resolve_client_ip(Some(proxy), &HeaderMap::new(), &[proxy]),
+			Some(proxy)
+		);
+	}
+
 	#[test]
 	fn uri_path_splitter() {
 		let uri = "/";
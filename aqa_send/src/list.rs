@@ -1,54 +1,46 @@
-use crate::cookie::parse_cookie;
-use crate::{uri_query_iter, AuthorizedUsers, FileEntry, HandlerError, HttpHandlerError, Lifetime};
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::{uri_query_iter, FileEntry, HandlerError, HttpHandlerError, Lifetime};
 use hyper::{Body, Request, Response, StatusCode};
 use log::debug;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::time::SystemTime;
+use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tracing::error;
 use uuid::Uuid;
 
-use crate::db::Db;
-use crate::error::{ErrorContentType, Field, IntoHandlerError};
-use crate::headers::{DownloadCount, Visibility};
+use crate::db::{Db, GroupsHM};
+use crate::db_stuff::Account;
+use crate::error::{ErrorContentType, IntoHandlerError};
+use crate::headers::{DownloadCount, Visibility, NEXT_CURSOR};
 
 #[derive(Debug, Error)]
 pub enum ListError {
 	#[error(transparent)]
 	Json(#[from] serde_json::Error),
 
-	#[error("malformed session cookie")]
-	MalformedSessionCookie(uuid::Error),
-
-	#[error("{0:?} must only contain visible ascii characters")]
-	AsciiOnly(Field),
-
-	#[error("Malformed {0:?} data")]
-	Malformed(Field),
+	#[error(transparent)]
+	Auth(#[from] AuthError),
 
-	#[error("Authorized user doesn't exist")]
-	UnknownUser,
+	#[error("Invalid `{0}` query parameter")]
+	InvalidQuery(&'static str),
 }
 
 impl HttpHandlerError for ListError {
 	fn code(&self) -> StatusCode {
 		match self {
 			ListError::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
-			ListError::MalformedSessionCookie(_) => StatusCode::BAD_REQUEST,
-			ListError::AsciiOnly(_) => StatusCode::BAD_REQUEST,
-			ListError::Malformed(_) => StatusCode::BAD_REQUEST,
-			ListError::UnknownUser => StatusCode::BAD_REQUEST,
+			ListError::Auth(err) => err.code(),
+			ListError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
 		}
 	}
 
 	fn user_presentable(&self) -> bool {
 		match self {
 			ListError::Json(_) => false,
-			ListError::MalformedSessionCookie(_) => true,
-			ListError::AsciiOnly(_) => true,
-			ListError::Malformed(_) => true,
-			ListError::UnknownUser => true,
+			ListError::Auth(err) => err.user_presentable(),
+			ListError::InvalidQuery(_) => true,
 		}
 	}
 
@@ -66,134 +58,121 @@ pub struct FileModel<'a> {
 	pub uploader_uuid: Option<Uuid>,
 
 	pub download_count: u64,
+	/// `None` if the number of downloads is unlimited
+	pub download_limit: Option<u64>,
+	/// `None` if the number of downloads is unlimited
+	pub downloads_remaining: Option<u64>,
+	/// Size in bytes. `None` while the file is being uploaded.
+	pub size: Option<u64>,
 
 	pub visibility: Visibility,
 	pub has_password: bool,
+	/// Only present for the uploader and admins
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub shared_with: Option<Vec<Uuid>>,
+	/// Only present for the uploader and admins
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub shared_with_groups: Option<Vec<Uuid>>,
 
 	pub lifetime: Lifetime,
 	pub upload_date: SystemTime,
+	/// RFC 3339 date, `None` if the entry never expires
+	pub expires_at: Option<String>,
+}
+
+impl<'a> FileModel<'a> {
+	/// Fields that only the uploader and admins may see are filled in if `viewer` is one of them
+	pub fn new(uuid: Uuid, entry: &'a FileEntry, viewer: Option<&Account>) -> Self {
+		let FileEntry {
+			filename,
+			content_type,
+			uploader_uuid,
+			download_count_type,
+			download_count,
+			size,
+			visibility,
+			password,
+			shared_with,
+			shared_with_groups,
+			lifetime,
+			upload_date,
+			..
+		} = entry;
+
+		let can_manage = viewer.is_some_and(|viewer| entry.can_manage(viewer));
+
+		FileModel {
+			uuid,
+			filename: Cow::Borrowed(filename.as_str()),
+			content_type: Cow::Borrowed(content_type.as_str()),
+			uploader_uuid: *uploader_uuid,
+			download_count: *download_count,
+			download_limit: match download_count_type {
+				DownloadCount::Infinite => None,
+				DownloadCount::Count(count) => Some(*count),
+			},
+			downloads_remaining: entry.downloads_remaining(),
+			size: *size,
+			visibility: *visibility,
+			has_password: password.is_some(),
+			shared_with: can_manage.then(|| shared_with.clone()),
+			shared_with_groups: can_manage.then(|| shared_with_groups.clone()),
+			lifetime: *lifetime,
+			upload_date: *upload_date,
+			expires_at: entry
+				.expires_at()
+				.map(|expires_at| humantime::format_rfc3339_seconds(expires_at).to_string()),
+		}
+	}
 }
 
 pub async fn list(
 	req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<ListError>> {
-	let uploader = match req.headers().get("Cookie") {
-		Some(cookie) => {
-			let cookie_header = cookie
-				.to_str()
-				.map_err(|_| ListError::AsciiOnly(Field::Cookie))?;
-			let (_, cookies) =
-				parse_cookie(cookie_header).map_err(|_| ListError::Malformed(Field::Cookie))?;
-			debug!("Cookies: {cookies:?}");
-			match cookies.get("session") {
-				Some(session_cookie) => {
-					let session_cookie: Uuid = session_cookie
-						.parse()
-						.map_err(ListError::MalformedSessionCookie)?;
-
-					match authorized_users.get_user_uuid(&session_cookie) {
-						Some(user_uuid) => {
-							debug!("Getting user with uuid {user_uuid}");
-							Some(
-								db.get_account(&user_uuid)
-									.await
-									.ok_or(ListError::UnknownUser)?,
-							)
-						}
-						None => None,
-					}
-				}
-				None => None,
-			}
-		}
-		None => None,
-	};
+	let uploader = ctx.user_or_anonymous().map_err(ListError::from)?;
 
-	let only_self_uploads = req
-		.uri()
-		.query()
-		.and_then(|query| uri_query_iter(query).find(|(key, _value)| *key == "uploader"))
-		.map(|(_, uploader)| uploader == "me")
-		.unwrap_or_default();
+	let query = ListQuery::from_query(req.uri().query().unwrap_or_default())?;
 
+	let groups = db.groups_reader().await;
 	let db_reader = db.reader().await;
-	let list: Vec<FileModel> = db_reader
+	let mut entries: Vec<(&Uuid, &FileEntry, SortValue)> = db_reader
 		.iter()
-		.filter(|(_uuid, entry)| {
-			if matches!(entry.visibility, Visibility::Public) {
-				true
-			} else {
-				match (entry.uploader_uuid, &uploader) {
-					(Some(uploader_uuid), Some(uploader)) => uploader_uuid == uploader.uuid,
-					_ => false,
-				}
-			}
-		})
-		.filter(|(_key, entry): &(_, &FileEntry)| {
-			if only_self_uploads {
-				let Some(me) = &uploader else {
-					return false;
-				};
-				let Some(uploader_uuid) = entry.uploader_uuid else {
-					return false;
-				};
-				if me.uuid != uploader_uuid {
-					return false;
-				}
-			}
+		.filter(|(_uuid, entry)| entry.is_listed_for(uploader.as_ref(), &groups))
+		.filter(|(_uuid, entry)| query.matches(entry, uploader.as_ref(), &groups))
+		.map(|(uuid, entry)| (uuid, entry, query.sort.value(entry)))
+		.collect();
 
-			if let Lifetime::Duration(lifetime) = entry.lifetime {
-				match entry.upload_date.elapsed() {
-					Ok(elapsed) => {
-						if elapsed > lifetime {
-							return false;
-						}
-					}
-					Err(err) => {
-						error!(
-							"Failed to get elapsed upload time. Diff: {:?}",
-							err.duration()
-						);
-						return false;
-					}
-				}
-			}
+	entries.sort_unstable_by(|(a_uuid, _, a_value), (b_uuid, _, b_value)| {
+		query.compare((a_uuid, a_value), (b_uuid, b_value))
+	});
+
+	if let Some(ref cursor) = query.cursor {
+		let after_cursor = entries.partition_point(|(uuid, _, value)| {
+			query.compare((uuid, value), (&cursor.uuid, &cursor.value)) != Ordering::Greater
+		});
+		entries.drain(..after_cursor);
+	}
 
-			if let DownloadCount::Count(max_count) = entry.download_count_type {
-				if entry.download_count >= max_count {
-					return false;
+	let next_cursor = match query.limit {
+		Some(limit) if entries.len() > limit => {
+			entries.truncate(limit);
+			entries.last().map(|(uuid, _entry, value)| {
+				Cursor {
+					sort: query.sort,
+					uuid: **uuid,
+					value: value.clone().into_owned(),
 				}
-			}
+				.encode()
+			})
+		}
+		_ => None,
+	};
 
-			true
-		})
-		.map(|(key, value)| {
-			let FileEntry {
-				filename,
-				content_type,
-				uploader_uuid,
-				download_count,
-				visibility,
-				password,
-				lifetime,
-				upload_date,
-				..
-			} = value;
-
-			FileModel {
-				uuid: *key,
-				filename: Cow::Borrowed(filename.as_str()),
-				content_type: Cow::Borrowed(content_type.as_str()),
-				uploader_uuid: *uploader_uuid,
-				download_count: *download_count,
-				visibility: *visibility,
-				has_password: password.is_some(),
-				lifetime: *lifetime,
-				upload_date: *upload_date,
-			}
-		})
+	let list: Vec<FileModel> = entries
+		.into_iter()
+		.map(|(uuid, entry, _value)| FileModel::new(*uuid, entry, uploader.as_ref()))
 		.collect();
 
 	debug!("Serving file list ({} files)", list.len());
@@ -205,8 +184,324 @@ pub async fn list(
 	}
 	.into_handler_error()?;
 
-	Ok(Response::builder()
+	let response = Response::builder()
 		.status(StatusCode::OK)
 		.header("Content-Type", "application/json")
-		.body(Body::from(resp))?)
+		.header("Access-Control-Expose-Headers", NEXT_CURSOR);
+	let response = match next_cursor {
+		Some(next_cursor) => response.header(NEXT_CURSOR, next_cursor),
+		None => response,
+	};
+	Ok(response.body(Body::from(resp))?)
+}
+
+/// Largest page size a client can ask for
+const MAX_LIMIT: usize = 1000;
+
+/// Query parameters of [list]
+#[derive(Debug, Default)]
+struct ListQuery {
+	/// `uploader=me`
+	only_self_uploads: bool,
+	/// `shared=me`
+	only_shared_with_me: bool,
+
+	sort: SortBy,
+	order: Order,
+	/// No limit if not specified
+	limit: Option<usize>,
+	cursor: Option<Cursor>,
+
+	/// Lowercase content type prefix, e.g. `image/`
+	content_type: Option<String>,
+	uploaded_after: Option<SystemTime>,
+	uploaded_before: Option<SystemTime>,
+	visibility: Option<Visibility>,
+	has_password: Option<bool>,
+	/// Lowercase substring of the filename
+	search: Option<String>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortBy {
+	#[default]
+	Date,
+	Name,
+	Size,
+	Expiry,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+enum Order {
+	Asc,
+	#[default]
+	Desc,
+}
+
+impl ListQuery {
+	fn from_query(query: &str) -> Result<Self, ListError> {
+		let mut list_query = ListQuery::default();
+		let mut order = None;
+		let decode = |key: &'static str, value: &str| {
+			urlencoding::decode(value)
+				.map(|value| value.into_owned())
+				.map_err(|_| ListError::InvalidQuery(key))
+		};
+		let unix_time = |key: &'static str, value: &str| {
+			value
+				.parse::<u64>()
+				.map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+				.map_err(|_| ListError::InvalidQuery(key))
+		};
+
+		for (key, value) in uri_query_iter(query) {
+			match key {
+				"uploader" => list_query.only_self_uploads = value == "me",
+				"shared" => list_query.only_shared_with_me = value == "me",
+				"sort" => {
+					list_query.sort = match value {
+						"date" => SortBy::Date,
+						"name" => SortBy::Name,
+						"size" => SortBy::Size,
+						"expiry" => SortBy::Expiry,
+						_ => return Err(ListError::InvalidQuery("sort")),
+					}
+				}
+				"order" => {
+					order = Some(match value {
+						"asc" => Order::Asc,
+						"desc" => Order::Desc,
+						_ => return Err(ListError::InvalidQuery("order")),
+					})
+				}
+				"limit" => match value.parse() {
+					Ok(limit @ 1..=MAX_LIMIT) => list_query.limit = Some(limit),
+					_ => return Err(ListError::InvalidQuery("limit")),
+				},
+				"cursor" => {
+					list_query.cursor =
+						Some(Cursor::decode(value).ok_or(ListError::InvalidQuery("cursor"))?)
+				}
+				"content_type" => {
+					list_query.content_type = Some(decode("content_type", value)?.to_lowercase())
+				}
+				"uploaded_after" => {
+					list_query.uploaded_after = Some(unix_time("uploaded_after", value)?)
+				}
+				"uploaded_before" => {
+					list_query.uploaded_before = Some(unix_time("uploaded_before", value)?)
+				}
+				"visibility" => {
+					list_query.visibility = Some(match value {
+						"public" => Visibility::Public,
+						"unlisted" => Visibility::Unlisted,
+						"private" => Visibility::Private,
+						_ => return Err(ListError::InvalidQuery("visibility")),
+					})
+				}
+				"has_password" => {
+					list_query.has_password = Some(
+						value
+							.parse()
+							.map_err(|_| ListError::InvalidQuery("has_password"))?,
+					)
+				}
+				"search" => list_query.search = Some(decode("search", value)?.to_lowercase()),
+				_ => (),
+			}
+		}
+
+		// Newest and biggest files first, names and expiry dates in ascending order
+		list_query.order = order.unwrap_or(match list_query.sort {
+			SortBy::Date | SortBy::Size => Order::Desc,
+			SortBy::Name | SortBy::Expiry => Order::Asc,
+		});
+
+		if matches!(list_query.cursor, Some(ref cursor) if cursor.sort != list_query.sort) {
+			return Err(ListError::InvalidQuery("cursor"));
+		}
+
+		Ok(list_query)
+	}
+
+	/// Order of entries in the list. Ties are broken by uuids, so that the cursor points at an
+	/// exact position.
+	fn compare(
+		&self,
+		(a_uuid, a_value): (&Uuid, &SortValue),
+		(b_uuid, b_value): (&Uuid, &SortValue),
+	) -> Ordering {
+		let ordering = a_value.cmp(b_value).then_with(|| a_uuid.cmp(b_uuid));
+		match self.order {
+			Order::Asc => ordering,
+			Order::Desc => ordering.reverse(),
+		}
+	}
+
+	fn matches(&self, entry: &FileEntry, viewer: Option<&Account>, groups: &GroupsHM) -> bool {
+		if self.only_self_uploads {
+			let Some(me) = viewer else {
+				return false;
+			};
+			if entry.uploader_uuid != Some(me.uuid) {
+				return false;
+			}
+		}
+
+		if self.only_shared_with_me && !entry.is_shared_with(viewer, groups) {
+			return false;
+		}
+
+		if let Some(ref content_type) = self.content_type {
+			let entry_content_type = entry.content_type.as_bytes();
+			if entry_content_type.len() < content_type.len()
+				|| !entry_content_type[..content_type.len()]
+					.eq_ignore_ascii_case(content_type.as_bytes())
+			{
+				return false;
+			}
+		}
+		if self
+			.uploaded_after
+			.is_some_and(|after| entry.upload_date < after)
+		{
+			return false;
+		}
+		if self
+			.uploaded_before
+			.is_some_and(|before| entry.upload_date >= before)
+		{
+			return false;
+		}
+		if let Some(visibility) = self.visibility {
+			if std::mem::discriminant(&visibility) != std::mem::discriminant(&entry.visibility) {
+				return false;
+			}
+		}
+		if self
+			.has_password
+			.is_some_and(|has_password| has_password != entry.password.is_some())
+		{
+			return false;
+		}
+		if let Some(ref search) = self.search {
+			if !entry.filename.to_lowercase().contains(search.as_str()) {
+				return false;
+			}
+		}
+
+		entry.is_available()
+	}
+}
+
+impl SortBy {
+	fn value(self, entry: &FileEntry) -> SortValue<'_> {
+		match self {
+			SortBy::Date => SortValue::Date(entry.upload_date),
+			SortBy::Name => SortValue::Name(Cow::Borrowed(&entry.filename)),
+			SortBy::Size => SortValue::Size(entry.size.unwrap_or_default()),
+			SortBy::Expiry => SortValue::Expiry(entry.expires_at()),
+		}
+	}
+}
+
+/// Value of the entry that the list is sorted by
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum SortValue<'a> {
+	Date(SystemTime),
+	Name(Cow<'a, str>),
+	Size(u64),
+	/// `None` if the entry never expires
+	Expiry(Option<SystemTime>),
+}
+
+impl SortValue<'_> {
+	fn into_owned(self) -> SortValue<'static> {
+		match self {
+			SortValue::Date(date) => SortValue::Date(date),
+			SortValue::Name(name) => SortValue::Name(Cow::Owned(name.into_owned())),
+			SortValue::Size(size) => SortValue::Size(size),
+			SortValue::Expiry(expiry) => SortValue::Expiry(expiry),
+		}
+	}
+}
+
+impl Ord for SortValue<'_> {
+	fn cmp(&self, other: &Self) -> Ordering {
+		match (self, other) {
+			(SortValue::Date(a), SortValue::Date(b)) => a.cmp(b),
+			// Case insensitive, falling back to the exact name for a stable order
+			(SortValue::Name(a), SortValue::Name(b)) => a
+				.chars()
+				.flat_map(char::to_lowercase)
+				.cmp(b.chars().flat_map(char::to_lowercase))
+				.then_with(|| a.cmp(b)),
+			(SortValue::Size(a), SortValue::Size(b)) => a.cmp(b),
+			// Entries that never expire go last
+			(SortValue::Expiry(a), SortValue::Expiry(b)) => match (a, b) {
+				(Some(a), Some(b)) => a.cmp(b),
+				(Some(_), None) => Ordering::Less,
+				(None, Some(_)) => Ordering::Greater,
+				(None, None) => Ordering::Equal,
+			},
+			// Cursors of a different sort are rejected in [ListQuery::from_query]
+			_ => Ordering::Equal,
+		}
+	}
+}
+
+impl PartialOrd for SortValue<'_> {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+/// Position in the list after the last returned entry. Sent to clients as an opaque hex string.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+	sort: SortBy,
+	uuid: Uuid,
+	value: SortValue<'static>,
+}
+
+impl Cursor {
+	fn encode(&self) -> String {
+		hex::encode(serde_json::to_vec(self).unwrap_or_default())
+	}
+
+	fn decode(cursor: &str) -> Option<Self> {
+		serde_json::from_slice(&hex::decode(cursor).ok()?).ok()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cursor_roundtrip() {
+		let cursor = Cursor {
+			sort: SortBy::Name,
+			uuid: Uuid::new_v4(),
+			value: SortValue::Name(Cow::Borrowed("zażółć gęślą jaźń.txt")),
+		};
+		let decoded = Cursor::decode(&cursor.encode()).unwrap();
+		assert_eq!(decoded.sort, cursor.sort);
+		assert_eq!(decoded.uuid, cursor.uuid);
+		assert_eq!(decoded.value, cursor.value);
+
+		assert!(Cursor::decode("not a cursor").is_none());
+		assert!(Cursor::decode("7b7d").is_none());
+	}
+
+	#[test]
+	fn names_sort_case_insensitively_and_never_expiring_last() {
+		let name = |name: &'static str| SortValue::Name(Cow::Borrowed(name));
+		assert!(name("apple") < name("Banana"));
+		assert!(name("Apple") < name("apple"));
+
+		let now = SystemTime::now();
+		assert!(SortValue::Expiry(Some(now)) < SortValue::Expiry(None));
+	}
 }
@@ -4,6 +4,7 @@ use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 use futures::future::join_all;
+use hyper::server::conn::AddrStream;
 use hyper::service::make_service_fn;
 use hyper::Server;
 use log::*;
@@ -13,8 +14,8 @@ use tokio::runtime::Runtime;
 use aqa_send::cli_commands::create_account::create_account_cmd;
 use aqa_send::db::{self, DbError};
 use aqa_send::db_stuff::AccountType;
+use aqa_send::tasks;
 use aqa_send::tasks::cleanup::{DEFAULT_CLEANUP_INTERVAL, DEFAULT_START_LAG};
-use aqa_send::{tasks, AuthorizedUsers};
 use aqa_send::{AqaService, AqaServiceError};
 
 const USAGE: &str = r#"aqaSend
@@ -156,19 +157,17 @@ fn run() -> Result<(), Box<dyn Error>> {
 		DEFAULT_CLEANUP_INTERVAL,
 		DEFAULT_START_LAG,
 	));
+	tokio::spawn(tasks::webhooks::webhook_task(db_handle.clone()));
 
 	drop(guard);
-	let authorized_users = AuthorizedUsers::default();
 
 	tokio_runtime
 		.block_on(join_all(servers.into_iter().map(|server| {
-			server.serve(make_service_fn(|_addr_stream| {
+			server.serve(make_service_fn(|addr_stream: &AddrStream| {
 				let db = db_handle.clone();
-				let authorized_users = authorized_users.clone();
-				ready(Result::<AqaService, AqaServiceError>::Ok(AqaService::new(
-					db,
-					authorized_users,
-				)))
+				ready(Result::<AqaService, AqaServiceError>::Ok(
+					AqaService::new(db).with_client_addr(addr_stream.remote_addr()),
+				))
 			}))
 		})))
 		.into_iter()
@@ -0,0 +1,85 @@
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+
+/// Message for an account owner, e.g. about suspicious activity on their entries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+	pub date: SystemTime,
+	pub message: String,
+	pub file_uuid: Option<Uuid>,
+}
+
+#[derive(Debug, Error)]
+pub enum NotificationsError {
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+}
+
+impl HttpHandlerError for NotificationsError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::AuthError(err) => err.code(),
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotLoggedIn => StatusCode::UNAUTHORIZED,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			Self::NotLoggedIn => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+pub async fn list_notifications(
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<NotificationsError>> {
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(NotificationsError::NotLoggedIn)?;
+
+	let notifications = db.notifications(&current_user.uuid).await;
+	json_response(StatusCode::OK, &notifications)
+}
+
+pub async fn clear_notifications(
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<NotificationsError>> {
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(NotificationsError::NotLoggedIn)?;
+
+	db.clear_notifications(&current_user.uuid).await;
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.body(Body::empty())?)
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::settings::Settings;
+
+/// Failed password attempts for protected entries, counted per entry and per client ip.
+///
+/// After [Settings::password_free_attempts] failures every next attempt has to wait for an
+/// exponentially growing delay. Reaching [Settings::password_lockout_after] failures locks the
+/// entry for [Settings::password_lockout_secs].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PasswordFailures {
+	pub entries: HashMap<Uuid, FailureCounter>,
+	pub clients: HashMap<IpAddr, FailureCounter>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FailureCounter {
+	pub failures: u32,
+	pub last_failure: SystemTime,
+	pub locked_until: Option<SystemTime>,
+}
+
+impl FailureCounter {
+	fn new(now: SystemTime) -> Self {
+		FailureCounter {
+			failures: 0,
+			last_failure: now,
+			locked_until: None,
+		}
+	}
+
+	fn retry_after(&self, settings: &Settings, now: SystemTime) -> Option<Duration> {
+		let backoff_until = backoff(self.failures, settings).map(|delay| self.last_failure + delay);
+		let allowed_at = backoff_until.max(self.locked_until)?;
+		allowed_at
+			.duration_since(now)
+			.ok()
+			.filter(|remaining| !remaining.is_zero())
+	}
+
+	fn is_stale(&self, settings: &Settings, now: SystemTime) -> bool {
+		let locked = matches!(self.locked_until, Some(locked_until) if locked_until > now);
+		let idle = now
+			.duration_since(self.last_failure)
+			.map(|idle| idle > Duration::from_secs(settings.password_failures_reset_secs))
+			.unwrap_or_default();
+		!locked && idle
+	}
+}
+
+fn backoff(failures: u32, settings: &Settings) -> Option<Duration> {
+	let exponent = failures.checked_sub(settings.password_free_attempts)?;
+	let base = Duration::from_secs(settings.password_backoff_base_secs);
+	let max = Duration::from_secs(settings.password_backoff_max_secs);
+	Some(base.saturating_mul(2_u32.saturating_pow(exponent)).min(max))
+}
+
+impl PasswordFailures {
+	/// Counts a password attempt as failed before the password is verified, so concurrent
+	/// guesses can't all get past the limits before any of them is counted. Returns how long the
+	/// client has to wait if it can't try yet, otherwise whether the attempt locked the entry.
+	/// A successful attempt is given back with [PasswordFailures::record_success].
+	pub fn begin_attempt(
+		&mut self,
+		entry: &Uuid,
+		client: Option<IpAddr>,
+		settings: &Settings,
+		now: SystemTime,
+	) -> Result<bool, Duration> {
+		if let Some(retry_after) = self.retry_after(entry, client, settings, now) {
+			return Err(retry_after);
+		}
+		Ok(self.record_failure(entry, client, settings, now))
+	}
+
+	/// Returns how long the client has to wait before trying another password, if at all
+	fn retry_after(
+		&self,
+		entry: &Uuid,
+		client: Option<IpAddr>,
+		settings: &Settings,
+		now: SystemTime,
+	) -> Option<Duration> {
+		let entry_delay = self
+			.entries
+			.get(entry)
+			.and_then(|counter| counter.retry_after(settings, now));
+		let client_delay = client
+			.and_then(|client| self.clients.get(&client))
+			.and_then(|counter| counter.retry_after(settings, now));
+		entry_delay.max(client_delay)
+	}
+
+	/// Returns true if the entry just got locked
+	fn record_failure(
+		&mut self,
+		entry: &Uuid,
+		client: Option<IpAddr>,
+		settings: &Settings,
+		now: SystemTime,
+	) -> bool {
+		if let Some(client) = client {
+			let counter = self
+				.clients
+				.entry(client)
+				.or_insert_with(|| FailureCounter::new(now));
+			if counter.is_stale(settings, now) {
+				*counter = FailureCounter::new(now);
+			}
+			counter.failures += 1;
+			counter.last_failure = now;
+		}
+
+		let counter = self
+			.entries
+			.entry(*entry)
+			.or_insert_with(|| FailureCounter::new(now));
+		if counter.is_stale(settings, now) {
+			*counter = FailureCounter::new(now);
+		}
+		counter.failures += 1;
+		counter.last_failure = now;
+
+		match settings.password_lockout_after {
+			Some(lockout_after) if counter.failures == lockout_after => {
+				counter.locked_until =
+					Some(now + Duration::from_secs(settings.password_lockout_secs));
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Clears the failures of the entry and gives back the attempt counted for the client by
+	/// [PasswordFailures::begin_attempt]. Earlier failures of the client keep counting.
+	pub fn record_success(&mut self, entry: &Uuid, client: Option<IpAddr>) {
+		self.entries.remove(entry);
+		if let Some(counter) = client.and_then(|client| self.clients.get_mut(&client)) {
+			counter.failures = counter.failures.saturating_sub(1);
+		}
+	}
+
+	pub fn remove_entry(&mut self, entry: &Uuid) {
+		self.entries.remove(entry);
+	}
+
+	pub fn remove_stale(&mut self, settings: &Settings, now: SystemTime) {
+		self.entries
+			.retain(|_, counter| !counter.is_stale(settings, now));
+		self.clients
+			.retain(|_, counter| !counter.is_stale(settings, now));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn backoff_grows_exponentially() {
+		let settings = Settings {
+			password_free_attempts: 3,
+			password_backoff_base_secs: 1,
+			password_backoff_max_secs: 10,
+			..Default::default()
+		};
+
+		assert_eq!(backoff(0, &settings), None);
+		assert_eq!(backoff(2, &settings), None);
+		assert_eq!(backoff(3, &settings), Some(Duration::from_secs(1)));
+		assert_eq!(backoff(4, &settings), Some(Duration::from_secs(2)));
+		assert_eq!(backoff(5, &settings), Some(Duration::from_secs(4)));
+		assert_eq!(backoff(7, &settings), Some(Duration::from_secs(10)));
+		assert_eq!(backoff(u32::MAX, &settings), Some(Duration::from_secs(10)));
+	}
+
+	#[test]
+	fn failures_are_counted_per_entry_and_client() {
+		let settings = Settings {
+			password_free_attempts: 2,
+			password_backoff_base_secs: 60,
+			..Default::default()
+		};
+		let now = SystemTime::now();
+		let client: IpAddr = [127, 0, 0, 1].into();
+		let (entry_a, entry_b) = (Uuid::new_v4(), Uuid::new_v4());
+
+		let mut failures = PasswordFailures::default();
+		assert_eq!(
+			failures.begin_attempt(&entry_a, Some(client), &settings, now),
+			Ok(false)
+		);
+		assert_eq!(
+			failures.retry_after(&entry_a, Some(client), &settings, now),
+			None
+		);
+
+		assert_eq!(
+			failures.begin_attempt(&entry_b, Some(client), &settings, now),
+			Ok(false)
+		);
+		assert_eq!(failures.retry_after(&entry_b, None, &settings, now), None);
+		assert_eq!(
+			failures.begin_attempt(&entry_b, Some(client), &settings, now),
+			Err(Duration::from_secs(60))
+		);
+
+		let later = now + Duration::from_secs(60);
+		assert_eq!(
+			failures.begin_attempt(&entry_b, Some(client), &settings, later),
+			Ok(false)
+		);
+		failures.record_success(&entry_b, Some(client));
+		assert!(!failures.entries.contains_key(&entry_b));
+		assert_eq!(
+			failures.retry_after(&entry_a, Some(client), &settings, later),
+			Some(Duration::from_secs(60))
+		);
+	}
+
+	#[test]
+	fn entry_gets_locked_out() {
+		let settings = Settings {
+			password_free_attempts: 100,
+			password_lockout_after: Some(2),
+			password_lockout_secs: 60,
+			..Default::default()
+		};
+		let now = SystemTime::now();
+		let entry = Uuid::new_v4();
+
+		let mut failures = PasswordFailures::default();
+		assert!(!failures.record_failure(&entry, None, &settings, now));
+		assert!(failures.record_failure(&entry, None, &settings, now));
+		assert!(!failures.record_failure(&entry, None, &settings, now));
+		assert_eq!(
+			failures.retry_after(&entry, None, &settings, now),
+			Some(Duration::from_secs(60))
+		);
+	}
+}
@@ -0,0 +1,158 @@
+//! Parsing of the `Range` header ([RFC 7233](https://www.rfc-editor.org/rfc/rfc7233))
+
+/// Requests with more ranges than that are served as a whole
+pub const MAX_RANGES: usize = 16;
+
+/// Inclusive range of bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ByteRange {
+	pub start: u64,
+	pub end: u64,
+}
+
+impl ByteRange {
+	pub fn length(&self) -> u64 {
+		self.end - self.start + 1
+	}
+
+	pub fn content_range(&self, size: u64) -> String {
+		format!("bytes {}-{}/{size}", self.start, self.end)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Unsatisfiable;
+
+/// Parses the `Range` header against a file of `size` bytes.
+///
+/// Returns `None` if the header should be ignored (unknown unit, syntax error or too many ranges),
+/// in which case the whole file is sent. Overlapping and adjacent ranges are merged.
+pub fn parse_range(header: &str, size: u64) -> Option<Result<Vec<ByteRange>, Unsatisfiable>> {
+	let specs = header.trim().strip_prefix("bytes=")?;
+
+	let mut ranges = Vec::new();
+	for (idx, spec) in specs
+		.split(',')
+		.map(str::trim)
+		.filter(|spec| !spec.is_empty())
+		.enumerate()
+	{
+		if idx >= MAX_RANGES {
+			return None;
+		}
+
+		let (first, last) = spec.split_once('-')?;
+		let range = match (first.trim(), last.trim()) {
+			("", suffix) => {
+				let suffix: u64 = suffix.parse().ok()?;
+				if suffix == 0 || size == 0 {
+					continue;
+				}
+				ByteRange {
+					start: size.saturating_sub(suffix),
+					end: size - 1,
+				}
+			}
+			(first, last) => {
+				let first: u64 = first.parse().ok()?;
+				let last: Option<u64> = match last {
+					"" => None,
+					last => Some(last.parse().ok()?),
+				};
+				if last.is_some_and(|last| last < first) {
+					return None;
+				}
+				if first >= size {
+					continue;
+				}
+				ByteRange {
+					start: first,
+					end: last.map_or(size - 1, |last| last.min(size - 1)),
+				}
+			}
+		};
+		ranges.push(range);
+	}
+
+	if ranges.is_empty() {
+		return Some(Err(Unsatisfiable));
+	}
+
+	ranges.sort_by_key(|range| range.start);
+	let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+	for range in ranges {
+		match merged.last_mut() {
+			Some(last) if range.start <= last.end.saturating_add(1) => {
+				last.end = last.end.max(range.end);
+			}
+			_ => merged.push(range),
+		}
+	}
+
+	Some(Ok(merged))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn range(start: u64, end: u64) -> ByteRange {
+		ByteRange { start, end }
+	}
+
+	#[test]
+	fn single_ranges() {
+		assert_eq!(
+			parse_range("bytes=0-99", 1000),
+			Some(Ok(vec![range(0, 99)]))
+		);
+		assert_eq!(
+			parse_range("bytes=500-", 1000),
+			Some(Ok(vec![range(500, 999)]))
+		);
+		assert_eq!(
+			parse_range("bytes=-100", 1000),
+			Some(Ok(vec![range(900, 999)]))
+		);
+		assert_eq!(
+			parse_range("bytes=-2000", 1000),
+			Some(Ok(vec![range(0, 999)]))
+		);
+		assert_eq!(
+			parse_range("bytes=900-5000", 1000),
+			Some(Ok(vec![range(900, 999)]))
+		);
+	}
+
+	#[test]
+	fn multiple_ranges_get_merged() {
+		assert_eq!(
+			parse_range("bytes=0-9, 20-29", 100),
+			Some(Ok(vec![range(0, 9), range(20, 29)]))
+		);
+		assert_eq!(
+			parse_range("bytes=20-29,0-10,5-19", 100),
+			Some(Ok(vec![range(0, 29)]))
+		);
+	}
+
+	#[test]
+	fn unsatisfiable_and_invalid_ranges() {
+		assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(Unsatisfiable)));
+		assert_eq!(parse_range("bytes=-0", 1000), Some(Err(Unsatisfiable)));
+		assert_eq!(parse_range("bytes=0-", 0), Some(Err(Unsatisfiable)));
+		assert_eq!(
+			parse_range("bytes=2000-3000, 0-0", 1000),
+			Some(Ok(vec![range(0, 0)]))
+		);
+
+		assert_eq!(parse_range("items=0-10", 1000), None);
+		assert_eq!(parse_range("bytes=10-0", 1000), None);
+		assert_eq!(parse_range("bytes=abc", 1000), None);
+		let too_many = (0..=MAX_RANGES)
+			.map(|idx| format!("{}-{}", idx * 10, idx * 10 + 1))
+			.collect::<Vec<_>>()
+			.join(",");
+		assert_eq!(parse_range(&format!("bytes={too_many}"), 1000), None);
+	}
+}
@@ -0,0 +1,447 @@
+//! Sessions of logged in users, persisted with the [Db](crate::db::Db), listed and revoked through
+//! `/api/sessions`
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use hyper::header::SET_COOKIE;
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+use crate::settings::Settings;
+use crate::Db;
+
+/// Removes the session cookie from the browser
+pub const CLEAR_SESSION_COOKIE: &str = "session=; Max-Age=0; Secure; HttpOnly; SameSite=None";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+	/// Identifies the session in `/api/sessions`. Unlike the token, it doesn't let anyone in.
+	pub id: Uuid,
+	pub account: Uuid,
+	pub created: SystemTime,
+	pub last_seen: SystemTime,
+	/// Address the session was last used from
+	pub client_ip: Option<IpAddr>,
+	/// `User-Agent` of the login request
+	pub user_agent: Option<String>,
+	/// When the cookie last sent to the client expires
+	pub cookie_expires: SystemTime,
+}
+
+impl SessionInfo {
+	pub fn new(
+		account: Uuid,
+		client_ip: Option<IpAddr>,
+		user_agent: Option<String>,
+		settings: &Settings,
+	) -> Self {
+		let now = SystemTime::now();
+		SessionInfo {
+			id: Uuid::new_v4(),
+			account,
+			created: now,
+			last_seen: now,
+			client_ip,
+			user_agent,
+			cookie_expires: now + settings.session_cookie_max_age(),
+		}
+	}
+
+	/// When the session expires, unless it's used again before then
+	pub fn expires(&self, settings: &Settings) -> SystemTime {
+		let idle = self.last_seen + Duration::from_secs(settings.session_idle_timeout_secs);
+		let absolute = self.created + Duration::from_secs(settings.session_absolute_timeout_secs);
+		idle.min(absolute)
+	}
+
+	pub fn is_expired(&self, settings: &Settings, now: SystemTime) -> bool {
+		self.expires(settings) <= now
+	}
+
+	/// Marks the session as used now. Returns the `Max-Age` of the cookie to send again, if the
+	/// use extended the session by at least [Settings::session_cookie_renewal_secs] past the
+	/// expiry of the client's cookie.
+	fn touch(
+		&mut self,
+		client_ip: Option<IpAddr>,
+		settings: &Settings,
+		now: SystemTime,
+	) -> Option<Duration> {
+		self.last_seen = now;
+		if client_ip.is_some() {
+			self.client_ip = client_ip;
+		}
+
+		let expires = self.expires(settings);
+		let extended_by = expires.duration_since(self.cookie_expires).ok()?;
+		if extended_by < Duration::from_secs(settings.session_cookie_renewal_secs) {
+			return None;
+		}
+		self.cookie_expires = expires;
+		Some(expires.duration_since(now).unwrap_or_default())
+	}
+}
+
+/// Session of a request, see [AuthorizedUsers::touch]
+#[derive(Debug, Clone, Copy)]
+pub struct TouchedSession {
+	pub account: Uuid,
+	/// `Max-Age` of the renewed session cookie, if it's due to be sent again
+	pub renew_cookie: Option<Duration>,
+}
+
+/// Key of a session in [AuthorizedUsers]. Only the hash of the token is stored, so that the
+/// sessions file doesn't let anyone log in.
+pub fn hash_token(token: &Uuid) -> String {
+	hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Concurrent hashmap containing logged in users, persisted with the [Db]
+/// key: [hash](hash_token) of the session cookie token
+/// value: the session, along with the Uuid of its user
+#[derive(Debug, Default, Clone)]
+pub struct AuthorizedUsers(Arc<DashMap<String, SessionInfo>>);
+
+impl AuthorizedUsers {
+	/// Starts a session of the account. Returns the token to be sent in the session cookie.
+	pub fn create_session(
+		&self,
+		account: Uuid,
+		client_ip: Option<IpAddr>,
+		user_agent: Option<String>,
+		settings: &Settings,
+	) -> Uuid {
+		let token = Uuid::new_v4();
+		self.insert(
+			hash_token(&token),
+			SessionInfo::new(account, client_ip, user_agent, settings),
+		);
+		token
+	}
+
+	/// Returns the session if it hasn't expired and marks it as just used by the client.
+	/// An expired session is removed.
+	pub fn touch(
+		&self,
+		token: &Uuid,
+		client_ip: Option<IpAddr>,
+		settings: &Settings,
+	) -> Option<TouchedSession> {
+		let now = SystemTime::now();
+		let key = hash_token(token);
+		self.remove_if(&key, |_, session| session.is_expired(settings, now));
+		let mut session = self.get_mut(&key)?;
+		let renew_cookie = session.touch(client_ip, settings, now);
+		Some(TouchedSession {
+			account: session.account,
+			renew_cookie,
+		})
+	}
+
+	/// Whether the session is still valid, without marking it as used. Lets long lived
+	/// connections notice that the session was revoked or has expired.
+	pub fn is_active(&self, token: &Uuid, settings: &Settings) -> bool {
+		self.get(&hash_token(token))
+			.is_some_and(|session| !session.is_expired(settings, SystemTime::now()))
+	}
+
+	/// [SessionInfo::id] of the session with the given token
+	pub fn session_id(&self, token: &Uuid) -> Option<Uuid> {
+		self.get(&hash_token(token)).map(|session| session.id)
+	}
+
+	/// Ends the session with the given token. Returns whether there was one.
+	pub fn log_out(&self, token: &Uuid) -> bool {
+		self.remove(&hash_token(token)).is_some()
+	}
+
+	pub fn sessions_of(&self, account: &Uuid) -> Vec<SessionInfo> {
+		self.iter()
+			.filter(|entry| entry.value().account == *account)
+			.map(|entry| entry.value().clone())
+			.collect()
+	}
+
+	/// Removes the session of the account with the given [SessionInfo::id]. Returns whether
+	/// there was one.
+	pub fn revoke(&self, account: &Uuid, session_id: &Uuid) -> bool {
+		let mut revoked = false;
+		self.retain(|_token, session| {
+			let matches = session.account == *account && session.id == *session_id;
+			revoked |= matches;
+			!matches
+		});
+		revoked
+	}
+
+	/// Logs the account out everywhere. Returns the number of removed sessions.
+	pub fn revoke_all(&self, account: &Uuid) -> usize {
+		let mut revoked = 0;
+		self.retain(|_token, session| {
+			let matches = session.account == *account;
+			revoked += usize::from(matches);
+			!matches
+		});
+		revoked
+	}
+
+	/// Returns the number of removed sessions
+	pub fn remove_expired(&self, settings: &Settings) -> usize {
+		let now = SystemTime::now();
+		let before = self.len();
+		self.retain(|_token, session| !session.is_expired(settings, now));
+		before.saturating_sub(self.len())
+	}
+
+	/// Copy of the sessions to be saved to disk
+	pub fn to_hash_map(&self) -> HashMap<String, SessionInfo> {
+		self.iter()
+			.map(|entry| (entry.key().clone(), entry.value().clone()))
+			.collect()
+	}
+}
+
+impl From<HashMap<String, SessionInfo>> for AuthorizedUsers {
+	fn from(sessions: HashMap<String, SessionInfo>) -> Self {
+		AuthorizedUsers(Arc::new(sessions.into_iter().collect()))
+	}
+}
+
+impl Deref for AuthorizedUsers {
+	type Target = Arc<DashMap<String, SessionInfo>>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+/// `Set-Cookie` value carrying the session token, kept by the browser for `max_age`
+pub fn session_cookie(token: &Uuid, max_age: Duration) -> String {
+	let max_age = max_age.as_secs();
+	format!("session={token}; Max-Age={max_age}; Secure; HttpOnly; SameSite=None")
+}
+
+#[derive(Debug, Error)]
+pub enum SessionsError {
+	#[error("Session id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("Session not found")]
+	NotFound,
+}
+
+impl HttpHandlerError for SessionsError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotLoggedIn => StatusCode::UNAUTHORIZED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionModel {
+	pub id: Uuid,
+	/// RFC 3339 date
+	pub created: String,
+	/// RFC 3339 date
+	pub last_seen: String,
+	/// RFC 3339 date the session expires at, unless it's used again before then
+	pub expires: String,
+	pub client_ip: Option<IpAddr>,
+	pub user_agent: Option<String>,
+	/// Whether it's the session the request was made with
+	pub current: bool,
+}
+
+/// Lists sessions of the current user, most recently used first
+pub async fn list_sessions(
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SessionsError>> {
+	let session = ctx
+		.session()
+		.into_handler_error()?
+		.ok_or(SessionsError::NotLoggedIn)?;
+
+	let settings = &db.config.settings;
+	let authorized_users = db.authorized_users();
+	let current_id = authorized_users.session_id(&session.token);
+	let mut sessions = authorized_users.sessions_of(&session.account.uuid);
+	sessions.sort_by_key(|info| std::cmp::Reverse(info.last_seen));
+	let now = SystemTime::now();
+	let sessions: Vec<SessionModel> = sessions
+		.into_iter()
+		// Not removed by the cleanup task yet
+		.filter(|info| !info.is_expired(settings, now))
+		.map(|info| SessionModel {
+			id: info.id,
+			created: humantime::format_rfc3339_seconds(info.created).to_string(),
+			last_seen: humantime::format_rfc3339_seconds(info.last_seen).to_string(),
+			expires: humantime::format_rfc3339_seconds(info.expires(settings)).to_string(),
+			client_ip: info.client_ip,
+			user_agent: info.user_agent,
+			current: Some(info.id) == current_id,
+		})
+		.collect();
+
+	json_response(StatusCode::OK, &sessions)
+}
+
+/// Revokes a single session of the current user. Revoking the current session logs the user out.
+pub async fn revoke_session(
+	session_id: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SessionsError>> {
+	let session_id = Uuid::parse_str(&session_id).into_handler_error()?;
+
+	let session = ctx
+		.session()
+		.into_handler_error()?
+		.ok_or(SessionsError::NotLoggedIn)?;
+
+	let authorized_users = db.authorized_users();
+	let current = authorized_users.session_id(&session.token) == Some(session_id);
+	if !authorized_users.revoke(&session.account.uuid, &session_id) {
+		return Err(SessionsError::NotFound.into());
+	}
+	debug!("Revoked session {session_id} of {}", session.account.uuid);
+	db.save_sessions().await;
+
+	let resp = Response::builder().status(StatusCode::NO_CONTENT);
+	let resp = if current {
+		resp.header(SET_COOKIE, CLEAR_SESSION_COOKIE)
+	} else {
+		resp
+	};
+	Ok(resp.body(Body::empty())?)
+}
+
+/// Logs the current user out everywhere, the current session included
+pub async fn revoke_all_sessions(
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SessionsError>> {
+	let session = ctx
+		.session()
+		.into_handler_error()?
+		.ok_or(SessionsError::NotLoggedIn)?;
+
+	let revoked = db.authorized_users().revoke_all(&session.account.uuid);
+	debug!("Revoked {revoked} sessions of {}", session.account.uuid);
+	db.save_sessions().await;
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.header(SET_COOKIE, CLEAR_SESSION_COOKIE)
+		.body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn settings() -> Settings {
+		Settings {
+			session_idle_timeout_secs: 60,
+			session_absolute_timeout_secs: 150,
+			session_cookie_renewal_secs: 10,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn idle_and_absolute_timeouts() {
+		let settings = settings();
+		let mut session = SessionInfo::new(Uuid::new_v4(), None, None, &settings);
+		let created = session.created;
+		assert_eq!(
+			session.expires(&settings),
+			created + Duration::from_secs(60)
+		);
+		assert!(session.is_expired(&settings, created + Duration::from_secs(60)));
+
+		session.last_seen = created + Duration::from_secs(50);
+		assert_eq!(
+			session.expires(&settings),
+			created + Duration::from_secs(110)
+		);
+		session.last_seen = created + Duration::from_secs(100);
+		assert_eq!(
+			session.expires(&settings),
+			created + Duration::from_secs(150)
+		);
+	}
+
+	#[test]
+	fn cookie_is_renewed_once_extended_enough() {
+		let settings = settings();
+		let mut session = SessionInfo::new(Uuid::new_v4(), None, None, &settings);
+		let created = session.created;
+
+		let now = created + Duration::from_secs(5);
+		assert_eq!(session.touch(None, &settings, now), None);
+		assert_eq!(session.last_seen, now);
+
+		let now = created + Duration::from_secs(20);
+		assert_eq!(
+			session.touch(None, &settings, now),
+			Some(Duration::from_secs(60))
+		);
+		assert_eq!(session.cookie_expires, created + Duration::from_secs(80));
+
+		assert!(session
+			.touch(None, &settings, created + Duration::from_secs(25))
+			.is_none());
+
+		// Capped by the absolute timeout
+		let now = created + Duration::from_secs(120);
+		assert_eq!(
+			session.touch(None, &settings, now),
+			Some(Duration::from_secs(30))
+		);
+		assert_eq!(session.touch(None, &settings, now), None);
+	}
+}
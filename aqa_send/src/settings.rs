@@ -0,0 +1,164 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Server settings, read from `DB/settings.json`. Missing fields use default values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+	/// Accept file passwords sent in the `?password=` query parameter.
+	///
+	/// Deprecated, as the password ends up in access logs, browser history and Referer headers.
+	/// Use the `aqa-password` header or unlock the file with a POST request instead.
+	pub allow_password_in_query: bool,
+
+	/// Number of invalid password attempts allowed before the backoff kicks in
+	pub password_free_attempts: u32,
+	/// Delay after the first attempt over the limit. Doubles with every next failure.
+	pub password_backoff_base_secs: u64,
+	pub password_backoff_max_secs: u64,
+	/// Failure counters are forgotten after being idle for this long
+	pub password_failures_reset_secs: u64,
+	/// Lock the entry after this many invalid attempts and notify the uploader
+	pub password_lockout_after: Option<u32>,
+	pub password_lockout_secs: u64,
+
+	/// Longest allowed lifetime of a signed download link
+	pub signed_link_max_lifetime_secs: u64,
+
+	/// Ranged requests of a client, that come within this long from its counted one, don't
+	/// count as another download
+	pub ranged_download_window_secs: u64,
+
+	/// Content types that `?disposition=inline` is honored for. Other files are always sent as
+	/// attachments, so that uploaded HTML or SVG can't run scripts in the context of the site.
+	pub inline_content_types: Vec<String>,
+
+	/// Bandwidth, in bytes per second, shared by all downloads and uploads
+	pub global_bandwidth_limit: Option<u64>,
+	/// Bandwidth limits of users who aren't logged in. Their per account limit applies to each IP
+	/// address separately.
+	pub anonymous_bandwidth: BandwidthLimits,
+	pub user_bandwidth: BandwidthLimits,
+	pub admin_bandwidth: BandwidthLimits,
+
+	/// Download log events are removed after this long. 0 disables the log.
+	pub download_log_retention_secs: u64,
+
+	/// Address the server is reachable at, e.g. `https://send.example.com`. Used for absolute
+	/// links in feeds.
+	pub public_url: Option<String>,
+	/// Origins of other sites allowed to open the WebSocket, e.g. `https://app.example.com`.
+	/// Browsers send the session cookie along with WebSocket handshakes started by any site, so
+	/// only pages of the server itself are allowed by default.
+	pub allowed_origins: Vec<String>,
+
+	/// Addresses of reverse proxies, whose `X-Forwarded-For` header is trusted to carry the
+	/// client address
+	pub trusted_proxies: Vec<IpAddr>,
+
+	/// Attempts of a webhook delivery before it's moved to the dead letters
+	pub webhook_max_attempts: u32,
+	/// Delay before the first retry of a webhook delivery. Doubles with every next one.
+	pub webhook_retry_base_secs: u64,
+	pub webhook_retry_max_secs: u64,
+	/// Time the receiver has to respond to a webhook request
+	pub webhook_timeout_secs: u64,
+	/// Allow webhooks to reach loopback, private and link-local addresses. Off by default, so
+	/// that webhooks can't be used to make requests to services on the internal network.
+	pub webhook_allow_private_addresses: bool,
+
+	/// A session expires after not being used for this long
+	pub session_idle_timeout_secs: u64,
+	/// A session expires this long after logging in, no matter how often it's used
+	pub session_absolute_timeout_secs: u64,
+	/// The session cookie is sent again, with a renewed `Max-Age`, once using the session
+	/// extended it by this much past the expiry of the cookie the client has
+	pub session_cookie_renewal_secs: u64,
+}
+
+/// Bandwidth limits in bytes per second. `None` means unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthLimits {
+	/// Limit of a single download or upload
+	pub per_connection: Option<u64>,
+	/// Limit shared by all downloads and uploads of an account
+	pub per_account: Option<u64>,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			allow_password_in_query: true,
+
+			password_free_attempts: 3,
+			password_backoff_base_secs: 1,
+			password_backoff_max_secs: 60 * 60,
+			password_failures_reset_secs: 60 * 60 * 24,
+			password_lockout_after: None,
+			password_lockout_secs: 60 * 60 * 24,
+
+			signed_link_max_lifetime_secs: 60 * 60 * 24 * 30,
+
+			ranged_download_window_secs: 60 * 60,
+
+			inline_content_types: [
+				"text/plain",
+				"image/png",
+				"image/jpeg",
+				"image/gif",
+				"image/webp",
+				"audio/mpeg",
+				"audio/ogg",
+				"video/mp4",
+				"video/webm",
+				"application/pdf",
+			]
+			.into_iter()
+			.map(String::from)
+			.collect(),
+
+			global_bandwidth_limit: None,
+			anonymous_bandwidth: BandwidthLimits::default(),
+			user_bandwidth: BandwidthLimits::default(),
+			admin_bandwidth: BandwidthLimits::default(),
+
+			download_log_retention_secs: 60 * 60 * 24 * 90,
+
+			public_url: None,
+			allowed_origins: Vec::new(),
+			trusted_proxies: Vec::new(),
+
+			webhook_max_attempts: 5,
+			webhook_retry_base_secs: 10,
+			webhook_retry_max_secs: 60 * 60,
+			webhook_timeout_secs: 10,
+			webhook_allow_private_addresses: false,
+
+			session_idle_timeout_secs: 60 * 60 * 24 * 7,
+			session_absolute_timeout_secs: 60 * 60 * 24 * 30,
+			session_cookie_renewal_secs: 60,
+		}
+	}
+}
+
+impl Settings {
+	/// Whether the content type is one of [Settings::inline_content_types]. Parameters, such as
+	/// `charset`, are ignored.
+	pub fn inline_allowed(&self, content_type: &str) -> bool {
+		let essence = content_type.split(';').next().unwrap_or_default().trim();
+		self.inline_content_types
+			.iter()
+			.any(|allowed| allowed.eq_ignore_ascii_case(essence))
+	}
+
+	/// `Max-Age` of the cookie of a new session
+	pub fn session_cookie_max_age(&self) -> Duration {
+		Duration::from_secs(
+			self.session_idle_timeout_secs
+				.min(self.session_absolute_timeout_secs),
+		)
+	}
+}
@@ -0,0 +1,246 @@
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::{Account, AccountType};
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+use crate::events::EventKind;
+use crate::headers::{HeaderError, ShareWith, ShareWithGroups, SHARE_WITH, SHARE_WITH_GROUPS};
+
+#[derive(Debug, Error)]
+pub enum ShareError {
+	#[error("File id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AqaHeader(#[from] HeaderError),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("File id not found or not present")]
+	NotFound,
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("You can only share your own files")]
+	NotAuthorized,
+
+	#[error("Account {0:?} doesn't exist")]
+	UnknownAccount(String),
+
+	#[error("Group {0:?} doesn't exist")]
+	UnknownGroup(String),
+
+	#[error("You can only share files with groups you're a member of ({0:?})")]
+	NotGroupMember(String),
+}
+
+impl HttpHandlerError for ShareError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AqaHeader(err) => err.code(),
+			Self::AuthError(err) => err.code(),
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::NotLoggedIn => StatusCode::FORBIDDEN,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+			Self::UnknownAccount(_) => StatusCode::BAD_REQUEST,
+			Self::UnknownGroup(_) => StatusCode::BAD_REQUEST,
+			Self::NotGroupMember(_) => StatusCode::FORBIDDEN,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::Uuid(_) => true,
+			Self::AqaHeader(err) => err.user_presentable(),
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			Self::NotFound => true,
+			Self::NotLoggedIn => true,
+			Self::NotAuthorized => true,
+			Self::UnknownAccount(_) => true,
+			Self::UnknownGroup(_) => true,
+			Self::NotGroupMember(_) => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SharedWith {
+	pub accounts: Vec<SharedAccount>,
+	pub groups: Vec<SharedGroup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SharedAccount {
+	pub uuid: Uuid,
+	pub username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SharedGroup {
+	pub uuid: Uuid,
+	pub name: String,
+}
+
+/// Resolves usernames and account uuids into a deduplicated list of account uuids.
+/// The uploader always has access, so they are left out.
+pub async fn resolve_share_with(
+	db: &Db,
+	share_with: &ShareWith,
+	uploader: Option<Uuid>,
+) -> Result<Vec<Uuid>, ShareError> {
+	let mut shared_with = Vec::with_capacity(share_with.0.len());
+	for account in &share_with.0 {
+		let uuid = db
+			.find_account_uuid(account)
+			.await
+			.ok_or_else(|| ShareError::UnknownAccount(account.clone()))?;
+		if Some(uuid) != uploader && !shared_with.contains(&uuid) {
+			shared_with.push(uuid);
+		}
+	}
+	Ok(shared_with)
+}
+
+/// Resolves group names and uuids into a deduplicated list of group uuids. Only members of
+/// a group (and admins) can share entries with it.
+pub async fn resolve_share_with_groups(
+	db: &Db,
+	share_with_groups: &ShareWithGroups,
+	sharer: Option<&Account>,
+) -> Result<Vec<Uuid>, ShareError> {
+	let mut shared_with_groups = Vec::with_capacity(share_with_groups.0.len());
+	for group in &share_with_groups.0 {
+		let uuid = db
+			.find_group_uuid(group)
+			.await
+			.ok_or_else(|| ShareError::UnknownGroup(group.clone()))?;
+		let is_member = match sharer {
+			Some(sharer) if matches!(sharer.acc_type, AccountType::Admin) => true,
+			Some(sharer) => db
+				.groups_reader()
+				.await
+				.get(&uuid)
+				.is_some_and(|group| group.is_member(&sharer.uuid)),
+			None => false,
+		};
+		if !is_member {
+			return Err(ShareError::NotGroupMember(group.clone()));
+		}
+		if !shared_with_groups.contains(&uuid) {
+			shared_with_groups.push(uuid);
+		}
+	}
+	Ok(shared_with_groups)
+}
+
+/// Replaces the accounts and/or groups the entry is shared with by the ones from the
+/// `aqa-share-with` and `aqa-share-with-groups` headers. A missing header leaves the respective
+/// list unchanged, an empty one clears it.
+pub async fn share(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<ShareError>> {
+	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(ShareError::NotLoggedIn)?;
+
+	let share_with: Option<ShareWith> = req
+		.headers()
+		.get(SHARE_WITH)
+		.map(TryInto::try_into)
+		.transpose()
+		.into_handler_error()?;
+	let share_with_groups: Option<ShareWithGroups> = req
+		.headers()
+		.get(SHARE_WITH_GROUPS)
+		.map(TryInto::try_into)
+		.transpose()
+		.into_handler_error()?;
+	if share_with.is_none() && share_with_groups.is_none() {
+		return Err(HeaderError::ShareWithHeaderMissing).into_handler_error();
+	}
+
+	let file_entry = db.get(&uuid).await.ok_or(ShareError::NotFound)?;
+	if !file_entry.can_manage(&current_user) {
+		return Err(ShareError::NotAuthorized.into());
+	}
+
+	let shared_with = match share_with {
+		Some(ref share_with) => {
+			Some(resolve_share_with(&db, share_with, file_entry.uploader_uuid).await?)
+		}
+		None => None,
+	};
+	let shared_with_groups = match share_with_groups {
+		Some(ref share_with_groups) => {
+			Some(resolve_share_with_groups(&db, share_with_groups, Some(&current_user)).await?)
+		}
+		None => None,
+	};
+	debug!("Sharing {uuid} with {shared_with:?} and groups {shared_with_groups:?}");
+
+	let (shared_with, shared_with_groups) = match db.writer().await.get_mut(&uuid) {
+		Some(file_entry) => {
+			if let Some(shared_with) = shared_with {
+				file_entry.shared_with = shared_with;
+			}
+			if let Some(shared_with_groups) = shared_with_groups {
+				file_entry.shared_with_groups = shared_with_groups;
+			}
+			db.publish(EventKind::Updated, uuid, file_entry.clone());
+			(
+				file_entry.shared_with.clone(),
+				file_entry.shared_with_groups.clone(),
+			)
+		}
+		None => return Err(ShareError::NotFound.into()),
+	};
+
+	let accounts = db.accounts_reader().await;
+	let accounts: Vec<SharedAccount> = shared_with
+		.into_iter()
+		.filter_map(|uuid| {
+			accounts.get(&uuid).map(|account| SharedAccount {
+				uuid,
+				username: account.username.clone(),
+			})
+		})
+		.collect();
+	let groups = db.groups_reader().await;
+	let groups: Vec<SharedGroup> = shared_with_groups
+		.into_iter()
+		.filter_map(|uuid| {
+			groups.get(&uuid).map(|group| SharedGroup {
+				uuid,
+				name: group.name.clone(),
+			})
+		})
+		.collect();
+
+	json_response(StatusCode::OK, &SharedWith { accounts, groups })
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+use crate::multipart::{self, Multipart, MultipartError};
+use crate::uri_query_iter;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 5; // 5 KB
+
+/// Download links minted by uploaders, persisted together with the key used to sign them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SignedLinks {
+	/// Hex encoded HMAC-SHA256 key
+	secret: String,
+	/// Key: link nonce
+	links: HashMap<Uuid, SignedLink>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedLink {
+	pub file_uuid: Uuid,
+	/// Seconds since the unix epoch
+	pub expires: u64,
+	pub single_use: bool,
+	pub created_by: Uuid,
+}
+
+/// Signed link parameters sent in the download query string
+#[derive(Debug)]
+pub struct SignedLinkQuery {
+	pub expires: u64,
+	pub nonce: Uuid,
+	pub signature: Vec<u8>,
+}
+
+impl SignedLinks {
+	/// Generates the signing key if there isn't one yet. Returns whether a new key was created.
+	pub fn ensure_secret(&mut self) -> bool {
+		if !self.secret.is_empty() {
+			return false;
+		}
+		let mut secret = [0_u8; 32];
+		OsRng.fill_bytes(&mut secret);
+		self.secret = hex::encode(secret);
+		true
+	}
+
+	fn mac(&self, file_uuid: &Uuid, expires: u64, nonce: &Uuid) -> HmacSha256 {
+		let secret = hex::decode(&self.secret).unwrap_or_default();
+		let mut mac = HmacSha256::new_from_slice(&secret).expect("HMAC accepts keys of any size");
+		mac.update(format!("{file_uuid}:{expires}:{nonce}").as_bytes());
+		mac
+	}
+
+	pub fn sign(&self, file_uuid: &Uuid, expires: u64, nonce: &Uuid) -> String {
+		hex::encode(self.mac(file_uuid, expires, nonce).finalize().into_bytes())
+	}
+
+	pub fn insert(&mut self, nonce: Uuid, link: SignedLink) {
+		self.links.insert(nonce, link);
+	}
+
+	/// Checks the signature (in constant time), expiry and whether the link was revoked
+	pub fn check(
+		&self,
+		file_uuid: &Uuid,
+		query: &SignedLinkQuery,
+		now: SystemTime,
+	) -> Result<&SignedLink, SignedLinkError> {
+		self.mac(file_uuid, query.expires, &query.nonce)
+			.verify_slice(&query.signature)
+			.map_err(|_| SignedLinkError::InvalidSignature)?;
+		if query.expires <= unix_secs(now) {
+			return Err(SignedLinkError::Expired);
+		}
+		match self.links.get(&query.nonce) {
+			Some(link) if link.file_uuid == *file_uuid => Ok(link),
+			_ => Err(SignedLinkError::Revoked),
+		}
+	}
+
+	/// Single use links are removed once used. Returns `false` if someone else used it first.
+	pub fn consume(&mut self, nonce: &Uuid) -> bool {
+		match self.links.get(nonce) {
+			Some(link) if link.single_use => self.links.remove(nonce).is_some(),
+			Some(_) => true,
+			None => false,
+		}
+	}
+
+	pub fn is_single_use(&self, nonce: &Uuid) -> bool {
+		self.links.get(nonce).is_some_and(|link| link.single_use)
+	}
+
+	pub fn links_for(&self, file_uuid: &Uuid) -> impl Iterator<Item = (&Uuid, &SignedLink)> {
+		let file_uuid = *file_uuid;
+		self.links
+			.iter()
+			.filter(move |(_nonce, link)| link.file_uuid == file_uuid)
+	}
+
+	pub fn revoke(&mut self, file_uuid: &Uuid, nonce: &Uuid) -> bool {
+		match self.links.get(nonce) {
+			Some(link) if link.file_uuid == *file_uuid => self.links.remove(nonce).is_some(),
+			_ => false,
+		}
+	}
+
+	/// Removes expired links and links to entries that no longer exist
+	pub fn remove_stale(&mut self, removed_entries: &[Uuid], now: SystemTime) {
+		let now = unix_secs(now);
+		self.links.retain(|_nonce, link| {
+			link.expires > now && !removed_entries.contains(&link.file_uuid)
+		});
+	}
+}
+
+impl SignedLinkQuery {
+	/// Returns `None` if the query doesn't contain a signed link
+	pub fn from_query(query: Option<&str>) -> Option<Result<Self, SignedLinkError>> {
+		let query = query?;
+		let (mut expires, mut nonce, mut signature) = (None, None, None);
+		for (key, value) in uri_query_iter(query) {
+			match key {
+				"expires" => expires = Some(value),
+				"nonce" => nonce = Some(value),
+				"signature" => signature = Some(value),
+				_ => (),
+			}
+		}
+		let signature = signature?;
+
+		let parse = || {
+			Ok(SignedLinkQuery {
+				expires: expires
+					.and_then(|expires| expires.parse().ok())
+					.ok_or(SignedLinkError::Malformed)?,
+				nonce: nonce
+					.and_then(|nonce| nonce.parse().ok())
+					.ok_or(SignedLinkError::Malformed)?,
+				signature: hex::decode(signature).map_err(|_| SignedLinkError::Malformed)?,
+			})
+		};
+		Some(parse())
+	}
+}
+
+/// Returns the signed link from the query, if there is one, after making sure it's valid for the
+/// entry
+pub async fn check_signed_link(
+	uuid: &Uuid,
+	query: Option<&str>,
+	db: &Db,
+) -> Result<Option<SignedLinkQuery>, SignedLinkError> {
+	let Some(signed_link) = SignedLinkQuery::from_query(query).transpose()? else {
+		return Ok(None);
+	};
+	db.signed_links_reader()
+		.await
+		.check(uuid, &signed_link, SystemTime::now())?;
+	Ok(Some(signed_link))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_secs())
+		.unwrap_or_default()
+}
+
+#[derive(Debug, Error)]
+pub enum SignedLinkError {
+	#[error("Id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Boundary(#[from] multipart::GetBoundaryError),
+
+	#[error(transparent)]
+	Multipart(#[from] MultipartError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("You can only manage links to your own files")]
+	NotAuthorized,
+
+	#[error("File id not found or not present")]
+	NotFound,
+
+	#[error("Link not found")]
+	LinkNotFound,
+
+	#[error("Expected `expires_in` to be a number of seconds, up to {0}")]
+	InvalidExpiresIn(u64),
+
+	#[error("Malformed download link")]
+	Malformed,
+
+	#[error("Invalid download link signature")]
+	InvalidSignature,
+
+	#[error("Download link expired")]
+	Expired,
+
+	#[error("Download link was revoked or already used")]
+	Revoked,
+
+	#[error("Single use download link is already being downloaded")]
+	InUse,
+}
+
+impl HttpHandlerError for SignedLinkError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::Boundary(err) => err.code(),
+			Self::Multipart(_) => StatusCode::BAD_REQUEST,
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotLoggedIn => StatusCode::FORBIDDEN,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::LinkNotFound => StatusCode::NOT_FOUND,
+			Self::InvalidExpiresIn(_) => StatusCode::BAD_REQUEST,
+			Self::Malformed => StatusCode::BAD_REQUEST,
+			Self::InvalidSignature => StatusCode::FORBIDDEN,
+			Self::Expired => StatusCode::FORBIDDEN,
+			Self::Revoked => StatusCode::FORBIDDEN,
+			Self::InUse => StatusCode::CONFLICT,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Boundary(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedLinkModel {
+	pub nonce: Uuid,
+	pub file_uuid: Uuid,
+	/// Seconds since the unix epoch
+	pub expires: u64,
+	pub single_use: bool,
+	/// Path and query of the download link
+	pub url: String,
+}
+
+impl SignedLinkModel {
+	fn new(signed_links: &SignedLinks, nonce: Uuid, link: &SignedLink) -> Self {
+		let signature = signed_links.sign(&link.file_uuid, link.expires, &nonce);
+		SignedLinkModel {
+			nonce,
+			file_uuid: link.file_uuid,
+			expires: link.expires,
+			single_use: link.single_use,
+			url: format!(
+				"/api/download/{}?expires={}&nonce={nonce}&signature={signature}",
+				link.file_uuid, link.expires
+			),
+		}
+	}
+}
+
+/// Mints a signed download link from a `multipart/form-data` request with an `expires_in`
+/// (seconds) and an optional `single_use` (`true`/`false`) field.
+pub async fn create_link(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SignedLinkError>> {
+	let file_uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let (parts, body) = req.into_parts();
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(SignedLinkError::NotLoggedIn)?;
+
+	let file_entry = db.get(&file_uuid).await.ok_or(SignedLinkError::NotFound)?;
+	if !file_entry.can_manage(&current_user) {
+		return Err(SignedLinkError::NotAuthorized.into());
+	}
+
+	let boundary = multipart::get_boundary_from_req(parts).map_err(SignedLinkError::from)?;
+	let mut multipart = Multipart::new(body, boundary, MAX_REQUEST_BODY_SIZE);
+	let chunks = multipart.read_all_chunks().await.into_handler_error()?;
+
+	let field = |name: &str| {
+		chunks
+			.iter()
+			.find(|(header, _data)| header.name == name)
+			.and_then(|(_header, data)| std::str::from_utf8(data).ok())
+	};
+
+	let max_lifetime = db.config.settings.signed_link_max_lifetime_secs;
+	let expires_in: u64 = field("expires_in")
+		.and_then(|expires_in| expires_in.trim().parse().ok())
+		.filter(|expires_in| (1..=max_lifetime).contains(expires_in))
+		.ok_or(SignedLinkError::InvalidExpiresIn(max_lifetime))?;
+	let single_use = field("single_use").is_some_and(|single_use| single_use.trim() == "true");
+
+	let link = SignedLink {
+		file_uuid,
+		expires: unix_secs(SystemTime::now() + Duration::from_secs(expires_in)),
+		single_use,
+		created_by: current_user.uuid,
+	};
+	let nonce = Uuid::new_v4();
+	debug!("Creating signed link {nonce} for {file_uuid}");
+
+	let model = {
+		let mut signed_links = db.signed_links_writer().await;
+		let model = SignedLinkModel::new(&signed_links, nonce, &link);
+		signed_links.insert(nonce, link);
+		model
+	};
+
+	json_response(StatusCode::CREATED, &model)
+}
+
+/// Lists links of the entry that haven't expired, been used up or revoked
+pub async fn list_links(
+	uuid: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SignedLinkError>> {
+	let file_uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(SignedLinkError::NotLoggedIn)?;
+
+	let file_entry = db.get(&file_uuid).await.ok_or(SignedLinkError::NotFound)?;
+	if !file_entry.can_manage(&current_user) {
+		return Err(SignedLinkError::NotAuthorized.into());
+	}
+
+	let now = unix_secs(SystemTime::now());
+	let signed_links = db.signed_links_reader().await;
+	let links: Vec<SignedLinkModel> = signed_links
+		.links_for(&file_uuid)
+		.filter(|(_nonce, link)| link.expires > now)
+		.map(|(nonce, link)| SignedLinkModel::new(&signed_links, *nonce, link))
+		.collect();
+	drop(signed_links);
+
+	json_response(StatusCode::OK, &links)
+}
+
+pub async fn revoke_link(
+	uuid: String,
+	nonce: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SignedLinkError>> {
+	let file_uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+	let nonce = Uuid::parse_str(&nonce).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(SignedLinkError::NotLoggedIn)?;
+
+	let file_entry = db.get(&file_uuid).await.ok_or(SignedLinkError::NotFound)?;
+	if !file_entry.can_manage(&current_user) {
+		return Err(SignedLinkError::NotAuthorized.into());
+	}
+
+	if !db.signed_links_writer().await.revoke(&file_uuid, &nonce) {
+		return Err(SignedLinkError::LinkNotFound.into());
+	}
+	debug!("Revoked signed link {nonce} for {file_uuid}");
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn signature_covers_uuid_expiry_and_nonce() {
+		let mut signed_links = SignedLinks::default();
+		assert!(signed_links.ensure_secret());
+		assert!(!signed_links.ensure_secret());
+
+		let now = SystemTime::now();
+		let (file_uuid, nonce) = (Uuid::new_v4(), Uuid::new_v4());
+		let expires = unix_secs(now) + 60;
+		signed_links.insert(
+			nonce,
+			SignedLink {
+				file_uuid,
+				expires,
+				single_use: true,
+				created_by: Uuid::new_v4(),
+			},
+		);
+
+		let query = |expires: u64, nonce: Uuid, file_uuid: &Uuid| SignedLinkQuery {
+			expires,
+			nonce,
+			signature: hex::decode(signed_links.sign(file_uuid, expires, &nonce)).unwrap(),
+		};
+
+		let valid = query(expires, nonce, &file_uuid);
+		assert!(signed_links.check(&file_uuid, &valid, now).is_ok());
+
+		let mut tampered = query(expires, nonce, &file_uuid);
+		tampered.expires += 1;
+		assert!(matches!(
+			signed_links.check(&file_uuid, &tampered, now),
+			Err(SignedLinkError::InvalidSignature)
+		));
+		assert!(matches!(
+			signed_links.check(&Uuid::new_v4(), &valid, now),
+			Err(SignedLinkError::InvalidSignature)
+		));
+		assert!(matches!(
+			signed_links.check(&file_uuid, &valid, now + Duration::from_secs(61)),
+			Err(SignedLinkError::Expired)
+		));
+
+		let unknown_nonce = query(expires, Uuid::new_v4(), &file_uuid);
+		assert!(matches!(
+			signed_links.check(&file_uuid, &unknown_nonce, now),
+			Err(SignedLinkError::Revoked)
+		));
+
+		assert!(signed_links.consume(&nonce));
+		assert!(!signed_links.consume(&nonce));
+		assert!(matches!(
+			signed_links.check(&file_uuid, &valid, now),
+			Err(SignedLinkError::Revoked)
+		));
+	}
+}
@@ -0,0 +1,183 @@
+//! `/api/events` Server-Sent Events stream of entry [events](crate::events), for clients that
+//! can't use the WebSocket
+
+use std::io;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio::time::{Instant, Interval};
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::Account;
+use crate::error::{HandlerError, HttpHandlerError, IntoHandlerError};
+use crate::events::Event;
+use crate::uri_query_iter;
+
+/// Comments sent while there are no events, so proxies don't close the connection
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum SseError {
+	#[error(transparent)]
+	Auth(#[from] AuthError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("Last-Event-ID must be an event id")]
+	InvalidLastEventId,
+}
+
+impl HttpHandlerError for SseError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Auth(err) => err.code(),
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::InvalidLastEventId => StatusCode::BAD_REQUEST,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::Auth(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			Self::InvalidLastEventId => true,
+		}
+	}
+}
+
+/// Streams events of the entries the user would see in `list.json`. A client reconnecting with
+/// the `Last-Event-ID` header, or the `last_event_id` query parameter, first gets the events it
+/// missed. If they're no longer available, it gets a `reset` event and should list the entries
+/// again. The stream of a logged in user ends once their session ends.
+pub async fn events(
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<SseError>> {
+	let session = ctx.session_or_anonymous().into_handler_error()?;
+	let session_token = session.as_ref().map(|session| session.token);
+	let viewer = session.map(|session| session.account);
+
+	let last_event_id = req
+		.headers()
+		.get("Last-Event-ID")
+		.map(|hv| hv.to_str().unwrap_or_default())
+		.or_else(|| {
+			uri_query_iter(req.uri().query().unwrap_or_default())
+				.find(|(key, _)| *key == "last_event_id")
+				.map(|(_, id)| id)
+		})
+		.map(|id| {
+			id.trim()
+				.parse::<u64>()
+				.map_err(|_| SseError::InvalidLastEventId)
+		})
+		.transpose()?;
+
+	let (replay, receiver) = match last_event_id {
+		Some(last_event_id) => {
+			let subscription = db.subscribe_since(last_event_id);
+			let replay = if subscription.gap {
+				vec![Bytes::from_static(b"event: reset\ndata: \n\n")]
+			} else {
+				let groups = db.groups_reader().await;
+				subscription
+					.replay
+					.iter()
+					.filter(|event| event.is_visible_to(viewer.as_ref(), &groups))
+					.map(|event| format_event(event, viewer.as_ref()))
+					.collect::<Result<_, _>>()
+					.into_handler_error()?
+			};
+			(replay, subscription.receiver)
+		}
+		None => (Vec::new(), db.subscribe()),
+	};
+
+	let live = LiveEvents {
+		receiver,
+		db,
+		viewer,
+		session_token,
+		keep_alive: tokio::time::interval_at(
+			Instant::now() + KEEP_ALIVE_INTERVAL,
+			KEEP_ALIVE_INTERVAL,
+		),
+	};
+	let stream = futures::stream::iter(replay.into_iter().map(Ok))
+		.chain(futures::stream::unfold(live, LiveEvents::next));
+
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header(CONTENT_TYPE, "text/event-stream")
+		.header(CACHE_CONTROL, "no-cache")
+		// Stops nginx from buffering the stream
+		.header("X-Accel-Buffering", "no")
+		.body(Body::wrap_stream(stream))?)
+}
+
+struct LiveEvents {
+	receiver: broadcast::Receiver<Event>,
+	db: Db,
+	viewer: Option<Account>,
+	/// Token of the viewer's session, checked before every message
+	session_token: Option<Uuid>,
+	keep_alive: Interval,
+}
+
+impl LiveEvents {
+	fn session_ended(&self) -> bool {
+		self.session_token.is_some_and(|token| {
+			!self
+				.db
+				.authorized_users()
+				.is_active(&token, &self.db.config.settings)
+		})
+	}
+
+	async fn next(mut self) -> Option<(io::Result<Bytes>, Self)> {
+		loop {
+			tokio::select! {
+				event = self.receiver.recv() => match event {
+					Ok(event) => {
+						if self.session_ended() {
+							return None;
+						}
+						let groups = self.db.groups_reader().await;
+						if !event.is_visible_to(self.viewer.as_ref(), &groups) {
+							continue;
+						}
+						drop(groups);
+						let message = format_event(&event, self.viewer.as_ref());
+						return Some((message.map_err(io::Error::from), self));
+					}
+					// The client reconnects with the last id it got and catches up from the
+					// replay buffer
+					Err(broadcast::error::RecvError::Lagged(_) | broadcast::error::RecvError::Closed) => {
+						return None;
+					}
+				},
+				_ = self.keep_alive.tick() => {
+					if self.session_ended() {
+						return None;
+					}
+					return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), self));
+				}
+			}
+		}
+	}
+}
+
+fn format_event(event: &Event, viewer: Option<&Account>) -> Result<Bytes, serde_json::Error> {
+	let data = serde_json::to_string(&event.model(viewer))?;
+	Ok(Bytes::from(format!("id: {}\ndata: {data}\n\n", event.id)))
+}
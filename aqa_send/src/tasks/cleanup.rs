@@ -4,6 +4,7 @@ use std::time::Duration;
 use tokio::time::Instant;
 use uuid::Uuid;
 
+use crate::events::EventKind;
 use crate::{Db, DownloadCount, FileEntry, Lifetime};
 
 /// Default cleanup interval is 1 hour
@@ -17,7 +18,7 @@ pub async fn cleanup_task(db: Db, cleanup_interval: Duration, start_lag: Duratio
 		let _ = cleanup_tick.tick().await;
 		debug!("Cleanup task starting");
 
-		let mut db_entries_to_delete = Vec::<Uuid>::new();
+		let mut db_entries_to_delete = Vec::<(Uuid, EventKind)>::new();
 		let mut deleted_files_count: u64 = 0;
 
 		let mut writer_lock = db.writer().await;
@@ -25,7 +26,7 @@ pub async fn cleanup_task(db: Db, cleanup_interval: Duration, start_lag: Duratio
 		for (uuid, file_entry) in writer_lock.iter_mut() {
 			if let DownloadCount::Count(max_count) = file_entry.download_count_type {
 				if file_entry.download_count >= max_count {
-					db_entries_to_delete.push(*uuid);
+					db_entries_to_delete.push((*uuid, EventKind::UsedUp));
 					remove_file(
 						file_entry,
 						uuid,
@@ -40,7 +41,7 @@ pub async fn cleanup_task(db: Db, cleanup_interval: Duration, start_lag: Duratio
 			if let Lifetime::Duration(lifetime) = file_entry.lifetime {
 				if let Ok(elapsed) = file_entry.upload_date.elapsed() {
 					if elapsed > lifetime {
-						db_entries_to_delete.push(*uuid);
+						db_entries_to_delete.push((*uuid, EventKind::Expired));
 						remove_file(
 							file_entry,
 							uuid,
@@ -54,9 +55,25 @@ pub async fn cleanup_task(db: Db, cleanup_interval: Duration, start_lag: Duratio
 			}
 		}
 
-		for uuid in db_entries_to_delete {
-			writer_lock.remove(&uuid);
+		for (uuid, event_kind) in &db_entries_to_delete {
+			if let Some(file_entry) = writer_lock.remove(uuid) {
+				db.publish(*event_kind, *uuid, file_entry);
+			}
 		}
+		drop(writer_lock);
+		let db_entries_to_delete: Vec<Uuid> = db_entries_to_delete
+			.into_iter()
+			.map(|(uuid, _)| uuid)
+			.collect();
+
+		db.remove_stale_password_failures(&db_entries_to_delete)
+			.await;
+		db.remove_expired_unlock_tokens();
+		db.remove_expired_sessions();
+		db.remove_stale_ranged_downloads();
+		db.remove_idle_bandwidth_buckets();
+		db.remove_stale_download_events();
+		db.remove_stale_signed_links(&db_entries_to_delete).await;
 
 		debug!("Cleanup task finished");
 		info!("Cleanup removed {} files.", deleted_files_count);
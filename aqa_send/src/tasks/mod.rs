@@ -1,2 +1,3 @@
 pub mod cleanup;
 pub mod save;
+pub mod webhooks;
@@ -0,0 +1,75 @@
+use std::future::Future;
+
+use log::{debug, error, warn};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::Event;
+use crate::webhooks::{self, Delivery, Webhook, WebhookClient};
+use crate::Db;
+
+/// Hands every published event to the webhooks that want it. Subscribes right away, so no event
+/// published after the call is missed.
+pub fn webhook_task(db: Db) -> impl Future<Output = ()> {
+	let mut receiver = db.subscribe();
+	let client = webhooks::client(&db.config.settings);
+
+	async move {
+		let mut last_id = None;
+		loop {
+			let event = match receiver.recv().await {
+				Ok(event) => event,
+				Err(RecvError::Lagged(skipped)) => {
+					debug!("Webhook task fell behind by {skipped} events");
+					// Catches up from the replay buffer. Without a previous event the receiver
+					// just continues with the oldest event it still has.
+					let Some(id) = last_id else {
+						continue;
+					};
+					let subscription = db.subscribe_since(id);
+					if subscription.gap {
+						warn!("Webhooks missed events following event {id}");
+					}
+					receiver = subscription.receiver;
+					for event in subscription.replay {
+						last_id = Some(event.id);
+						dispatch(&db, &client, &event).await;
+					}
+					continue;
+				}
+				Err(RecvError::Closed) => return,
+			};
+			last_id = Some(event.id);
+			dispatch(&db, &client, &event).await;
+		}
+	}
+}
+
+async fn dispatch(db: &Db, client: &WebhookClient, event: &Event) {
+	let hooks: Vec<Webhook> = db
+		.webhooks_reader()
+		.await
+		.iter()
+		.filter(|webhook| webhook.wants(event))
+		.cloned()
+		.collect();
+
+	for webhook in hooks {
+		let owner = match webhook.owner {
+			Some(owner) => db.get_account(&owner).await,
+			None => None,
+		};
+		let delivery = match Delivery::new(&webhook, event, owner.as_ref()) {
+			Ok(delivery) => delivery,
+			Err(err) => {
+				error!("Failed to serialize webhook payload: {err:?}");
+				continue;
+			}
+		};
+		tokio::spawn(webhooks::deliver(
+			client.clone(),
+			db.clone(),
+			webhook,
+			delivery,
+		));
+	}
+}
@@ -0,0 +1,332 @@
+//! Token bucket bandwidth limits of downloads and uploads
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::Stream;
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use thiserror::Error;
+use tokio::time::{Instant, Sleep};
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::{Account, AccountType};
+use crate::error::{ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError};
+use crate::events::EventKind;
+use crate::headers::{parse_bandwidth_limit, BandwidthLimit, HeaderError, BANDWIDTH_LIMIT};
+use crate::settings::{BandwidthLimits, Settings};
+
+/// Token bucket, where a token is a byte. It holds at most a second worth of tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+	/// Bytes per second
+	rate: u64,
+	state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+	/// Negative when transfers took more than there was
+	tokens: f64,
+	updated: Instant,
+}
+
+impl TokenBucket {
+	pub fn new(rate: u64, now: Instant) -> Self {
+		let rate = rate.max(1);
+		TokenBucket {
+			rate,
+			state: Mutex::new(BucketState {
+				tokens: rate as f64,
+				updated: now,
+			}),
+		}
+	}
+
+	/// Takes `amount` tokens, going into debt if there aren't enough. Returns how long to wait
+	/// for the debt to be paid off.
+	pub fn consume(&self, amount: u64, now: Instant) -> Duration {
+		let mut state = self.state.lock().unwrap();
+		self.refill(&mut state, now);
+		state.tokens -= amount as f64;
+		if state.tokens >= 0.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64(-state.tokens / self.rate as f64)
+		}
+	}
+
+	/// Whether the bucket refilled completely, i.e. it's no different from a new one
+	pub fn is_full(&self, now: Instant) -> bool {
+		let mut state = self.state.lock().unwrap();
+		self.refill(&mut state, now);
+		state.tokens >= self.rate as f64
+	}
+
+	fn refill(&self, state: &mut BucketState, now: Instant) {
+		let elapsed = now.saturating_duration_since(state.updated);
+		state.tokens =
+			(state.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+		state.updated = state.updated.max(now);
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum BucketOwner {
+	Account(Uuid),
+	Anonymous(Option<IpAddr>),
+}
+
+/// Buckets shared between transfers. Not persisted.
+#[derive(Debug)]
+pub struct Bandwidth {
+	global: Option<Arc<TokenBucket>>,
+	per_account: DashMap<BucketOwner, Arc<TokenBucket>>,
+}
+
+impl Bandwidth {
+	pub fn new(settings: &Settings) -> Self {
+		Bandwidth {
+			global: settings
+				.global_bandwidth_limit
+				.map(|rate| Arc::new(TokenBucket::new(rate, Instant::now()))),
+			per_account: DashMap::new(),
+		}
+	}
+
+	/// Buckets a transfer has to take tokens from. `entry_limit` replaces the per connection and
+	/// per account limits, but not the global one.
+	pub fn buckets(
+		&self,
+		settings: &Settings,
+		account: Option<&Account>,
+		client_ip: Option<IpAddr>,
+		entry_limit: Option<BandwidthLimit>,
+	) -> Vec<Arc<TokenBucket>> {
+		let now = Instant::now();
+		let mut buckets = Vec::with_capacity(3);
+
+		match entry_limit {
+			Some(BandwidthLimit::Unlimited) => (),
+			Some(BandwidthLimit::BytesPerSecond(rate)) => {
+				buckets.push(Arc::new(TokenBucket::new(rate, now)));
+			}
+			None => {
+				let (limits, owner) = match account {
+					Some(account) => (
+						limits_of(settings, &account.acc_type),
+						BucketOwner::Account(account.uuid),
+					),
+					None => (
+						&settings.anonymous_bandwidth,
+						BucketOwner::Anonymous(client_ip),
+					),
+				};
+				if let Some(rate) = limits.per_connection {
+					buckets.push(Arc::new(TokenBucket::new(rate, now)));
+				}
+				if let Some(rate) = limits.per_account {
+					let bucket = self
+						.per_account
+						.entry(owner)
+						.or_insert_with(|| Arc::new(TokenBucket::new(rate, now)));
+					buckets.push(Arc::clone(&bucket));
+				}
+			}
+		}
+
+		if let Some(ref global) = self.global {
+			buckets.push(Arc::clone(global));
+		}
+		buckets
+	}
+
+	/// Forgets per account buckets that aren't used by any transfer and refilled completely
+	pub fn remove_idle(&self) {
+		let now = Instant::now();
+		self.per_account
+			.retain(|_owner, bucket| Arc::strong_count(bucket) > 1 || !bucket.is_full(now));
+	}
+}
+
+fn limits_of<'a>(settings: &'a Settings, acc_type: &AccountType) -> &'a BandwidthLimits {
+	match acc_type {
+		AccountType::Admin => &settings.admin_bandwidth,
+		AccountType::User => &settings.user_bandwidth,
+	}
+}
+
+/// Holds back chunks of the inner stream until every bucket has enough tokens for them
+pub struct Throttled<S> {
+	inner: S,
+	buckets: Vec<Arc<TokenBucket>>,
+	delay: Option<Pin<Box<Sleep>>>,
+	/// Chunk that's sent once the delay elapses
+	pending: Option<Bytes>,
+}
+
+impl<S> Throttled<S> {
+	pub fn new(inner: S, buckets: Vec<Arc<TokenBucket>>) -> Self {
+		Throttled {
+			inner,
+			buckets,
+			delay: None,
+			pending: None,
+		}
+	}
+}
+
+impl<S, E> Stream for Throttled<S>
+where
+	S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+	type Item = Result<Bytes, E>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+
+		if let Some(delay) = this.delay.as_mut() {
+			ready!(delay.as_mut().poll(cx));
+			this.delay = None;
+			if let Some(chunk) = this.pending.take() {
+				return Poll::Ready(Some(Ok(chunk)));
+			}
+		}
+
+		let chunk = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+			Some(Ok(chunk)) => chunk,
+			item => return Poll::Ready(item),
+		};
+
+		let now = Instant::now();
+		let wait = this
+			.buckets
+			.iter()
+			.map(|bucket| bucket.consume(chunk.len() as u64, now))
+			.max()
+			.unwrap_or_default();
+		if wait.is_zero() {
+			return Poll::Ready(Some(Ok(chunk)));
+		}
+
+		this.pending = Some(chunk);
+		this.delay = Some(Box::pin(tokio::time::sleep_until(now + wait)));
+		Pin::new(this).poll_next(cx)
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ThrottleError {
+	#[error("File id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AqaHeader(#[from] HeaderError),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error("File id not found or not present")]
+	NotFound,
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("Only admins can change bandwidth limits")]
+	NotAuthorized,
+}
+
+impl HttpHandlerError for ThrottleError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AqaHeader(err) => err.code(),
+			Self::AuthError(err) => err.code(),
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::NotLoggedIn => StatusCode::FORBIDDEN,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AqaHeader(err) => err.user_presentable(),
+			Self::AuthError(err) => err.user_presentable(),
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+/// Sets the bandwidth limit of downloads of the entry from the `aqa-bandwidth-limit` header.
+/// Admin only.
+pub async fn set_bandwidth_limit(
+	uuid: String,
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<ThrottleError>> {
+	let uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(ThrottleError::NotLoggedIn)?;
+	if !matches!(current_user.acc_type, AccountType::Admin) {
+		return Err(ThrottleError::NotAuthorized.into());
+	}
+
+	let bandwidth_limit =
+		parse_bandwidth_limit(req.headers().get(BANDWIDTH_LIMIT)).into_handler_error()?;
+
+	match db.writer().await.get_mut(&uuid) {
+		Some(file_entry) => {
+			file_entry.bandwidth_limit = bandwidth_limit;
+			db.publish(EventKind::Updated, uuid, file_entry.clone());
+		}
+		None => return Err(ThrottleError::NotFound.into()),
+	}
+	debug!("Bandwidth limit of {uuid} set to {bandwidth_limit:?}");
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bucket_goes_into_debt() {
+		let start = Instant::now();
+		let bucket = TokenBucket::new(1000, start);
+
+		assert_eq!(bucket.consume(600, start), Duration::ZERO);
+		assert_eq!(bucket.consume(900, start), Duration::from_millis(500));
+		assert!(!bucket.is_full(start + Duration::from_millis(1400)));
+		assert!(bucket.is_full(start + Duration::from_millis(1500)));
+	}
+
+	#[test]
+	fn bucket_holds_at_most_a_second_of_tokens() {
+		let start = Instant::now();
+		let bucket = TokenBucket::new(1000, start);
+
+		let later = start + Duration::from_secs(60);
+		assert_eq!(bucket.consume(1000, later), Duration::ZERO);
+		assert_eq!(bucket.consume(250, later), Duration::from_millis(250));
+	}
+}
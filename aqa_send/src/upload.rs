@@ -8,12 +8,19 @@ use thiserror::Error;
 use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
-use crate::account::{get_logged_in_user, AuthError};
+use crate::account::AuthError;
+use crate::context::RequestContext;
 use crate::db::Db;
 use crate::db_stuff::FileEntry;
 use crate::error::{ErrorContentType, IntoHandlerError};
-use crate::headers::{DownloadCount, HeaderError, Lifetime, Password, Visibility, DOWNLOAD_COUNT};
-use crate::{AuthorizedUsers, HandlerError, HttpHandlerError, LIFETIME, PASSWORD, VISIBILITY};
+use crate::events::EventKind;
+use crate::headers::{
+	DownloadCount, HeaderError, Lifetime, Password, PasswordHash, ShareWith, ShareWithGroups,
+	Visibility, DOWNLOAD_COUNT, SHARE_WITH, SHARE_WITH_GROUPS,
+};
+use crate::share::{resolve_share_with, resolve_share_with_groups, ShareError};
+use crate::throttle::Throttled;
+use crate::{HandlerError, HttpHandlerError, LIFETIME, PASSWORD, VISIBILITY};
 
 #[derive(Debug, Error)]
 pub enum UploadError {
@@ -43,6 +50,12 @@ pub enum UploadError {
 
 	#[error(transparent)]
 	AuthError(#[from] AuthError),
+
+	#[error("Failed to hash the password")]
+	PasswordHash(argon2::password_hash::Error),
+
+	#[error(transparent)]
+	Share(#[from] ShareError),
 }
 
 impl HttpHandlerError for UploadError {
@@ -57,6 +70,8 @@ impl HttpHandlerError for UploadError {
 			UploadError::DbSerialize(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			UploadError::PrivateUploadWithoutAccount => StatusCode::UNAUTHORIZED,
 			UploadError::AuthError(err) => err.code(),
+			UploadError::PasswordHash(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			UploadError::Share(err) => err.code(),
 		}
 	}
 
@@ -71,6 +86,8 @@ impl HttpHandlerError for UploadError {
 			UploadError::DbSerialize(_) => false,
 			UploadError::PrivateUploadWithoutAccount => true,
 			UploadError::AuthError(_) => true,
+			UploadError::PasswordHash(_) => false,
+			UploadError::Share(err) => err.user_presentable(),
 		}
 	}
 
@@ -91,7 +108,7 @@ pub struct UploadedFile {
 pub async fn upload(
 	req: Request<Body>,
 	db: Db,
-	authorized_users: AuthorizedUsers,
+	ctx: RequestContext,
 ) -> Result<Response<Body>, HandlerError<UploadError>> {
 	use UploadError::{BoundaryExpected, FileCreate, FileWrite, InvalidContentType};
 
@@ -112,9 +129,7 @@ pub async fn upload(
 	let boundary = format!("--{}", boundary);
 	debug!("Boundary: {}", boundary);
 
-	let uploader = get_logged_in_user(&parts.headers, db.clone(), authorized_users.clone())
-		.await
-		.into_handler_error()?;
+	let uploader = ctx.user_or_anonymous().into_handler_error()?;
 
 	let download_count: DownloadCount = parts
 		.headers
@@ -127,6 +142,9 @@ pub async fn upload(
 		.map(|v| v.try_into())
 		.transpose()
 		.into_handler_error()?;
+	let password: Option<PasswordHash> =
+		tokio::task::block_in_place(|| password.as_ref().map(Password::hash).transpose())
+			.map_err(UploadError::PasswordHash)?;
 	let visibility: Visibility = parts
 		.headers
 		.get(VISIBILITY)
@@ -142,8 +160,35 @@ pub async fn upload(
 		return Err(UploadError::PrivateUploadWithoutAccount.into());
 	}
 
+	let share_with: ShareWith = parts
+		.headers
+		.get(SHARE_WITH)
+		.map(|v| v.try_into())
+		.transpose()
+		.into_handler_error()?
+		.unwrap_or_default();
+	let shared_with = resolve_share_with(
+		&db,
+		&share_with,
+		uploader.as_ref().map(|uploader| uploader.uuid),
+	)
+	.await
+	.into_handler_error()?;
+
+	let share_with_groups: ShareWithGroups = parts
+		.headers
+		.get(SHARE_WITH_GROUPS)
+		.map(|v| v.try_into())
+		.transpose()
+		.into_handler_error()?
+		.unwrap_or_default();
+	let shared_with_groups = resolve_share_with_groups(&db, &share_with_groups, uploader.as_ref())
+		.await
+		.into_handler_error()?;
+
+	let buckets = db.bandwidth_buckets(uploader.as_ref(), ctx.client_ip, None);
 	let mut multipart = Multipart {
-		body,
+		body: Body::wrap_stream(Throttled::new(body, buckets)),
 		boundary,
 		buf: BytesMut::default(),
 	};
@@ -176,18 +221,31 @@ pub async fn upload(
 
 			download_count_type: download_count,
 			download_count: 0,
+			size: None,
 
 			visibility,
 			password: password.clone(),
+			shared_with: shared_with.clone(),
+			shared_with_groups: shared_with_groups.clone(),
+			bandwidth_limit: None,
 
 			lifetime,
 			upload_date: SystemTime::now(),
 		};
 		db.put(upload_uuid, file_entry.clone()).await;
 
+		let mut size = 0_u64;
 		while let Some(chunk) = multipart.read_data().await {
 			let chunk = chunk.into_handler_error()?;
 			file.write_all(&chunk).await.map_err(FileWrite)?;
+			size += chunk.len() as u64;
+		}
+		// Tokio finishes writes in the background, the file has to be complete once the upload is
+		// acknowledged
+		file.flush().await.map_err(FileWrite)?;
+		if let Some(file_entry) = db.writer().await.get_mut(&upload_uuid) {
+			file_entry.size = Some(size);
+			db.publish(EventKind::Created, upload_uuid, file_entry.clone());
 		}
 
 		uploaded_files.push(UploadedFile {
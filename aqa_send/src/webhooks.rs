@@ -0,0 +1,731 @@
+//! Outgoing webhooks, posting entry [events](crate::events) to URLs registered by accounts and
+//! admins
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, USER_AGENT};
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::{Account, AccountType};
+use crate::error::{
+	json_response, ErrorContentType, HandlerError, HttpHandlerError, IntoHandlerError,
+};
+use crate::events::{Event, EventKind, EventModel};
+use crate::headers::{WEBHOOK_DELIVERY, WEBHOOK_EVENT, WEBHOOK_SIGNATURE};
+use crate::multipart::{self, Multipart, MultipartError};
+use crate::settings::Settings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 5; // 5 KB
+
+/// Oldest dead letters are dropped once there are more
+const MAX_DEAD_LETTERS: usize = 1000;
+
+/// Registered webhooks and the deliveries that failed, persisted in `DB/webhooks`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Webhooks {
+	/// Key: webhook uuid
+	hooks: HashMap<Uuid, Webhook>,
+	/// Deliveries that failed after all retries, oldest first
+	dead_letters: Vec<DeadLetter>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+	pub uuid: Uuid,
+	pub url: String,
+	/// Events the webhook is sent for. Empty means all of them.
+	pub events: Vec<EventKind>,
+	/// Key of the HMAC-SHA256 signature sent in the `aqa-signature` header
+	pub secret: String,
+	/// Account whose entries the webhook gets events of. `None` for global webhooks, which get
+	/// events of all entries.
+	pub owner: Option<Uuid>,
+	pub created_by: Uuid,
+}
+
+/// A delivery that failed after [Settings::webhook_max_attempts] attempts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+	pub delivery: Uuid,
+	pub webhook: Uuid,
+	pub event: EventKind,
+	pub file_uuid: Uuid,
+	pub date: SystemTime,
+	pub attempts: u32,
+	/// Error of the last attempt
+	pub error: String,
+	/// Body of the request that wasn't delivered
+	pub payload: String,
+}
+
+impl Webhook {
+	/// Whether the event should be delivered to the webhook
+	pub fn wants(&self, event: &Event) -> bool {
+		let kind_matches = self.events.is_empty() || self.events.contains(&event.kind);
+		let entry_matches = match self.owner {
+			Some(owner) => event.entry.uploader_uuid == Some(owner),
+			None => true,
+		};
+		kind_matches && entry_matches
+	}
+
+	/// Whether `account` is allowed to remove the webhook and read its dead letters
+	pub fn can_manage(&self, account: &Account) -> bool {
+		matches!(account.acc_type, AccountType::Admin) || self.owner == Some(account.uuid)
+	}
+}
+
+impl Webhooks {
+	pub fn get(&self, uuid: &Uuid) -> Option<&Webhook> {
+		self.hooks.get(uuid)
+	}
+
+	pub fn insert(&mut self, webhook: Webhook) {
+		self.hooks.insert(webhook.uuid, webhook);
+	}
+
+	/// Removes the webhook along with its dead letters
+	pub fn remove(&mut self, uuid: &Uuid) -> Option<Webhook> {
+		let webhook = self.hooks.remove(uuid)?;
+		self.dead_letters
+			.retain(|dead_letter| dead_letter.webhook != *uuid);
+		Some(webhook)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Webhook> {
+		self.hooks.values()
+	}
+
+	pub fn add_dead_letter(&mut self, dead_letter: DeadLetter) {
+		if self.dead_letters.len() >= MAX_DEAD_LETTERS {
+			self.dead_letters.remove(0);
+		}
+		self.dead_letters.push(dead_letter);
+	}
+
+	pub fn dead_letters_of(&self, webhook: &Uuid) -> impl Iterator<Item = &DeadLetter> {
+		let webhook = *webhook;
+		self.dead_letters
+			.iter()
+			.filter(move |dead_letter| dead_letter.webhook == webhook)
+	}
+}
+
+/// JSON body of a webhook request
+#[derive(Serialize, Deserialize)]
+pub struct WebhookPayload<'a> {
+	/// Same for all attempts of a delivery
+	pub delivery: Uuid,
+	pub webhook: Uuid,
+	pub event: EventModel<'a>,
+}
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of `body`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+	let mut mac =
+		HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+	mac.update(body);
+	format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before the attempt following `attempt`. Doubles with every attempt.
+pub fn retry_delay(settings: &Settings, attempt: u32) -> Duration {
+	let delay = settings
+		.webhook_retry_base_secs
+		.saturating_mul(2_u64.saturating_pow(attempt.saturating_sub(1)))
+		.min(settings.webhook_retry_max_secs);
+	Duration::from_secs(delay)
+}
+
+/// Client posting webhooks to `http://` and `https://` urls
+pub type WebhookClient = Client<HttpsConnector<HttpConnector<PublicResolver>>>;
+
+pub fn client(settings: &Settings) -> WebhookClient {
+	let resolver = PublicResolver {
+		inner: GaiResolver::new(),
+		allow_private: settings.webhook_allow_private_addresses,
+	};
+	let mut http = HttpConnector::new_with_resolver(resolver);
+	// https urls are passed on by the TLS connector
+	http.enforce_http(false);
+	let connector = HttpsConnectorBuilder::new()
+		.with_webpki_roots()
+		.https_or_http()
+		.enable_http1()
+		.wrap_connector(http);
+	Client::builder().build(connector)
+}
+
+/// Resolves the hosts of webhooks, leaving out addresses of the internal network unless
+/// [Settings::webhook_allow_private_addresses] is set
+#[derive(Clone)]
+pub struct PublicResolver {
+	inner: GaiResolver,
+	allow_private: bool,
+}
+
+impl Service<Name> for PublicResolver {
+	type Response = std::vec::IntoIter<SocketAddr>;
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, name: Name) -> Self::Future {
+		let allow_private = self.allow_private;
+		let resolving = self.inner.call(name);
+		Box::pin(async move {
+			let addrs: Vec<SocketAddr> = resolving
+				.await?
+				.filter(|addr| allow_private || is_public_address(addr.ip()))
+				.collect();
+			if addrs.is_empty() {
+				return Err(io::Error::new(
+					io::ErrorKind::PermissionDenied,
+					"Host has no public address",
+				));
+			}
+			Ok(addrs.into_iter())
+		})
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum DeliveryError {
+	#[error(transparent)]
+	Http(#[from] hyper::http::Error),
+
+	#[error(transparent)]
+	Hyper(#[from] hyper::Error),
+
+	#[error("Timed out")]
+	Timeout,
+
+	#[error("Webhook url points to a private address")]
+	PrivateAddress,
+
+	#[error("Receiver responded with {0}")]
+	Status(StatusCode),
+}
+
+/// A single event on its way to a webhook
+pub struct Delivery {
+	pub uuid: Uuid,
+	pub kind: EventKind,
+	pub file_uuid: Uuid,
+	pub payload: String,
+}
+
+impl Delivery {
+	/// Entry fields are shown as the owner of the webhook would see them in `list.json`
+	pub fn new(
+		webhook: &Webhook,
+		event: &Event,
+		owner: Option<&Account>,
+	) -> Result<Self, serde_json::Error> {
+		let uuid = Uuid::new_v4();
+		let payload = serde_json::to_string(&WebhookPayload {
+			delivery: uuid,
+			webhook: webhook.uuid,
+			event: event.model(owner),
+		})?;
+		Ok(Delivery {
+			uuid,
+			kind: event.kind,
+			file_uuid: event.uuid,
+			payload,
+		})
+	}
+}
+
+/// Posts the delivery, retrying with a backoff. Once all attempts fail, the delivery is added to
+/// the dead letters of the webhook. Stops early if the webhook gets removed in the meantime.
+pub async fn deliver(client: WebhookClient, db: Db, webhook: Webhook, delivery: Delivery) {
+	let settings = &db.config.settings;
+	let mut attempt = 1;
+	loop {
+		let err = match send(&client, &webhook, &delivery, settings).await {
+			Ok(()) => {
+				debug!(
+					"Delivered {:?} of {} to webhook {} on attempt {attempt}",
+					delivery.kind, delivery.file_uuid, webhook.uuid
+				);
+				return;
+			}
+			Err(err) => err,
+		};
+		debug!(
+			"Attempt {attempt} of delivery {} to webhook {} failed: {err}",
+			delivery.uuid, webhook.uuid
+		);
+
+		if attempt >= settings.webhook_max_attempts {
+			warn!(
+				"Giving up on delivery {} to webhook {} after {attempt} attempts: {err}",
+				delivery.uuid, webhook.uuid
+			);
+			let mut webhooks = db.webhooks_writer().await;
+			if webhooks.get(&webhook.uuid).is_some() {
+				webhooks.add_dead_letter(DeadLetter {
+					delivery: delivery.uuid,
+					webhook: webhook.uuid,
+					event: delivery.kind,
+					file_uuid: delivery.file_uuid,
+					date: SystemTime::now(),
+					attempts: attempt,
+					error: err.to_string(),
+					payload: delivery.payload,
+				});
+			}
+			return;
+		}
+
+		tokio::time::sleep(retry_delay(settings, attempt)).await;
+		if db.webhooks_reader().await.get(&webhook.uuid).is_none() {
+			debug!(
+				"Webhook {} was removed, dropping its delivery",
+				webhook.uuid
+			);
+			return;
+		}
+		attempt += 1;
+	}
+}
+
+async fn send(
+	client: &WebhookClient,
+	webhook: &Webhook,
+	delivery: &Delivery,
+	settings: &Settings,
+) -> Result<(), DeliveryError> {
+	// Addresses given by name are checked by the resolver
+	let uri: Uri = webhook.url.parse().map_err(hyper::http::Error::from)?;
+	if !settings.webhook_allow_private_addresses && uri.host().is_some_and(is_private_host) {
+		return Err(DeliveryError::PrivateAddress);
+	}
+
+	let request = Request::builder()
+		.method(Method::POST)
+		.uri(uri)
+		.header(CONTENT_TYPE, "application/json")
+		.header(USER_AGENT, "aqaSend")
+		.header(WEBHOOK_EVENT, delivery.kind.name())
+		.header(WEBHOOK_DELIVERY, delivery.uuid.to_string())
+		.header(
+			WEBHOOK_SIGNATURE,
+			sign(&webhook.secret, delivery.payload.as_bytes()),
+		)
+		.body(Body::from(delivery.payload.clone()))?;
+
+	let timeout = Duration::from_secs(settings.webhook_timeout_secs);
+	let response = tokio::time::timeout(timeout, client.request(request))
+		.await
+		.map_err(|_| DeliveryError::Timeout)??;
+	if !response.status().is_success() {
+		return Err(DeliveryError::Status(response.status()));
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum WebhooksError {
+	#[error("Webhook id is not a valid uuid")]
+	Uuid(#[from] uuid::Error),
+
+	#[error(transparent)]
+	AuthError(#[from] AuthError),
+
+	#[error(transparent)]
+	Boundary(#[from] multipart::GetBoundaryError),
+
+	#[error(transparent)]
+	Multipart(#[from] MultipartError),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+
+	#[error("You must be logged in to do that")]
+	NotLoggedIn,
+
+	#[error("Only admins can manage global webhooks and webhooks of other accounts")]
+	NotAuthorized,
+
+	#[error("Webhook not found")]
+	NotFound,
+
+	#[error("Webhook url must be an absolute http:// or https:// url of a public address")]
+	InvalidUrl,
+
+	#[error(
+		"Unknown event {0:?}. Possible events: created, downloaded, updated, deleted, expired"
+	)]
+	InvalidEvent(String),
+
+	#[error("Webhook secret must be valid utf-8 and can't be empty")]
+	InvalidSecret,
+}
+
+impl HttpHandlerError for WebhooksError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Uuid(_) => StatusCode::BAD_REQUEST,
+			Self::AuthError(err) => err.code(),
+			Self::Boundary(err) => err.code(),
+			Self::Multipart(_) => StatusCode::BAD_REQUEST,
+			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotLoggedIn => StatusCode::FORBIDDEN,
+			Self::NotAuthorized => StatusCode::UNAUTHORIZED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::InvalidUrl => StatusCode::BAD_REQUEST,
+			Self::InvalidEvent(_) => StatusCode::BAD_REQUEST,
+			Self::InvalidSecret => StatusCode::BAD_REQUEST,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		match self {
+			Self::AuthError(err) => err.user_presentable(),
+			Self::Boundary(err) => err.user_presentable(),
+			Self::Json(_) => false,
+			_ => true,
+		}
+	}
+
+	fn content_type() -> ErrorContentType {
+		ErrorContentType::Json
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookModel {
+	pub uuid: Uuid,
+	pub url: String,
+	pub events: Vec<EventKind>,
+	pub owner: Option<Uuid>,
+	pub global: bool,
+	/// Only sent back when the webhook is created
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub secret: Option<String>,
+}
+
+impl WebhookModel {
+	fn new(webhook: &Webhook) -> Self {
+		WebhookModel {
+			uuid: webhook.uuid,
+			url: webhook.url.clone(),
+			events: webhook.events.clone(),
+			owner: webhook.owner,
+			global: webhook.owner.is_none(),
+			secret: None,
+		}
+	}
+}
+
+/// Lists webhooks of the current user. Admins also see global webhooks.
+pub async fn list_webhooks(
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<WebhooksError>> {
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(WebhooksError::NotLoggedIn)?;
+	let is_admin = matches!(current_user.acc_type, AccountType::Admin);
+
+	let webhooks: Vec<WebhookModel> = db
+		.webhooks_reader()
+		.await
+		.iter()
+		.filter(|webhook| match webhook.owner {
+			Some(owner) => owner == current_user.uuid,
+			None => is_admin,
+		})
+		.map(WebhookModel::new)
+		.collect();
+
+	json_response(StatusCode::OK, &webhooks)
+}
+
+/// Registers a webhook from a `multipart/form-data` request with a `url`, optional comma
+/// separated `events` and an optional `secret` field. A secret is generated if none is given.
+/// Admins can register a webhook for events of all entries with `global=true`.
+pub async fn create_webhook(
+	req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<WebhooksError>> {
+	let (parts, body) = req.into_parts();
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(WebhooksError::NotLoggedIn)?;
+
+	let boundary = multipart::get_boundary_from_req(parts).map_err(WebhooksError::from)?;
+	let mut multipart = Multipart::new(body, boundary, MAX_REQUEST_BODY_SIZE);
+	let chunks = multipart.read_all_chunks().await.into_handler_error()?;
+
+	let field = |name: &str| {
+		chunks
+			.iter()
+			.find(|(header, _data)| header.name == name)
+			.map(|(_header, data)| std::str::from_utf8(data))
+	};
+
+	let url = field("url")
+		.and_then(Result::ok)
+		.map(str::trim)
+		.filter(|url| is_valid_url(url, &db.config.settings))
+		.ok_or(WebhooksError::InvalidUrl)?;
+
+	let events = match field("events") {
+		Some(events) => events
+			.map_err(|_| WebhooksError::InvalidEvent(String::new()))?
+			.split(',')
+			.map(str::trim)
+			.filter(|event| !event.is_empty())
+			.map(|event| {
+				event
+					.parse::<EventKind>()
+					.map_err(|_| WebhooksError::InvalidEvent(event.to_string()))
+			})
+			.collect::<Result<Vec<_>, _>>()?,
+		None => Vec::new(),
+	};
+
+	let secret = match field("secret") {
+		Some(secret) => {
+			let secret = secret.map_err(|_| WebhooksError::InvalidSecret)?.trim();
+			if secret.is_empty() {
+				return Err(WebhooksError::InvalidSecret.into());
+			}
+			secret.to_string()
+		}
+		None => {
+			let mut secret = [0_u8; 32];
+			OsRng.fill_bytes(&mut secret);
+			hex::encode(secret)
+		}
+	};
+
+	let global = field("global").is_some_and(|global| global.is_ok_and(|g| g.trim() == "true"));
+	if global && !matches!(current_user.acc_type, AccountType::Admin) {
+		return Err(WebhooksError::NotAuthorized.into());
+	}
+
+	let webhook = Webhook {
+		uuid: Uuid::new_v4(),
+		url: url.to_string(),
+		events,
+		secret,
+		owner: (!global).then_some(current_user.uuid),
+		created_by: current_user.uuid,
+	};
+	debug!("Registering webhook {} for {}", webhook.uuid, webhook.url);
+
+	let model = WebhookModel {
+		secret: Some(webhook.secret.clone()),
+		..WebhookModel::new(&webhook)
+	};
+	db.webhooks_writer().await.insert(webhook);
+
+	json_response(StatusCode::CREATED, &model)
+}
+
+pub async fn delete_webhook(
+	uuid: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<WebhooksError>> {
+	let webhook_uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(WebhooksError::NotLoggedIn)?;
+
+	let mut webhooks = db.webhooks_writer().await;
+	let webhook = webhooks.get(&webhook_uuid).ok_or(WebhooksError::NotFound)?;
+	if !webhook.can_manage(&current_user) {
+		return Err(WebhooksError::NotAuthorized.into());
+	}
+	debug!("Removing webhook {webhook_uuid}");
+	webhooks.remove(&webhook_uuid);
+	drop(webhooks);
+
+	Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.body(Body::empty())?)
+}
+
+/// Lists deliveries to the webhook that failed after all retries
+pub async fn list_dead_letters(
+	uuid: String,
+	_req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<WebhooksError>> {
+	let webhook_uuid = Uuid::parse_str(&uuid).into_handler_error()?;
+
+	let current_user = ctx
+		.user()
+		.into_handler_error()?
+		.ok_or(WebhooksError::NotLoggedIn)?;
+
+	let webhooks = db.webhooks_reader().await;
+	let webhook = webhooks.get(&webhook_uuid).ok_or(WebhooksError::NotFound)?;
+	if !webhook.can_manage(&current_user) {
+		return Err(WebhooksError::NotAuthorized.into());
+	}
+	let dead_letters: Vec<&DeadLetter> = webhooks.dead_letters_of(&webhook_uuid).collect();
+
+	json_response(StatusCode::OK, &dead_letters)
+}
+
+/// Absolute `http://` or `https://` url. Unless [Settings::webhook_allow_private_addresses] is
+/// set, the host can't be a private address. Hosts given by name are checked once they're
+/// resolved, by [PublicResolver].
+fn is_valid_url(url: &str, settings: &Settings) -> bool {
+	let Ok(uri) = url.parse::<Uri>() else {
+		return false;
+	};
+	let Some(host) = uri.host() else {
+		return false;
+	};
+	matches!(uri.scheme_str(), Some("http" | "https"))
+		&& (settings.webhook_allow_private_addresses || !is_private_host(host))
+}
+
+fn is_private_host(host: &str) -> bool {
+	if host.eq_ignore_ascii_case("localhost") {
+		return true;
+	}
+	host.trim_start_matches('[')
+		.trim_end_matches(']')
+		.parse::<IpAddr>()
+		.is_ok_and(|ip| !is_public_address(ip))
+}
+
+/// Addresses outside of loopback, private, link-local, shared and multicast ranges
+fn is_public_address(ip: IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => {
+			let [first, second, ..] = ip.octets();
+			let shared = first == 100 && (second & 0xC0) == 64;
+			!(ip.is_loopback()
+				|| ip.is_private()
+				|| ip.is_link_local()
+				|| ip.is_unspecified()
+				|| ip.is_broadcast()
+				|| ip.is_multicast()
+				|| shared)
+		}
+		IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+			Some(ip) => is_public_address(IpAddr::V4(ip)),
+			None => {
+				let unique_local = (ip.segments()[0] & 0xFE00) == 0xFC00;
+				let link_local = (ip.segments()[0] & 0xFFC0) == 0xFE80;
+				!(ip.is_loopback()
+					|| ip.is_unspecified()
+					|| ip.is_multicast()
+					|| unique_local || link_local)
+			}
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn retries_back_off_exponentially() {
+		let settings = Settings {
+			webhook_retry_base_secs: 10,
+			webhook_retry_max_secs: 60,
+			..Default::default()
+		};
+		let delays: Vec<u64> = (1..=5)
+			.map(|attempt| retry_delay(&settings, attempt).as_secs())
+			.collect();
+		assert_eq!(delays, [10, 20, 40, 60, 60]);
+		assert_eq!(retry_delay(&settings, u32::MAX), Duration::from_secs(60));
+	}
+
+	#[test]
+	fn signature_matches_known_value() {
+		// RFC 4231 test case 2
+		assert_eq!(
+			sign("Jefe", b"what do ya want for nothing?"),
+			"sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[test]
+	fn urls_must_be_absolute_http_or_https() {
+		let settings = Settings::default();
+		assert!(is_valid_url(
+			"http://ci.example.com/hook?token=1",
+			&settings
+		));
+		assert!(is_valid_url("https://ci.example.com/hook", &settings));
+		assert!(is_valid_url("https://93.184.216.34/hook", &settings));
+		assert!(!is_valid_url("ftp://ci.example.com/hook", &settings));
+		assert!(!is_valid_url("/hook", &settings));
+		assert!(!is_valid_url("not a url", &settings));
+	}
+
+	#[test]
+	fn private_addresses_are_rejected_unless_allowed() {
+		let settings = Settings::default();
+		for url in [
+			"http://127.0.0.1:8080/hook",
+			"http://localhost/hook",
+			"http://10.1.2.3/hook",
+			"http://172.16.0.1/hook",
+			"http://192.168.1.1/hook",
+			"http://169.254.169.254/latest/meta-data",
+			"http://100.64.0.1/hook",
+			"http://0.0.0.0/hook",
+			"http://[::1]/hook",
+			"http://[fd00::1]/hook",
+			"http://[fe80::1]/hook",
+			"http://[::ffff:127.0.0.1]/hook",
+		] {
+			assert!(!is_valid_url(url, &settings), "{url}");
+		}
+
+		let settings = Settings {
+			webhook_allow_private_addresses: true,
+			..Default::default()
+		};
+		assert!(is_valid_url("http://127.0.0.1:8080/hook", &settings));
+		assert!(is_valid_url("http://[::1]/hook", &settings));
+	}
+}
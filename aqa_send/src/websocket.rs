@@ -0,0 +1,307 @@
+//! `/api/ws` WebSocket ([RFC 6455](https://www.rfc-editor.org/rfc/rfc6455)) pushing entry
+//! [events](crate::events) to subscribers. Messages sent by clients are ignored, pings are
+//! answered by the protocol implementation.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use hyper::header::{HeaderValue, CONNECTION, HOST, ORIGIN, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use log::{debug, error};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+use crate::account::AuthError;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::db_stuff::Account;
+use crate::error::{HandlerError, HttpHandlerError, IntoHandlerError};
+use crate::events::Event;
+use crate::settings::Settings;
+
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Clients aren't expected to send anything but control frames, anything bigger is rejected
+const MAX_CLIENT_MESSAGE: usize = 4 * 1024;
+
+/// Keeps the connection open through proxies that drop idle connections
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum WebSocketError {
+	#[error(transparent)]
+	Auth(#[from] AuthError),
+
+	#[error("Expected a WebSocket upgrade request")]
+	NotUpgrade,
+
+	#[error("Only version {WEBSOCKET_VERSION} of the WebSocket protocol is supported")]
+	UnsupportedVersion,
+
+	#[error("WebSocket connections from this origin are not allowed")]
+	OriginNotAllowed,
+}
+
+impl HttpHandlerError for WebSocketError {
+	fn code(&self) -> StatusCode {
+		match self {
+			Self::Auth(err) => err.code(),
+			Self::NotUpgrade => StatusCode::BAD_REQUEST,
+			Self::UnsupportedVersion => StatusCode::UPGRADE_REQUIRED,
+			Self::OriginNotAllowed => StatusCode::FORBIDDEN,
+		}
+	}
+
+	fn user_presentable(&self) -> bool {
+		true
+	}
+
+	fn headers(&self) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		if let Self::UnsupportedVersion = self {
+			headers.insert(
+				"Sec-WebSocket-Version",
+				HeaderValue::from_static(WEBSOCKET_VERSION),
+			);
+		}
+		headers
+	}
+}
+
+/// Upgrades the connection and pushes events of the entries the user would see in `list.json`.
+/// Works for anonymous users too, who get the events of public entries. The connection of a
+/// logged in user is closed once their session ends.
+pub async fn ws(
+	mut req: Request<Body>,
+	db: Db,
+	ctx: RequestContext,
+) -> Result<Response<Body>, HandlerError<WebSocketError>> {
+	let headers = req.headers();
+	// Checked before the session, which the browser sends no matter what site opened the socket
+	if !origin_allowed(headers, &db.config.settings) {
+		return Err(WebSocketError::OriginNotAllowed.into());
+	}
+
+	let session = ctx.session_or_anonymous().into_handler_error()?;
+	let session_token = session.as_ref().map(|session| session.token);
+	let viewer = session.map(|session| session.account);
+
+	if !header_contains_token(headers, CONNECTION, "upgrade")
+		|| !header_contains_token(headers, UPGRADE, "websocket")
+	{
+		return Err(WebSocketError::NotUpgrade.into());
+	}
+	if headers
+		.get("Sec-WebSocket-Version")
+		.is_none_or(|version| version != WEBSOCKET_VERSION)
+	{
+		return Err(WebSocketError::UnsupportedVersion.into());
+	}
+	let accept_key = headers
+		.get("Sec-WebSocket-Key")
+		.map(|key| derive_accept_key(key.as_bytes()))
+		.ok_or(WebSocketError::NotUpgrade)?;
+
+	// Subscribed before responding, so no event published after the handshake is missed
+	let events = db.subscribe();
+	let on_upgrade = hyper::upgrade::on(&mut req);
+	let request_id = ctx.request_id.clone();
+	tokio::spawn(async move {
+		match on_upgrade.await {
+			Ok(upgraded) => {
+				debug!("{request_id} WebSocket connected");
+				match serve(upgraded, events, db, viewer, session_token).await {
+					Ok(()) => debug!("{request_id} WebSocket closed"),
+					Err(err) => debug!("{request_id} WebSocket failed: {err}"),
+				}
+			}
+			Err(err) => error!("{request_id} WebSocket upgrade failed: {err}"),
+		}
+	});
+
+	Ok(Response::builder()
+		.status(StatusCode::SWITCHING_PROTOCOLS)
+		.header(CONNECTION, "Upgrade")
+		.header(UPGRADE, "websocket")
+		.header("Sec-WebSocket-Accept", accept_key)
+		.body(Body::empty())?)
+}
+
+fn header_contains_token(headers: &HeaderMap, name: impl AsRef<str>, token: &str) -> bool {
+	headers
+		.get_all(name.as_ref())
+		.iter()
+		.filter_map(|hv| hv.to_str().ok())
+		.flat_map(|hv| hv.split(','))
+		.any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Pages served by aqaSend itself, where the `Origin` matches the `Host`, and the
+/// [allowed origins](Settings::allowed_origins). Clients other than browsers don't send an
+/// `Origin` and are allowed too.
+fn origin_allowed(headers: &HeaderMap, settings: &Settings) -> bool {
+	let Some(origin) = headers.get(ORIGIN) else {
+		return true;
+	};
+	let Ok(origin) = origin.to_str() else {
+		return false;
+	};
+
+	if settings
+		.allowed_origins
+		.iter()
+		.any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+	{
+		return true;
+	}
+
+	let origin_host = origin
+		.strip_prefix("https://")
+		.or_else(|| origin.strip_prefix("http://"));
+	let host = headers.get(HOST).and_then(|host| host.to_str().ok());
+	match (origin_host, host) {
+		(Some(origin_host), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+		_ => false,
+	}
+}
+
+#[derive(Debug, Error)]
+enum ServeError {
+	#[error(transparent)]
+	WebSocket(#[from] tungstenite::Error),
+
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+}
+
+async fn serve(
+	upgraded: Upgraded,
+	mut events: broadcast::Receiver<Event>,
+	db: Db,
+	viewer: Option<Account>,
+	session_token: Option<Uuid>,
+) -> Result<(), ServeError> {
+	let config = WebSocketConfig {
+		max_message_size: Some(MAX_CLIENT_MESSAGE),
+		max_frame_size: Some(MAX_CLIENT_MESSAGE),
+		..Default::default()
+	};
+	let mut socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await;
+
+	let mut ping_interval = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+	let session_ended = || {
+		session_token
+			.is_some_and(|token| !db.authorized_users().is_active(&token, &db.config.settings))
+	};
+
+	loop {
+		tokio::select! {
+			event = events.recv() => match event {
+				Ok(event) => {
+					if session_ended() {
+						return close(&mut socket, CloseCode::Policy, "Session ended").await;
+					}
+					if !event.is_visible_to(viewer.as_ref(), &*db.groups_reader().await) {
+						continue;
+					}
+					let message = serde_json::to_string(&event.model(viewer.as_ref()))?;
+					socket.send(Message::Text(message)).await?;
+				}
+				// The client would silently miss events, it has to reconnect and list entries again
+				Err(broadcast::error::RecvError::Lagged(skipped)) => {
+					debug!("WebSocket subscriber lagged behind by {skipped} events");
+					return close(&mut socket, CloseCode::Policy, "Too slow").await;
+				}
+				Err(broadcast::error::RecvError::Closed) => {
+					return close(&mut socket, CloseCode::Normal, "").await;
+				}
+			},
+			// Pings and close frames are answered while reading
+			message = socket.next() => match message {
+				Some(Ok(_)) => (),
+				Some(Err(err)) => return Err(err.into()),
+				None => return Ok(()),
+			},
+			_ = ping_interval.tick() => {
+				if session_ended() {
+					return close(&mut socket, CloseCode::Policy, "Session ended").await;
+				}
+				socket.send(Message::Ping(Vec::new())).await?;
+			}
+		}
+	}
+}
+
+async fn close(
+	socket: &mut WebSocketStream<Upgraded>,
+	code: CloseCode,
+	reason: &str,
+) -> Result<(), ServeError> {
+	let frame = CloseFrame {
+		code,
+		reason: reason.into(),
+	};
+	Ok(socket.close(Some(frame)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn headers(origin: Option<&'static str>, host: &'static str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(HOST, HeaderValue::from_static(host));
+		if let Some(origin) = origin {
+			headers.insert(ORIGIN, HeaderValue::from_static(origin));
+		}
+		headers
+	}
+
+	#[test]
+	fn allowed_origins() {
+		let settings = Settings {
+			allowed_origins: vec![String::from("https://app.example.com/")],
+			..Default::default()
+		};
+
+		// Same origin, allowed origin and clients that aren't browsers
+		assert!(origin_allowed(
+			&headers(Some("https://send.example.com"), "send.example.com"),
+			&settings
+		));
+		assert!(origin_allowed(
+			&headers(Some("http://localhost:8000"), "localhost:8000"),
+			&settings
+		));
+		assert!(origin_allowed(
+			&headers(Some("https://app.example.com"), "send.example.com"),
+			&settings
+		));
+		assert!(origin_allowed(
+			&headers(None, "send.example.com"),
+			&settings
+		));
+
+		// Any other site
+		assert!(!origin_allowed(
+			&headers(Some("https://evil.example.com"), "send.example.com"),
+			&settings
+		));
+		assert!(!origin_allowed(
+			&headers(Some("https://send.example.com:8443"), "send.example.com"),
+			&settings
+		));
+		assert!(!origin_allowed(
+			&headers(Some("null"), "send.example.com"),
+			&settings
+		));
+	}
+}
@@ -1,7 +1,13 @@
 use anyhow::Result;
 use aqa_send::account::CreateAccountResponse;
+use aqa_send::audit::{DownloadEvent, DownloadStatus};
+use aqa_send::download::UnlockResponse;
 use hyper::body::to_bytes;
-use hyper::header::SET_COOKIE;
+use hyper::header::{
+	ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+	IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE, RETRY_AFTER,
+	SET_COOKIE,
+};
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use log::debug;
@@ -13,11 +19,18 @@ use uuid::Uuid;
 use zeroize::Zeroizing;
 
 use aqa_send::cli_commands::create_account::create_account;
-use aqa_send::db_stuff::AccountType;
+use aqa_send::db_stuff::{AccountType, Group};
 use aqa_send::files::DB_DIR;
 use aqa_send::headers::Lifetime;
+use aqa_send::settings::{BandwidthLimits, Settings};
+use aqa_send::signed_links::SignedLinkModel;
 use aqa_send::upload::UploadResponse;
-use aqa_send::{cookie, db, headers, list, tasks, AqaService, AqaServiceError, AuthorizedUsers};
+use aqa_send::{
+	cookie, db, headers, list, notifications, share, tasks, AqaService, AqaServiceError,
+};
+
+/// Password of the accounts created by [TestServer::create_user]
+const TEST_PASSWORD: &str = "makota";
 
 struct TestServer {
 	#[allow(dead_code)]
@@ -29,7 +42,16 @@ struct TestServer {
 
 impl TestServer {
 	fn new() -> Result<Self> {
+		Self::with_settings(&Settings::default())
+	}
+
+	fn with_settings(settings: &Settings) -> Result<Self> {
 		let db_dir = tempfile::tempdir()?;
+		std::fs::create_dir(db_dir.path().join(DB_DIR))?;
+		std::fs::write(
+			db_dir.path().join(DB_DIR).join("settings.json"),
+			serde_json::to_vec(settings)?,
+		)?;
 
 		aqa_logger::init();
 		// if let Err(err) = tracing_subscriber::FmtSubscriber::builder().try_init() {
@@ -39,7 +61,7 @@ impl TestServer {
 		// init_app_directory_structure(db_dir.path())?;
 
 		let db_handle = db::init(db_dir.path())?;
-		let aqa_service = AqaService::new(db_handle.clone(), AuthorizedUsers::default());
+		let aqa_service = AqaService::new(db_handle.clone());
 
 		Ok(Self {
 			db_dir,
@@ -57,12 +79,91 @@ impl TestServer {
 		));
 	}
 
+	#[allow(dead_code)]
+	fn start_webhook_task(&self) {
+		tokio::spawn(tasks::webhooks::webhook_task(self.db_handle.clone()));
+	}
+
 	async fn process_request(
 		&mut self,
 		request: Request<Body>,
 	) -> Result<Response<Body>, AqaServiceError> {
 		self.aqa_service.call(request).await
 	}
+
+	/// Logs in and returns the `Cookie` header value carrying the session
+	async fn login(&mut self, username: &str, password: &str) -> Result<String> {
+		let request = login_request(username, password, &[])?;
+
+		let response = self.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::CREATED);
+
+		let cookie = response
+			.headers()
+			.get(SET_COOKIE)
+			.expect("Set-Cookie missing")
+			.to_str()?;
+		let (_, cookie) = cookie::parse_set_cookie(cookie).unwrap();
+		Ok(format!("{}={}", cookie.name, cookie.value))
+	}
+
+	/// Creates an account with the password [TEST_PASSWORD] and logs it in. Returns the uuid of
+	/// the account and the `Cookie` header value carrying its session.
+	async fn create_user(
+		&mut self,
+		username: &str,
+		account_type: AccountType,
+	) -> Result<(Uuid, String)> {
+		let account_uuid = create_account(
+			self.db_handle.clone(),
+			username.to_string(),
+			account_type,
+			Zeroizing::new(TEST_PASSWORD.to_string()),
+		)
+		.await?;
+		let cookie = self.login(username, TEST_PASSWORD).await?;
+		Ok((account_uuid, cookie))
+	}
+
+	/// Entries listed by `list.json` at the given uri
+	async fn list(
+		&mut self,
+		uri: &str,
+		headers: &[(&str, &str)],
+	) -> Result<Vec<list::FileModel<'static>>> {
+		let mut request = Request::builder().uri(uri).method(Method::GET);
+		for (name, value) in headers {
+			request = request.header(*name, *value);
+		}
+		let mut response = self.process_request(request.body(Body::empty())?).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		Ok(serde_json::from_slice(&response_bytes)?)
+	}
+
+	/// Uploads a file and returns the uuid of its entry
+	async fn upload(&mut self, file_contents: &str, headers: &[(&str, &str)]) -> Result<Uuid> {
+		self.upload_named("sample_file", "text/plain", file_contents, headers)
+			.await
+	}
+
+	/// Same as [Self::upload], for a file with the given name and `Content-Type`
+	async fn upload_named(
+		&mut self,
+		filename: &str,
+		content_type: &str,
+		file_contents: &str,
+		headers: &[(&str, &str)],
+	) -> Result<Uuid> {
+		let request = named_upload_request(filename, content_type, file_contents, headers)?;
+		let mut response = self.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		let UploadResponse(uploaded_files) = serde_json::from_slice(&response_bytes)?;
+		Ok(uploaded_files[0].uuid)
+	}
 }
 
 fn random_string(len: usize) -> String {
@@ -70,6 +171,103 @@ fn random_string(len: usize) -> String {
 	rand::distributions::Alphanumeric.sample_string(&mut thread_rng(), len)
 }
 
+fn login_request(
+	username: &str,
+	password: &str,
+	headers: &[(&str, &str)],
+) -> Result<Request<Body>> {
+	let boundary = random_string(50);
+	let mut request = Request::builder()
+		.uri("/api/login")
+		.method(Method::POST)
+		.header(
+			"Content-Type",
+			format!("multipart/form-data; boundary={boundary}"),
+		);
+	for (name, value) in headers {
+		request = request.header(*name, *value);
+	}
+	Ok(request.body(Body::from(format!(
+		"--{boundary}\r\n\
+Content-Disposition: form-data; name=\"username\"\r\n\r\n\
+{username}\r\n\
+--{boundary}--\r\n\
+Content-Disposition: form-data; name=\"password\"\r\n\r\n\
+{password}\r\n\
+--{boundary}--\r\n"
+	)))?)
+}
+
+/// Upload of a single file named `sample_file`, along with the given headers
+fn upload_request(file_contents: &str, headers: &[(&str, &str)]) -> Result<Request<Body>> {
+	named_upload_request("sample_file", "text/plain", file_contents, headers)
+}
+
+/// Same as [upload_request], for a file with the given name and `Content-Type`
+fn named_upload_request(
+	filename: &str,
+	content_type: &str,
+	file_contents: &str,
+	headers: &[(&str, &str)],
+) -> Result<Request<Body>> {
+	let boundary = random_string(50);
+	let mut request = Request::builder()
+		.uri("/api/upload")
+		.method(Method::POST)
+		.header(
+			"Content-Type",
+			format!("multipart/form-data; boundary={boundary}"),
+		);
+	for (name, value) in headers {
+		request = request.header(*name, *value);
+	}
+	Ok(request.body(Body::from(format!(
+		"--{boundary}\r\n\
+Content-Disposition: form-data; name=\"sample_file\"; filename=\"{filename}\"\r\n\
+Content-Type: {content_type}\r\n\r\n\
+{file_contents}\r\n\
+--{boundary}--\r\n"
+	)))?)
+}
+
+fn download_request(uuid: &Uuid, headers: &[(&str, &str)]) -> Result<Request<Body>> {
+	let mut request = Request::builder()
+		.uri(format!("/api/download/{uuid}"))
+		.method(Method::GET);
+	for (name, value) in headers {
+		request = request.header(*name, *value);
+	}
+	Ok(request.body(Body::empty())?)
+}
+
+fn entry_request(uuid: &Uuid, headers: &[(&str, &str)]) -> Result<Request<Body>> {
+	let mut request = Request::builder()
+		.uri(format!("/api/entry/{uuid}"))
+		.method(Method::GET);
+	for (name, value) in headers {
+		request = request.header(*name, *value);
+	}
+	Ok(request.body(Body::empty())?)
+}
+
+/// `POST` exchanging the password of the entry for an unlock token
+fn unlock_request(uuid: &Uuid, password: &str) -> Result<Request<Body>> {
+	let boundary = random_string(50);
+	Ok(Request::builder()
+		.uri(format!("/api/download/{uuid}"))
+		.method(Method::POST)
+		.header(
+			"Content-Type",
+			format!("multipart/form-data; boundary={boundary}"),
+		)
+		.body(Body::from(format!(
+			"--{boundary}\r\n\
+Content-Disposition: form-data; name=\"password\"\r\n\r\n\
+{password}\r\n\
+--{boundary}--\r\n"
+		)))?)
+}
+
 #[tokio::test]
 async fn test_hello() -> Result<()> {
 	let mut test_server = TestServer::new()?;
@@ -690,20 +888,20 @@ Content-Type: text/plain\r\n\r\n\
 
 	assert!(uploaded_file_path.exists());
 
-	{
-		test_server
-			.db_handle
-			.writer()
-			.await
-			.get_mut(&uploaded_files[0].uuid)
-			.unwrap()
-			.lifetime = Lifetime::Duration(Duration::from_millis(400));
-	}
+	// Lifetime is counted from the upload date, not from the moment it was changed
+	let start_time = {
+		let mut writer = test_server.db_handle.writer().await;
+		let file_entry = writer.get_mut(&uploaded_files[0].uuid).unwrap();
+		file_entry.lifetime = Lifetime::Duration(Duration::from_millis(400));
+		file_entry.upload_date
+	};
 
-	let start_time = std::time::SystemTime::now();
+	while start_time.elapsed()? < Duration::from_millis(380) {
+		assert!(uploaded_file_path.exists());
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
 	while start_time.elapsed()? < Duration::from_millis(400) {
 		tokio::time::sleep(Duration::from_millis(10)).await;
-		assert!(uploaded_file_path.exists());
 	}
 
 	tokio::time::sleep(Duration::from_millis(20)).await;
@@ -856,6 +1054,258 @@ Content-Disposition: form-data; name=\"password\"\r\n\r\n\
 	Ok(())
 }
 
+#[tokio::test]
+async fn logout_and_sessions() -> Result<()> {
+	use aqa_send::sessions::SessionModel;
+
+	let mut test_server = TestServer::new()?;
+
+	for name in ["Ala", "Ola"] {
+		create_account(
+			test_server.db_handle.clone(),
+			String::from(name),
+			AccountType::User,
+			Zeroizing::new(TEST_PASSWORD.to_string()),
+		)
+		.await?;
+	}
+
+	let mut cookies = Vec::new();
+	for (username, user_agent) in [
+		("Ala", "laptop"),
+		("Ala", "phone"),
+		("Ala", "tablet"),
+		("Ala", "tv"),
+		("Ola", "laptop"),
+	] {
+		let request = login_request(username, TEST_PASSWORD, &[("User-Agent", user_agent)])?;
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::CREATED);
+		let cookie = response.headers()[SET_COOKIE].to_str()?;
+		let (_, cookie) = cookie::parse_set_cookie(cookie).unwrap();
+		cookies.push(format!("session={}", cookie.value));
+	}
+	let cookies: Vec<&str> = cookies.iter().map(String::as_str).collect();
+	let [laptop, phone, tablet, tv, ola] = <[&str; 5]>::try_from(cookies).unwrap();
+
+	let request = |method: Method, uri: &str, cookie: Option<&str>| {
+		let request = Request::builder().uri(uri).method(method);
+		let request = match cookie {
+			Some(cookie) => request.header("Cookie", cookie),
+			None => request,
+		};
+		request.body(Body::empty())
+	};
+	let list_sessions = |cookie: &str| request(Method::GET, "/api/sessions", Some(cookie));
+
+	debug!("Sessions of other accounts aren't listed");
+	let mut response = test_server.process_request(list_sessions(laptop)?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let sessions: Vec<SessionModel> =
+		serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+	assert_eq!(sessions.len(), 4);
+	let current: Vec<_> = sessions.iter().filter(|session| session.current).collect();
+	assert_eq!(current.len(), 1);
+	assert_eq!(current[0].user_agent.as_deref(), Some("laptop"));
+	humantime::parse_rfc3339(&current[0].created)?;
+	humantime::parse_rfc3339(&current[0].last_seen)?;
+	let phone_session = sessions
+		.iter()
+		.find(|session| session.user_agent.as_deref() == Some("phone"))
+		.unwrap();
+
+	debug!("Revoking another session");
+	let uri = format!("/api/sessions/{}", phone_session.id);
+	let response = test_server
+		.process_request(request(Method::DELETE, &uri, Some(ola))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	let response = test_server
+		.process_request(request(Method::DELETE, &uri, Some(laptop))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	assert!(!response.headers().contains_key(SET_COOKIE));
+	let response = test_server
+		.process_request(request(Method::GET, "/api/whoami", Some(phone))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Logging out");
+	let response = test_server
+		.process_request(request(Method::POST, "/api/logout", None)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = test_server
+		.process_request(request(Method::POST, "/api/logout", Some(laptop))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	let cookie = response.headers()[SET_COOKIE].to_str()?;
+	assert!(cookie.starts_with("session=; Max-Age=0;"), "{cookie}");
+	let response = test_server
+		.process_request(request(Method::GET, "/api/whoami", Some(laptop))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Logging out everywhere");
+	let mut response = test_server.process_request(list_sessions(tablet)?).await?;
+	let sessions: Vec<SessionModel> =
+		serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+	assert_eq!(sessions.len(), 2);
+	let response = test_server
+		.process_request(request(Method::DELETE, "/api/sessions", Some(tablet))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	assert!(response.headers().contains_key(SET_COOKIE));
+	for cookie in [tablet, tv] {
+		let response = test_server
+			.process_request(request(Method::GET, "/api/whoami", Some(cookie))?)
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	}
+	let response = test_server
+		.process_request(request(Method::GET, "/api/whoami", Some(ola))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn session_timeouts() -> Result<()> {
+	let mut test_server = TestServer::with_settings(&Settings {
+		session_idle_timeout_secs: 2,
+		session_absolute_timeout_secs: 3,
+		session_cookie_renewal_secs: 1,
+		..Default::default()
+	})?;
+	create_account(
+		test_server.db_handle.clone(),
+		String::from("Ala"),
+		AccountType::User,
+		Zeroizing::new(TEST_PASSWORD.to_string()),
+	)
+	.await?;
+
+	let whoami = |cookie: &str| {
+		Request::builder()
+			.uri("/api/whoami")
+			.method(Method::GET)
+			.header("Cookie", cookie)
+			.body(Body::empty())
+	};
+
+	let mut cookies = Vec::new();
+	for _ in 0..2 {
+		let request = login_request("Ala", TEST_PASSWORD, &[])?;
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::CREATED);
+		let cookie = response.headers()[SET_COOKIE].to_str()?;
+		let (_, cookie) = cookie::parse_set_cookie(cookie).unwrap();
+		assert_eq!(cookie.max_age, Some(2));
+		cookies.push(format!("session={}", cookie.value));
+	}
+	let [idle, used] = <[String; 2]>::try_from(cookies).unwrap();
+
+	debug!("Using the session right away doesn't renew the cookie");
+	let response = test_server.process_request(whoami(&used)?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(!response.headers().contains_key(SET_COOKIE));
+
+	debug!("Using the session later renews the cookie up to the absolute timeout");
+	tokio::time::sleep(Duration::from_millis(1100)).await;
+	let response = test_server.process_request(whoami(&used)?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let cookie = response.headers()[SET_COOKIE].to_str()?;
+	let (_, cookie) = cookie::parse_set_cookie(cookie).unwrap();
+	assert_eq!(cookie.value, used.trim_start_matches("session="));
+	assert_eq!(cookie.max_age, Some(1));
+
+	debug!("A session expires after being idle");
+	tokio::time::sleep(Duration::from_millis(1100)).await;
+	let response = test_server.process_request(whoami(&used)?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(!response.headers().contains_key(SET_COOKIE));
+	assert_eq!(test_server.db_handle.authorized_users().len(), 2);
+	test_server.db_handle.remove_expired_sessions();
+	assert_eq!(test_server.db_handle.authorized_users().len(), 1);
+	let response = test_server.process_request(whoami(&idle)?).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("A session expires after the absolute timeout, even when used");
+	tokio::time::sleep(Duration::from_millis(1000)).await;
+	let response = test_server.process_request(whoami(&used)?).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let cookie = response.headers()[SET_COOKIE].to_str()?;
+	assert!(cookie.starts_with("session=; Max-Age=0;"), "{cookie}");
+	assert!(test_server.db_handle.authorized_users().is_empty());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn sessions_survive_restart() -> Result<()> {
+	let test_server = TestServer::new()?;
+	let ala_uuid = create_account(
+		test_server.db_handle.clone(),
+		String::from("Ala"),
+		AccountType::User,
+		Zeroizing::new(TEST_PASSWORD.to_string()),
+	)
+	.await?;
+	let session = test_server.db_handle.authorized_users().create_session(
+		ala_uuid,
+		None,
+		Some(String::from("laptop")),
+		&test_server.db_handle.config.settings,
+	);
+
+	test_server.db_handle.save().await?;
+	let sessions_path = test_server.db_dir.path().join(DB_DIR).join("sessions");
+	let sessions_file = std::fs::read_to_string(&sessions_path)?;
+	assert!(!sessions_file.contains(&session.to_string()));
+
+	debug!("Plaintext tokens saved by older versions are hashed on start");
+	let mut sessions: serde_json::Map<String, serde_json::Value> =
+		serde_json::from_str(&sessions_file)?;
+	let session_info = sessions
+		.remove(&aqa_send::sessions::hash_token(&session))
+		.unwrap();
+	sessions.insert(session.to_string(), session_info);
+	std::fs::write(&sessions_path, serde_json::to_vec(&sessions)?)?;
+
+	let db_handle = db::init(test_server.db_dir.path())?;
+	assert!(!std::fs::read_to_string(&sessions_path)?.contains(&session.to_string()));
+	let mut aqa_service = AqaService::new(db_handle);
+
+	let request = Request::builder()
+		.uri("/api/sessions")
+		.method(Method::GET)
+		.header("Cookie", format!("session={session}"))
+		.body(Body::empty())?;
+	let mut response = aqa_service.call(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let sessions: Vec<aqa_send::sessions::SessionModel> =
+		serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+	assert_eq!(sessions.len(), 1);
+	assert!(sessions[0].current);
+	assert_eq!(sessions[0].user_agent.as_deref(), Some("laptop"));
+	humantime::parse_rfc3339(&sessions[0].expires)?;
+
+	debug!("Revoked sessions are saved right away");
+	let request = Request::builder()
+		.uri("/api/sessions")
+		.method(Method::DELETE)
+		.header("Cookie", format!("session={session}"))
+		.body(Body::empty())?;
+	let response = aqa_service.call(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+	let db_handle = db::init(test_server.db_dir.path())?;
+	assert!(db_handle.authorized_users().is_empty());
+
+	Ok(())
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn registration_code_works() -> Result<()> {
 	let mut test_server = TestServer::new()?;
@@ -1738,3 +2188,2619 @@ Content-Type: text/plain\r\n\r\n\
 
 	Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_can_delete_someones_private_file() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let (_, admin_cookie) = test_server.create_user("Ala", AccountType::Admin).await?;
+	let (_, uploader_cookie) = test_server.create_user("Ola", AccountType::User).await?;
+	let (_, other_cookie) = test_server.create_user("Ela", AccountType::User).await?;
+
+	let uuid = test_server
+		.upload(
+			&random_string(143),
+			&[
+				("Cookie", &uploader_cookie),
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				(headers::VISIBILITY, "private"),
+			],
+		)
+		.await?;
+
+	let delete = |cookie: &str| {
+		Request::builder()
+			.uri(format!("/api/delete/{uuid}"))
+			.method(Method::DELETE)
+			.header("Cookie", cookie)
+			.body(Body::empty())
+	};
+
+	debug!("Other users can't delete the private entry");
+	let response = test_server.process_request(delete(&other_cookie)?).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	assert!(test_server.db_handle.get(&uuid).await.is_some());
+
+	debug!("Admins can delete it");
+	let response = test_server.process_request(delete(&admin_cookie)?).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	assert!(test_server.db_handle.get(&uuid).await.is_none());
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_downloads_respect_download_count() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(143);
+	let uuid = test_server
+		.upload(&file_contents, &[(headers::DOWNLOAD_COUNT, "2")])
+		.await?;
+
+	debug!("Download whose body isn't read yet holds its slot");
+	let mut first_response = test_server
+		.process_request(download_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(first_response.status(), StatusCode::OK);
+
+	let tasks = (0..16)
+		.map(|_| {
+			let mut aqa_service = AqaService::new(test_server.db_handle.clone());
+			tokio::spawn(async move {
+				let request = download_request(&uuid, &[]).unwrap();
+				let mut response = aqa_service.call(request).await.unwrap();
+				let status = response.status();
+				let body = to_bytes(response.body_mut()).await.unwrap();
+				(status, body)
+			})
+		})
+		.collect::<Vec<_>>();
+
+	let mut successful_downloads = 0;
+	for task in tasks {
+		let (status, body) = task.await?;
+		match status {
+			StatusCode::OK => {
+				successful_downloads += 1;
+				assert_eq!(file_contents.as_bytes(), body.as_ref());
+			}
+			StatusCode::NOT_FOUND => (),
+			status => panic!("Unexpected status {status}"),
+		}
+	}
+	assert_eq!(successful_downloads, 1);
+
+	let file_entry = test_server.db_handle.get(&uuid).await.unwrap();
+	assert_eq!(file_entry.download_count, 1);
+
+	debug!("Finishing the first download uses up its slot");
+	let response_bytes = to_bytes(first_response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+
+	let response = test_server
+		.process_request(download_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_download_does_not_count() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(143);
+	let uuid = test_server
+		.upload(&file_contents, &[(headers::DOWNLOAD_COUNT, "1")])
+		.await?;
+
+	debug!("Starting a download and dropping it before the body is read");
+	let response = test_server
+		.process_request(download_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	debug!("Download in progress holds the only slot");
+	let second_response = test_server
+		.process_request(download_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(second_response.status(), StatusCode::NOT_FOUND);
+
+	drop(response);
+
+	let file_entry = test_server.db_handle.get(&uuid).await.unwrap();
+	assert_eq!(file_entry.download_count, 0);
+
+	debug!("Slot is released, so the file can be downloaded again");
+	let mut response = test_server
+		.process_request(download_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+
+	let file_entry = test_server.db_handle.get(&uuid).await.unwrap();
+	assert_eq!(file_entry.download_count, 1);
+
+	let response = test_server
+		.process_request(download_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plaintext_file_passwords_get_hashed_on_load() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(143);
+
+	const PASSWORD: &str = "alamakota";
+
+	let uuid = test_server
+		.upload(
+			&file_contents,
+			&[
+				(headers::DOWNLOAD_COUNT, "1"),
+				(headers::PASSWORD, urlencoding::encode(PASSWORD).as_ref()),
+			],
+		)
+		.await?;
+
+	let index_path = test_server.db_dir.path().join(DB_DIR).join("index");
+
+	debug!("Checking that the password isn't stored in plaintext");
+	test_server.db_handle.save().await?;
+	let index = fs::read_to_string(&index_path).await?;
+	assert!(!index.contains(PASSWORD));
+
+	debug!("Simulating an index saved by an older version");
+	test_server
+		.db_handle
+		.writer()
+		.await
+		.get_mut(&uuid)
+		.unwrap()
+		.password = Some(headers::PasswordHash(PASSWORD.to_string()));
+	test_server.db_handle.save().await?;
+	let index = fs::read_to_string(&index_path).await?;
+	assert!(index.contains(PASSWORD));
+
+	let db_handle = db::init(test_server.db_dir.path())?;
+	let index = fs::read_to_string(&index_path).await?;
+	assert!(!index.contains(PASSWORD));
+
+	let password_hash = db_handle.get(&uuid).await.unwrap().password.unwrap();
+	assert!(!password_hash.is_plaintext());
+	assert!(password_hash.verify(PASSWORD));
+	assert!(!password_hash.verify("alamapsa"));
+
+	let mut aqa_service = AqaService::new(db_handle);
+
+	let request = Request::builder()
+		.uri(format!(
+			"/api/download/{uuid}?password={}",
+			urlencoding::encode("alamapsa")
+		))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = aqa_service.call(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let request = Request::builder()
+		.uri(format!(
+			"/api/download/{uuid}?password={}",
+			urlencoding::encode(PASSWORD)
+		))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let mut response = aqa_service.call(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn password_can_be_sent_in_header_or_form() -> Result<()> {
+	let mut test_server = TestServer::with_settings(&Settings {
+		allow_password_in_query: false,
+		..Default::default()
+	})?;
+
+	let file_contents = random_string(143);
+
+	const PASSWORD: &str = "zażółć gęsią jaźń";
+
+	let uuid = test_server
+		.upload(
+			&file_contents,
+			&[
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				(headers::PASSWORD, urlencoding::encode(PASSWORD).as_ref()),
+			],
+		)
+		.await?;
+
+	debug!("Query string password is disabled");
+	let request = Request::builder()
+		.uri(format!(
+			"/api/download/{uuid}?password={}",
+			urlencoding::encode(PASSWORD)
+		))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Wrong password in header");
+	let request = download_request(&uuid, &[(headers::PASSWORD, "alamakota")])?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Correct password in header");
+	let request = download_request(
+		&uuid,
+		&[(headers::PASSWORD, urlencoding::encode(PASSWORD).as_ref())],
+	)?;
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+
+	debug!("Unlocking with a wrong password");
+	let response = test_server
+		.process_request(unlock_request(&uuid, "alamakota")?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Unlocking with the correct password");
+	let mut response = test_server
+		.process_request(unlock_request(&uuid, PASSWORD)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let UnlockResponse { unlock_token, .. } = serde_json::from_slice(&response_bytes)?;
+
+	debug!("Unlock token is only valid for the entry it was issued for");
+	let request = Request::builder()
+		.uri(format!(
+			"/api/download/{}?unlock_token={unlock_token}",
+			Uuid::new_v4()
+		))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	let request = Request::builder()
+		.uri(format!(
+			"/api/download/{uuid}?unlock_token={}",
+			Uuid::new_v4()
+		))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let request = Request::builder()
+		.uri(format!("/api/download/{uuid}?unlock_token={unlock_token}"))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn password_guessing_gets_throttled() -> Result<()> {
+	let mut test_server = TestServer::with_settings(&Settings {
+		password_free_attempts: 2,
+		password_backoff_base_secs: 60,
+		password_lockout_after: Some(2),
+		..Default::default()
+	})?;
+
+	let (_, cookie_header_value) = test_server.create_user("Ala", AccountType::User).await?;
+
+	const PASSWORD: &str = "alamakota";
+
+	let mut uuids = Vec::new();
+	for _ in 0..2 {
+		let uuid = test_server
+			.upload(
+				&random_string(143),
+				&[
+					("Cookie", &cookie_header_value),
+					(headers::DOWNLOAD_COUNT, "infinite"),
+					(headers::PASSWORD, PASSWORD),
+				],
+			)
+			.await?;
+		uuids.push(uuid);
+	}
+
+	let mut attacker = AqaService::new(test_server.db_handle.clone())
+		.with_client_addr(([10, 0, 0, 1], 4000).into());
+	let mut other_client = AqaService::new(test_server.db_handle.clone())
+		.with_client_addr(([10, 0, 0, 2], 4000).into());
+
+	let download_request =
+		|uuid: Uuid, password: &str| download_request(&uuid, &[(headers::PASSWORD, password)]);
+
+	debug!("Failures are counted per client across entries");
+	let response = attacker.call(download_request(uuids[0], "guess")?).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = attacker.call(download_request(uuids[1], "guess")?).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let response = attacker.call(download_request(uuids[1], PASSWORD)?).await?;
+	assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+	let retry_after: u64 = response
+		.headers()
+		.get(RETRY_AFTER)
+		.expect("Retry-After missing")
+		.to_str()?
+		.parse()?;
+	assert!(retry_after > 0 && retry_after <= 60);
+
+	let mut response = other_client
+		.call(download_request(uuids[1], PASSWORD)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	to_bytes(response.body_mut()).await?;
+
+	debug!("Failures are counted per entry across clients");
+	let response = other_client
+		.call(download_request(uuids[0], "guess")?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = other_client
+		.call(download_request(uuids[0], PASSWORD)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+	debug!("Uploader gets notified after the entry gets locked");
+	let request = Request::builder()
+		.uri("/api/notifications")
+		.method(Method::GET)
+		.header("Cookie", &cookie_header_value)
+		.body(Body::empty())?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let notifications: Vec<notifications::Notification> = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(notifications.len(), 1);
+	assert_eq!(notifications[0].file_uuid, Some(uuids[0]));
+
+	debug!("Failure counters survive a restart");
+	test_server.db_handle.save().await?;
+	let db_handle = db::init(test_server.db_dir.path())?;
+	assert!(db_handle
+		.begin_password_attempt(&uuids[0], None)
+		.await
+		.is_err());
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_password_guesses_get_throttled() -> Result<()> {
+	let mut test_server = TestServer::with_settings(&Settings {
+		password_free_attempts: 3,
+		password_backoff_base_secs: 60,
+		..Default::default()
+	})?;
+
+	let uuid = test_server
+		.upload(
+			&random_string(143),
+			&[
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				(headers::PASSWORD, "alamakota"),
+			],
+		)
+		.await?;
+
+	let tasks = (0..16)
+		.map(|_| {
+			let mut attacker = AqaService::new(test_server.db_handle.clone())
+				.with_client_addr(([10, 0, 0, 1], 4000).into());
+			tokio::spawn(async move {
+				let request = download_request(&uuid, &[(headers::PASSWORD, "guess")]).unwrap();
+				attacker.call(request).await.unwrap().status()
+			})
+		})
+		.collect::<Vec<_>>();
+
+	let mut checked_guesses = 0;
+	for task in tasks {
+		match task.await? {
+			StatusCode::UNAUTHORIZED => checked_guesses += 1,
+			StatusCode::TOO_MANY_REQUESTS => (),
+			status => panic!("Unexpected status {status}"),
+		}
+	}
+	assert_eq!(checked_guesses, 3);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unlisted_file_downloadable_but_listed_only_to_uploader() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let (_, cookie_header_value) = test_server.create_user("Ala", AccountType::User).await?;
+
+	let file_contents = random_string(143);
+	let mut uuids = Vec::new();
+	for cookie in [Some(cookie_header_value.as_str()), None] {
+		let mut headers = vec![
+			(headers::DOWNLOAD_COUNT, "infinite"),
+			(headers::VISIBILITY, "unlisted"),
+		];
+		headers.extend(cookie.map(|cookie| ("Cookie", cookie)));
+		uuids.push(test_server.upload(&file_contents, &headers).await?);
+	}
+
+	debug!("Unlisted entries aren't listed to anonymous users");
+	let list = test_server.list("/api/list.json", &[]).await?;
+	assert_eq!(list.len(), 0);
+
+	debug!("Uploader sees only their own unlisted entry");
+	let list = test_server
+		.list("/api/list.json", &[("Cookie", &cookie_header_value)])
+		.await?;
+	assert_eq!(list.len(), 1);
+	assert_eq!(list[0].uuid, uuids[0]);
+
+	debug!("Anyone with the link can download");
+	for uuid in uuids {
+		let mut response = test_server
+			.process_request(download_request(&uuid, &[])?)
+			.await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	}
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn private_file_shared_with_specific_accounts() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let mut account_uuids = Vec::new();
+	let mut cookies = Vec::new();
+	for username in ["Ala", "Ola", "Ela"] {
+		let (account_uuid, cookie) = test_server.create_user(username, AccountType::User).await?;
+		account_uuids.push(account_uuid);
+		cookies.push(cookie);
+	}
+	let [uploader_cookie, ola_cookie, ela_cookie] = cookies.as_slice() else {
+		unreachable!()
+	};
+
+	debug!("Sharing with an unknown account fails");
+	let file_contents = random_string(143);
+	let upload_headers = |share_with| {
+		[
+			(headers::DOWNLOAD_COUNT, "infinite"),
+			(headers::VISIBILITY, "private"),
+			(headers::SHARE_WITH, share_with),
+			("Cookie", uploader_cookie.as_str()),
+		]
+	};
+
+	let request = upload_request(&file_contents, &upload_headers("Ola, Nobody"))?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let uuid = test_server
+		.upload(&file_contents, &upload_headers("Ola"))
+		.await?;
+
+	debug!("Only the accounts the file is shared with can download it");
+	for (cookie, expected_status) in [
+		(ola_cookie, StatusCode::OK),
+		(ela_cookie, StatusCode::UNAUTHORIZED),
+	] {
+		let request = download_request(&uuid, &[("Cookie", cookie)])?;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), expected_status);
+		to_bytes(response.body_mut()).await?;
+	}
+
+	debug!("Shared file is listed under shared with me");
+	for (cookie, expected_len) in [(ola_cookie, 1), (ela_cookie, 0), (uploader_cookie, 0)] {
+		let list = test_server
+			.list("/api/list.json?shared=me", &[("Cookie", cookie)])
+			.await?;
+		assert_eq!(list.len(), expected_len);
+		if let Some(entry) = list.first() {
+			assert_eq!(entry.uuid, uuid);
+			assert!(entry.shared_with.is_none());
+		}
+	}
+
+	debug!("Only the uploader can change who the file is shared with");
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/share"))
+		.method(Method::PUT)
+		.header(headers::SHARE_WITH, "Ela")
+		.header("Cookie", ola_cookie)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/share"))
+		.method(Method::PUT)
+		.header(headers::SHARE_WITH, account_uuids[2].to_string())
+		.header("Cookie", uploader_cookie)
+		.body(Body::empty())?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let shared_with: share::SharedWith = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(shared_with.accounts.len(), 1);
+	assert_eq!(shared_with.accounts[0].uuid, account_uuids[2]);
+	assert_eq!(shared_with.accounts[0].username, "Ela");
+	assert!(shared_with.groups.is_empty());
+
+	for (cookie, expected_status) in [
+		(ola_cookie, StatusCode::UNAUTHORIZED),
+		(ela_cookie, StatusCode::OK),
+	] {
+		let request = download_request(&uuid, &[("Cookie", cookie)])?;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), expected_status);
+		to_bytes(response.body_mut()).await?;
+	}
+
+	debug!("Uploader sees who the file is shared with");
+	let list = test_server
+		.list("/api/list.json?uploader=me", &[("Cookie", uploader_cookie)])
+		.await?;
+	assert_eq!(list.len(), 1);
+	assert_eq!(list[0].shared_with, Some(vec![account_uuids[2]]));
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn private_file_shared_with_group() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let mut cookies = Vec::new();
+	for username in ["Ala", "Ola", "Ela"] {
+		let (_, cookie) = test_server.create_user(username, AccountType::User).await?;
+		cookies.push(cookie);
+	}
+	let [owner_cookie, member_cookie, outsider_cookie] = cookies.as_slice() else {
+		unreachable!()
+	};
+
+	debug!("Creating a group");
+	let boundary = random_string(50);
+	let request = Request::builder()
+		.uri("/api/groups")
+		.method(Method::POST)
+		.header(
+			"Content-Type",
+			format!("multipart/form-data; boundary={boundary}"),
+		)
+		.header("Cookie", owner_cookie)
+		.body(Body::from(format!(
+			"--{boundary}\r\n\
+Content-Disposition: form-data; name=\"name\"\r\n\r\n\
+backend\r\n\
+--{boundary}--\r\n"
+		)))?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::CREATED);
+
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let group: Group = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(group.name, "backend");
+	let group_uuid = group.uuid;
+
+	debug!("Only the owner can add members");
+	for (cookie, account, expected_status) in [
+		(member_cookie, "Ela", StatusCode::UNAUTHORIZED),
+		(owner_cookie, "Ola", StatusCode::OK),
+	] {
+		let request = Request::builder()
+			.uri(format!("/api/groups/{group_uuid}/members/{account}"))
+			.method(Method::PUT)
+			.header("Cookie", cookie)
+			.body(Body::empty())?;
+
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), expected_status);
+	}
+
+	for (cookie, expected_len) in [(member_cookie, 1), (outsider_cookie, 0)] {
+		let request = Request::builder()
+			.uri("/api/groups")
+			.method(Method::GET)
+			.header("Cookie", cookie)
+			.body(Body::empty())?;
+
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		let groups: Vec<Group> = serde_json::from_slice(&response_bytes)?;
+		assert_eq!(groups.len(), expected_len);
+	}
+
+	let file_contents = random_string(143);
+	let upload_headers = |cookie| {
+		[
+			(headers::DOWNLOAD_COUNT, "infinite"),
+			(headers::VISIBILITY, "private"),
+			(headers::SHARE_WITH_GROUPS, "backend"),
+			("Cookie", cookie),
+		]
+	};
+
+	debug!("Only members can share with the group");
+	let request = upload_request(&file_contents, &upload_headers(outsider_cookie))?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let uuid = test_server
+		.upload(&file_contents, &upload_headers(owner_cookie))
+		.await?;
+
+	debug!("Group members can download and list the file");
+	for (cookie, expected_status, expected_len) in [
+		(member_cookie, StatusCode::OK, 1),
+		(outsider_cookie, StatusCode::UNAUTHORIZED, 0),
+	] {
+		let request = download_request(&uuid, &[("Cookie", cookie)])?;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), expected_status);
+		to_bytes(response.body_mut()).await?;
+
+		let list = test_server
+			.list("/api/list.json?shared=me", &[("Cookie", cookie)])
+			.await?;
+		assert_eq!(list.len(), expected_len);
+	}
+
+	debug!("Only the uploader can delete the file");
+	for (cookie, expected_status) in [
+		(member_cookie, StatusCode::UNAUTHORIZED),
+		(outsider_cookie, StatusCode::UNAUTHORIZED),
+	] {
+		let request = Request::builder()
+			.uri(format!("/api/delete/{uuid}"))
+			.method(Method::DELETE)
+			.header("Cookie", cookie)
+			.body(Body::empty())?;
+
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), expected_status);
+	}
+
+	debug!("Groups are persisted");
+	test_server.db_handle.save().await?;
+	let reloaded_db = db::init(test_server.db_dir.path())?;
+	assert!(reloaded_db.groups_reader().await.contains_key(&group_uuid));
+
+	debug!("Removed members lose access");
+	let request = Request::builder()
+		.uri(format!("/api/groups/{group_uuid}/members/Ola"))
+		.method(Method::DELETE)
+		.header("Cookie", owner_cookie)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let request = download_request(&uuid, &[("Cookie", member_cookie)])?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Deleting the group removes it from entries");
+	let request = Request::builder()
+		.uri(format!("/api/groups/{group_uuid}"))
+		.method(Method::DELETE)
+		.header("Cookie", owner_cookie)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+	let file_entry = test_server.db_handle.get(&uuid).await.unwrap();
+	assert!(file_entry.shared_with_groups.is_empty());
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signed_links_grant_access_to_private_files() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let (_, cookie_header_value) = test_server.create_user("Ala", AccountType::User).await?;
+
+	let file_contents = random_string(143);
+	let uuid = test_server
+		.upload(
+			&file_contents,
+			&[
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				(headers::VISIBILITY, "private"),
+				("Cookie", &cookie_header_value),
+			],
+		)
+		.await?;
+
+	let mut links = Vec::new();
+	for single_use in ["true", "false"] {
+		let boundary = random_string(50);
+		let request = Request::builder()
+			.uri(format!("/api/entry/{uuid}/links"))
+			.method(Method::POST)
+			.header(
+				"Content-Type",
+				format!("multipart/form-data; boundary={boundary}"),
+			)
+			.header("Cookie", &cookie_header_value)
+			.body(Body::from(format!(
+				"--{boundary}\r\n\
+Content-Disposition: form-data; name=\"expires_in\"\r\n\r\n\
+60\r\n\
+--{boundary}--\r\n\
+Content-Disposition: form-data; name=\"single_use\"\r\n\r\n\
+{single_use}\r\n\
+--{boundary}--\r\n"
+			)))?;
+
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::CREATED);
+
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		let link: SignedLinkModel = serde_json::from_slice(&response_bytes)?;
+		assert_eq!(link.file_uuid, uuid);
+		links.push(link);
+	}
+	let [single_use_link, link] = links.as_slice() else {
+		unreachable!()
+	};
+
+	debug!("Interrupted download doesn't use up a single use link");
+	let single_use_request = || {
+		Request::builder()
+			.uri(&single_use_link.url)
+			.method(Method::GET)
+			.body(Body::empty())
+	};
+	let response = test_server.process_request(single_use_request()?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let concurrent_response = test_server.process_request(single_use_request()?).await?;
+	assert_eq!(concurrent_response.status(), StatusCode::CONFLICT);
+	drop(response);
+
+	debug!("Single use link works only once");
+	for expected_status in [StatusCode::OK, StatusCode::FORBIDDEN] {
+		let request = Request::builder()
+			.uri(&single_use_link.url)
+			.method(Method::GET)
+			.body(Body::empty())?;
+
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), expected_status);
+
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		if expected_status == StatusCode::OK {
+			assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+		}
+	}
+
+	debug!("Reusable link works until revoked");
+	for _ in 0..2 {
+		let request = Request::builder()
+			.uri(&link.url)
+			.method(Method::GET)
+			.body(Body::empty())?;
+
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		to_bytes(response.body_mut()).await?;
+	}
+
+	let tampered_url = link.url.replace(
+		&format!("expires={}", link.expires),
+		&format!("expires={}", link.expires + 60),
+	);
+	let request = Request::builder()
+		.uri(tampered_url)
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/links"))
+		.method(Method::GET)
+		.header("Cookie", &cookie_header_value)
+		.body(Body::empty())?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let listed_links: Vec<SignedLinkModel> = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(listed_links.len(), 1);
+	assert_eq!(listed_links[0].nonce, link.nonce);
+	assert_eq!(listed_links[0].url, link.url);
+
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/links/{}", link.nonce))
+		.method(Method::DELETE)
+		.header("Cookie", &cookie_header_value)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+	let request = Request::builder()
+		.uri(&link.url)
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn range_requests() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(1000);
+	let uuid = test_server
+		.upload(&file_contents, &[(headers::DOWNLOAD_COUNT, "10")])
+		.await?;
+
+	test_server.aqa_service = AqaService::new(test_server.db_handle.clone())
+		.with_client_addr(([203, 0, 113, 7], 4000).into());
+
+	let download_count = |db: db::Db| async move { db.get(&uuid).await.unwrap().download_count };
+
+	debug!("Full download advertises range support");
+	let request = download_request(&uuid, &[])?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers()[CONTENT_LENGTH], "1000");
+	assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+	let etag = response.headers()[ETAG].to_str()?.to_string();
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(&uuid)
+			.await
+			.unwrap()
+			.download_count,
+		1
+	);
+
+	debug!("Single range");
+	let request = download_request(&uuid, &[(RANGE.as_str(), "bytes=100-199")])?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+	assert_eq!(response.headers()[CONTENT_RANGE], "bytes 100-199/1000");
+	assert_eq!(response.headers()[CONTENT_LENGTH], "100");
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(&file_contents.as_bytes()[100..200], response_bytes.as_ref());
+	assert_eq!(download_count(test_server.db_handle.clone()).await, 2);
+
+	debug!("Next ranged requests of the same client don't count");
+	let request = download_request(
+		&uuid,
+		&[
+			(RANGE.as_str(), "bytes=0-9, 990-"),
+			(IF_RANGE.as_str(), etag.as_str()),
+		],
+	)?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+	let content_type = response.headers()[CONTENT_TYPE].to_str()?.to_string();
+	let multipart_boundary = content_type
+		.strip_prefix("multipart/byteranges; boundary=")
+		.expect("multipart/byteranges content type");
+	let expected_body = format!(
+		"\r\n--{multipart_boundary}\r\n\
+Content-Type: text/plain\r\n\
+Content-Range: bytes 0-9/1000\r\n\r\n\
+{}\
+\r\n--{multipart_boundary}\r\n\
+Content-Type: text/plain\r\n\
+Content-Range: bytes 990-999/1000\r\n\r\n\
+{}\
+\r\n--{multipart_boundary}--\r\n",
+		&file_contents[..10],
+		&file_contents[990..],
+	);
+	assert_eq!(
+		response.headers()[CONTENT_LENGTH],
+		expected_body.len().to_string()
+	);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(expected_body.as_bytes(), response_bytes.as_ref());
+	assert_eq!(download_count(test_server.db_handle.clone()).await, 2);
+
+	debug!("Ranged requests asking for more than the file count again");
+	for expected_download_count in [3, 4] {
+		let request = download_request(&uuid, &[(RANGE.as_str(), "bytes=0-")])?;
+
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+		assert_eq!(
+			download_count(test_server.db_handle.clone()).await,
+			expected_download_count
+		);
+	}
+
+	debug!("Ranged requests of a client with an unknown address always count");
+	let mut unknown_client = AqaService::new(test_server.db_handle.clone());
+	for expected_download_count in [5, 6] {
+		let request = download_request(&uuid, &[(RANGE.as_str(), "bytes=0-9")])?;
+
+		let mut response = unknown_client.call(request).await?;
+		assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+		to_bytes(response.body_mut()).await?;
+		assert_eq!(
+			download_count(test_server.db_handle.clone()).await,
+			expected_download_count
+		);
+	}
+
+	debug!("Mismatched If-Range gets the whole file");
+	let request = download_request(
+		&uuid,
+		&[
+			(RANGE.as_str(), "bytes=0-9"),
+			(IF_RANGE.as_str(), "\"something-else\""),
+		],
+	)?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	assert_eq!(download_count(test_server.db_handle.clone()).await, 7);
+
+	debug!("Unsatisfiable range");
+	let request = download_request(&uuid, &[(RANGE.as_str(), "bytes=1000-")])?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+	assert_eq!(response.headers()[CONTENT_RANGE], "bytes */1000");
+	assert_eq!(download_count(test_server.db_handle.clone()).await, 7);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conditional_requests() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(100);
+	let uuid = test_server
+		.upload(&file_contents, &[(headers::DOWNLOAD_COUNT, "5")])
+		.await?;
+
+	let download_count = |db: db::Db| async move { db.get(&uuid).await.unwrap().download_count };
+
+	let request = download_request(&uuid, &[])?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let etag = response.headers()[ETAG].to_str()?.to_string();
+	let last_modified = response.headers()[LAST_MODIFIED].to_str()?.to_string();
+	to_bytes(response.body_mut()).await?;
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(&uuid)
+			.await
+			.unwrap()
+			.download_count,
+		1
+	);
+
+	debug!("Matching If-None-Match");
+	for if_none_match in [
+		etag.clone(),
+		format!("\"other\", W/{etag}"),
+		"*".to_string(),
+	] {
+		let request = download_request(&uuid, &[(IF_NONE_MATCH.as_str(), if_none_match.as_str())])?;
+
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+		assert_eq!(response.headers()[ETAG], etag.as_str());
+		assert_eq!(response.headers()[LAST_MODIFIED], last_modified.as_str());
+		assert!(to_bytes(response.body_mut()).await?.is_empty());
+	}
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(&uuid)
+			.await
+			.unwrap()
+			.download_count,
+		1
+	);
+
+	debug!("Matching If-Modified-Since");
+	let request = download_request(
+		&uuid,
+		&[(IF_MODIFIED_SINCE.as_str(), last_modified.as_str())],
+	)?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(&uuid)
+			.await
+			.unwrap()
+			.download_count,
+		1
+	);
+
+	debug!("If-None-Match takes precedence over If-Modified-Since");
+	let request = download_request(
+		&uuid,
+		&[
+			(IF_NONE_MATCH.as_str(), "\"other\""),
+			(IF_MODIFIED_SINCE.as_str(), last_modified.as_str()),
+		],
+	)?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	assert_eq!(download_count(test_server.db_handle.clone()).await, 2);
+
+	debug!("Older If-Modified-Since");
+	let request = download_request(
+		&uuid,
+		&[(IF_MODIFIED_SINCE.as_str(), "Sun, 06 Nov 1994 08:49:37 GMT")],
+	)?;
+
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	to_bytes(response.body_mut()).await?;
+	assert_eq!(download_count(test_server.db_handle.clone()).await, 3);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn content_type_and_disposition() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let text_uuid = test_server
+		.upload_named(
+			"zażółć 100%.txt",
+			"text/plain; charset=utf-8",
+			&random_string(100),
+			&[(headers::DOWNLOAD_COUNT, "10")],
+		)
+		.await?;
+	let html_uuid = test_server
+		.upload_named(
+			"page.html",
+			"text/html",
+			&random_string(100),
+			&[(headers::DOWNLOAD_COUNT, "10")],
+		)
+		.await?;
+
+	debug!("Attachment by default, with the stored content type");
+	let request = download_request(&text_uuid, &[])?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(
+		response.headers()[CONTENT_TYPE],
+		"text/plain; charset=utf-8"
+	);
+	assert_eq!(
+		response.headers()[CONTENT_DISPOSITION],
+		"attachment; filename=\"za____ 100_.txt\"; \
+filename*=UTF-8''za%C5%BC%C3%B3%C5%82%C4%87%20100%25.txt"
+	);
+
+	debug!("Inline for safe content types");
+	let request = Request::builder()
+		.uri(format!("/api/download/{text_uuid}?disposition=inline"))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(response.headers()[CONTENT_DISPOSITION]
+		.to_str()?
+		.starts_with("inline; "));
+
+	debug!("Other content types are always attachments");
+	let request = Request::builder()
+		.uri(format!("/api/download/{html_uuid}?disposition=inline"))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers()[CONTENT_TYPE], "text/html");
+	assert_eq!(
+		response.headers()[CONTENT_DISPOSITION],
+		"attachment; filename=\"page.html\"; filename*=UTF-8''page.html"
+	);
+
+	debug!("Invalid disposition");
+	let request = Request::builder()
+		.uri(format!("/api/download/{text_uuid}?disposition=evil"))
+		.method(Method::GET)
+		.body(Body::empty())?;
+
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn entry_metadata_and_head_requests() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let (_, cookie) = test_server.create_user("Ala", AccountType::User).await?;
+
+	let file_contents = random_string(321);
+	let uuid = test_server
+		.upload(
+			&file_contents,
+			&[
+				(headers::DOWNLOAD_COUNT, "2"),
+				(headers::LIFETIME, "1 day"),
+				(headers::VISIBILITY, "private"),
+				("Cookie", &cookie),
+			],
+		)
+		.await?;
+
+	debug!("Private entries follow the download rules");
+	let response = test_server
+		.process_request(entry_request(&uuid, &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let mut request = download_request(&uuid, &[])?;
+	*request.method_mut() = Method::HEAD;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	debug!("Entry metadata");
+	let mut response = test_server
+		.process_request(entry_request(&uuid, &[("Cookie", &cookie)])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let file_model: list::FileModel = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(file_model.uuid, uuid);
+	assert_eq!(file_model.size, Some(321));
+	assert_eq!(file_model.download_limit, Some(2));
+	assert_eq!(file_model.downloads_remaining, Some(2));
+	let expires_at = humantime::parse_rfc3339(file_model.expires_at.as_deref().unwrap())?;
+	assert!(expires_at > std::time::SystemTime::now() + Duration::from_secs(60 * 60 * 23));
+	assert_eq!(file_model.shared_with, Some(Vec::new()));
+
+	debug!("HEAD doesn't consume a download");
+	for _ in 0..3 {
+		let mut request = download_request(&uuid, &[("Cookie", &cookie)])?;
+		*request.method_mut() = Method::HEAD;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[CONTENT_LENGTH], "321");
+		assert_eq!(response.headers()[headers::DOWNLOADS_REMAINING], "2");
+		assert!(response.headers().contains_key(headers::EXPIRES_AT));
+		assert!(to_bytes(response.body_mut()).await?.is_empty());
+	}
+	let download_count = test_server
+		.db_handle
+		.get(&uuid)
+		.await
+		.unwrap()
+		.download_count;
+	assert_eq!(download_count, 0);
+
+	let mut response = test_server
+		.process_request(download_request(&uuid, &[("Cookie", &cookie)])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+
+	let mut response = test_server
+		.process_request(entry_request(&uuid, &[("Cookie", &cookie)])?)
+		.await?;
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let file_model: list::FileModel = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(file_model.downloads_remaining, Some(1));
+
+	debug!("Unknown entry");
+	let response = test_server
+		.process_request(entry_request(&Uuid::new_v4(), &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bandwidth_limits() -> Result<()> {
+	let mut test_server = TestServer::with_settings(&Settings {
+		anonymous_bandwidth: BandwidthLimits {
+			per_connection: Some(64 * 1024),
+			per_account: None,
+		},
+		..Default::default()
+	})?;
+
+	let (_, admin_cookie) = test_server.create_user("Ala", AccountType::Admin).await?;
+
+	let file_contents = random_string(160 * 1024);
+	let uuid = test_server
+		.upload(&file_contents, &[(headers::DOWNLOAD_COUNT, "infinite")])
+		.await?;
+
+	debug!("Anonymous downloads are limited to 64 KiB/s");
+	let start = std::time::Instant::now();
+	let request = download_request(&uuid, &[])?;
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	assert!(start.elapsed() > Duration::from_secs(1));
+
+	debug!("Only admins can override the limit of an entry");
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/bandwidth"))
+		.method(Method::PUT)
+		.header(headers::BANDWIDTH_LIMIT, "unlimited")
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/bandwidth"))
+		.method(Method::PUT)
+		.header(headers::BANDWIDTH_LIMIT, "fast")
+		.header("Cookie", &admin_cookie)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let request = Request::builder()
+		.uri(format!("/api/entry/{uuid}/bandwidth"))
+		.method(Method::PUT)
+		.header(headers::BANDWIDTH_LIMIT, "unlimited")
+		.header("Cookie", &admin_cookie)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+	let start = std::time::Instant::now();
+	let request = download_request(&uuid, &[])?;
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	assert!(start.elapsed() < Duration::from_secs(1));
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn landing_page() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(2500);
+	let uuid = test_server
+		.upload_named(
+			"<b>report</b>.txt",
+			"text/plain",
+			&file_contents,
+			&[
+				(headers::DOWNLOAD_COUNT, "3"),
+				(headers::LIFETIME, "1 day"),
+				(headers::PASSWORD, TEST_PASSWORD),
+			],
+		)
+		.await?;
+
+	debug!("The page shows details of the file, but isn't a download");
+	for _ in 0..5 {
+		let request = Request::builder()
+			.uri(format!("/d/{uuid}"))
+			.method(Method::GET)
+			.body(Body::empty())?;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+		let page = String::from_utf8(to_bytes(response.body_mut()).await?.to_vec())?;
+		assert!(
+			page.contains(r#"<meta property="og:title" content="&lt;b&gt;report&lt;/b&gt;.txt">"#)
+		);
+		assert!(page.contains("2.5 KB · expires in 23 hours · 3 downloads left"));
+		assert!(page.contains(r#"<input type="password" name="password""#));
+		assert!(!page.contains("<b>report</b>"));
+	}
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(&uuid)
+			.await
+			.unwrap()
+			.download_count,
+		0
+	);
+
+	debug!("Invalid password renders the page again");
+	let password_form = |password: &str| -> Result<Request<Body>> {
+		let boundary = random_string(50);
+		Ok(Request::builder()
+			.uri(format!("/d/{uuid}"))
+			.method(Method::POST)
+			.header(
+				"Content-Type",
+				format!("multipart/form-data; boundary={boundary}"),
+			)
+			.body(Body::from(format!(
+				"--{boundary}\r\n\
+Content-Disposition: form-data; name=\"password\"\r\n\r\n\
+{password}\r\n\
+--{boundary}--\r\n"
+			)))?)
+	};
+	let mut response = test_server.process_request(password_form("psina")?).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let page = String::from_utf8(to_bytes(response.body_mut()).await?.to_vec())?;
+	assert!(page.contains("Invalid password"));
+
+	debug!("Valid password redirects to the download");
+	let response = test_server
+		.process_request(password_form(TEST_PASSWORD)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::SEE_OTHER);
+	let location = response.headers()[LOCATION].to_str()?.to_string();
+	assert!(location.starts_with(&format!("/api/download/{uuid}?unlock_token=")));
+
+	let request = Request::builder()
+		.uri(location)
+		.method(Method::GET)
+		.body(Body::empty())?;
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(&uuid)
+			.await
+			.unwrap()
+			.download_count,
+		1
+	);
+
+	debug!("Unknown entry");
+	let request = Request::builder()
+		.uri(format!("/d/{}", Uuid::new_v4()))
+		.method(Method::GET)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn download_audit_log() -> Result<()> {
+	let proxy_ip: std::net::IpAddr = "10.0.0.1".parse()?;
+	let mut test_server = TestServer::with_settings(&Settings {
+		trusted_proxies: vec![proxy_ip],
+		..Default::default()
+	})?;
+
+	let (_, uploader_cookie) = test_server.create_user("Ala", AccountType::User).await?;
+	let (_, other_cookie) = test_server.create_user("Ola", AccountType::User).await?;
+
+	let file_contents = random_string(143);
+	let uuid = test_server
+		.upload(
+			&file_contents,
+			&[
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				("Cookie", uploader_cookie.as_str()),
+			],
+		)
+		.await?;
+
+	let mut behind_proxy =
+		AqaService::new(test_server.db_handle.clone()).with_client_addr((proxy_ip, 4000).into());
+	let proxied_download_request = || {
+		download_request(
+			&uuid,
+			&[
+				("X-Forwarded-For", "198.51.100.1, 203.0.113.7"),
+				("User-Agent", "aqa-test/1.0"),
+			],
+		)
+	};
+
+	debug!("Completed download");
+	let mut response = behind_proxy.call(proxied_download_request()?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	assert_eq!(file_contents.as_bytes(), response_bytes.as_ref());
+	drop(response);
+
+	debug!("Download dropped before the body is read");
+	let response = behind_proxy.call(proxied_download_request()?).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	drop(response);
+
+	debug!("Download by a logged in account");
+	let request = download_request(&uuid, &[("Cookie", other_cookie.as_str())])?;
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	to_bytes(response.body_mut()).await?;
+	drop(response);
+
+	let list_downloads = |cookie: Option<&str>| {
+		let request = Request::builder()
+			.uri(format!("/api/entry/{uuid}/downloads"))
+			.method(Method::GET);
+		match cookie {
+			Some(cookie) => request.header("Cookie", cookie),
+			None => request,
+		}
+		.body(Body::empty())
+	};
+
+	let mut response = test_server
+		.process_request(list_downloads(Some(&uploader_cookie))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let events: Vec<DownloadEvent> = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(events.len(), 3);
+
+	assert_eq!(events[0].status, DownloadStatus::Completed);
+	assert_eq!(events[0].bytes_sent, file_contents.len() as u64);
+	assert_eq!(events[0].client_ip, Some("203.0.113.7".parse()?));
+	assert_eq!(events[0].user_agent.as_deref(), Some("aqa-test/1.0"));
+	assert_eq!(events[0].account, None);
+
+	assert_eq!(events[1].status, DownloadStatus::Interrupted);
+	assert_eq!(events[1].bytes_sent, 0);
+
+	assert_eq!(events[2].status, DownloadStatus::Completed);
+	assert_eq!(events[2].username.as_deref(), Some("Ola"));
+
+	debug!("Only the uploader can read the log");
+	let response = test_server
+		.process_request(list_downloads(Some(&other_cookie))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = test_server.process_request(list_downloads(None)?).await?;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	debug!("Log outlives the entry");
+	test_server.db_handle.writer().await.remove(&uuid);
+	let mut response = test_server
+		.process_request(list_downloads(Some(&uploader_cookie))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response_bytes = to_bytes(response.body_mut()).await?;
+	let events: Vec<DownloadEvent> = serde_json::from_slice(&response_bytes)?;
+	assert_eq!(events.len(), 3);
+	let response = test_server
+		.process_request(list_downloads(Some(&other_cookie))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn list_pagination_sorting_and_filters() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let files = [
+		("report.pdf", "application/pdf", 300, None),
+		("Holiday.png", "image/png", 500, None),
+		("avatar.jpg", "image/jpeg", 100, Some("makota")),
+		("notes.txt", "text/plain", 200, None),
+		("Annual REPORT.txt", "text/plain", 400, Some("makota")),
+	];
+	for (filename, content_type, size, password) in files {
+		let mut upload_headers = vec![(headers::DOWNLOAD_COUNT, "10")];
+		if let Some(password) = password {
+			upload_headers.push((headers::PASSWORD, password));
+		}
+		test_server
+			.upload_named(
+				filename,
+				content_type,
+				&random_string(size),
+				&upload_headers,
+			)
+			.await?;
+	}
+
+	async fn list_names(
+		test_server: &mut TestServer,
+		query: &str,
+	) -> Result<(Vec<String>, Option<String>)> {
+		let request = Request::builder()
+			.uri(format!("/api/list.json?{query}"))
+			.method(Method::GET)
+			.body(Body::empty())?;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let next_cursor = response
+			.headers()
+			.get(headers::NEXT_CURSOR)
+			.map(|cursor| cursor.to_str().unwrap().to_string());
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		let list: Vec<list::FileModel> = serde_json::from_slice(&response_bytes)?;
+		let names = list
+			.into_iter()
+			.map(|file| file.filename.into_owned())
+			.collect();
+		Ok((names, next_cursor))
+	}
+
+	debug!("Paging through the list sorted by name");
+	let mut names = Vec::new();
+	let mut query = String::from("sort=name&limit=2");
+	let mut pages = 0;
+	loop {
+		let (page, next_cursor) = list_names(&mut test_server, &query).await?;
+		assert!(page.len() <= 2);
+		names.extend(page);
+		pages += 1;
+		match next_cursor {
+			Some(cursor) => query = format!("sort=name&limit=2&cursor={cursor}"),
+			None => break,
+		}
+	}
+	assert_eq!(pages, 3);
+	assert_eq!(
+		names,
+		[
+			"Annual REPORT.txt",
+			"avatar.jpg",
+			"Holiday.png",
+			"notes.txt",
+			"report.pdf"
+		]
+	);
+
+	let (names, next_cursor) = list_names(&mut test_server, "sort=name&order=desc").await?;
+	assert_eq!(names[0], "report.pdf");
+	assert_eq!(next_cursor, None);
+
+	let (names, _) = list_names(&mut test_server, "sort=size").await?;
+	assert_eq!(
+		names,
+		[
+			"Holiday.png",
+			"Annual REPORT.txt",
+			"report.pdf",
+			"notes.txt",
+			"avatar.jpg"
+		]
+	);
+
+	debug!("Filters");
+	let (mut names, _) = list_names(&mut test_server, "content_type=IMAGE%2F").await?;
+	names.sort();
+	assert_eq!(names, ["Holiday.png", "avatar.jpg"]);
+
+	let (mut names, _) = list_names(&mut test_server, "search=report").await?;
+	names.sort();
+	assert_eq!(names, ["Annual REPORT.txt", "report.pdf"]);
+
+	let (names, _) = list_names(&mut test_server, "has_password=true&sort=name").await?;
+	assert_eq!(names, ["Annual REPORT.txt", "avatar.jpg"]);
+
+	let (names, _) = list_names(&mut test_server, "uploaded_before=1000").await?;
+	assert!(names.is_empty());
+	let (names, _) = list_names(&mut test_server, "uploaded_after=1000&visibility=public").await?;
+	assert_eq!(names.len(), 5);
+
+	debug!("Invalid queries");
+	let (_, cursor) = list_names(&mut test_server, "sort=name&limit=1").await?;
+	for query in [
+		String::from("sort=popularity"),
+		String::from("limit=0"),
+		String::from("cursor=abc"),
+		format!("sort=size&cursor={}", cursor.unwrap()),
+	] {
+		let request = Request::builder()
+			.uri(format!("/api/list.json?{query}"))
+			.method(Method::GET)
+			.body(Body::empty())?;
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{query}");
+	}
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sizes_are_recorded_and_backfilled() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let file_contents = random_string(1234);
+	let uuid = test_server
+		.upload(&file_contents, &[(headers::DOWNLOAD_COUNT, "infinite")])
+		.await?;
+
+	assert_eq!(
+		test_server.db_handle.get(&uuid).await.unwrap().size,
+		Some(1234)
+	);
+
+	let list = test_server.list("/api/list.json", &[]).await?;
+	assert_eq!(list[0].size, Some(1234));
+	assert_eq!(list[0].download_limit, None);
+	assert_eq!(list[0].downloads_remaining, None);
+	assert_eq!(list[0].expires_at, None);
+
+	debug!("Simulating an index saved before sizes were recorded");
+	test_server
+		.db_handle
+		.writer()
+		.await
+		.get_mut(&uuid)
+		.unwrap()
+		.size = None;
+	test_server.db_handle.save().await?;
+
+	let db_handle = db::init(test_server.db_dir.path())?;
+	assert_eq!(db_handle.get(&uuid).await.unwrap().size, Some(1234));
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn atom_feeds() -> Result<()> {
+	let mut test_server = TestServer::with_settings(&Settings {
+		public_url: Some(String::from("https://send.example.com/")),
+		..Default::default()
+	})?;
+
+	let (_, cookie) = test_server
+		.create_user("Ala Kowalska", AccountType::User)
+		.await?;
+
+	let mut uuids = Vec::new();
+	for (filename, content_type, visibility, logged_in) in [
+		("<song>.mp3", "audio/mpeg", "public", true),
+		("secret.txt", "text/plain", "unlisted", true),
+		("anonymous.txt", "text/plain", "public", false),
+	] {
+		let mut upload_headers = vec![
+			(headers::DOWNLOAD_COUNT, "1"),
+			(headers::VISIBILITY, visibility),
+		];
+		if logged_in {
+			upload_headers.push(("Cookie", &cookie));
+		}
+		let uuid = test_server
+			.upload_named(filename, content_type, &random_string(321), &upload_headers)
+			.await?;
+		uuids.push(uuid);
+	}
+	let [song, secret, anonymous] = uuids.as_slice() else {
+		unreachable!()
+	};
+
+	async fn feed(test_server: &mut TestServer, path: &str) -> Result<(StatusCode, String)> {
+		let request = Request::builder()
+			.uri(path)
+			.method(Method::GET)
+			.body(Body::empty())?;
+		let mut response = test_server.process_request(request).await?;
+		if response.status() == StatusCode::OK {
+			assert_eq!(
+				response.headers()[CONTENT_TYPE],
+				"application/atom+xml; charset=utf-8"
+			);
+		}
+		let body = String::from_utf8(to_bytes(response.body_mut()).await?.to_vec())?;
+		Ok((response.status(), body))
+	}
+
+	let (status, public_feed) = feed(&mut test_server, "/feeds/public.atom").await?;
+	assert_eq!(status, StatusCode::OK);
+	assert!(public_feed.contains(&format!("<id>urn:uuid:{song}</id>")));
+	assert!(public_feed.contains(&format!("<id>urn:uuid:{anonymous}</id>")));
+	assert!(!public_feed.contains(&secret.to_string()));
+	assert!(public_feed.contains("<title>&lt;song&gt;.mp3</title>"));
+	assert!(public_feed.contains("<author><name>Ala Kowalska</name></author>"));
+	assert!(public_feed.contains(&format!(
+		r#"<link rel="enclosure" type="audio/mpeg" href="https://send.example.com/api/download/{song}" length="321"/>"#
+	)));
+	assert!(public_feed.contains(&format!(
+		r#"<link rel="alternate" type="text/html" href="https://send.example.com/d/{song}"/>"#
+	)));
+
+	let (status, user_feed) = feed(&mut test_server, "/feeds/user/Ala%20Kowalska.atom").await?;
+	assert_eq!(status, StatusCode::OK);
+	assert!(user_feed.contains(&song.to_string()));
+	assert!(!user_feed.contains(&anonymous.to_string()));
+	assert!(!user_feed.contains(&secret.to_string()));
+
+	debug!("Feeds don't consume downloads");
+	assert_eq!(
+		test_server
+			.db_handle
+			.get(song)
+			.await
+			.unwrap()
+			.download_count,
+		0
+	);
+
+	for path in ["/feeds/user/Ola.atom", "/feeds/user/Ala%20Kowalska.rss"] {
+		let (status, _) = feed(&mut test_server, path).await?;
+		assert_eq!(status, StatusCode::NOT_FOUND);
+	}
+
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_context() -> Result<()> {
+	let proxy_ip: std::net::IpAddr = "10.0.0.1".parse()?;
+	let mut test_server = TestServer::with_settings(&Settings {
+		trusted_proxies: vec![proxy_ip],
+		..Default::default()
+	})?;
+
+	debug!("Every response carries a request ID and CORS headers, errors and 404s included");
+	for uri in ["/api", "/api/entry/not-a-uuid", "/nothing/here"] {
+		let request = Request::builder()
+			.uri(uri)
+			.method(Method::GET)
+			.header("Origin", "https://example.com")
+			.body(Body::empty())?;
+		let response = test_server.process_request(request).await?;
+		let request_id = response.headers()[headers::REQUEST_ID].to_str()?;
+		Uuid::parse_str(request_id)?;
+		assert_eq!(
+			response.headers()["Access-Control-Allow-Origin"],
+			"https://example.com"
+		);
+		assert_eq!(
+			response.headers()["Access-Control-Allow-Credentials"],
+			"true"
+		);
+	}
+
+	debug!("Preflight requests without an Origin");
+	let request = Request::builder()
+		.uri("/api/upload")
+		.method(Method::OPTIONS)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	assert!(!response
+		.headers()
+		.contains_key("Access-Control-Allow-Origin"));
+
+	debug!("Request IDs are only taken from trusted proxies");
+	let request = || {
+		Request::builder()
+			.uri("/api")
+			.method(Method::GET)
+			.header(headers::REQUEST_ID, "proxy-request-1")
+			.body(Body::empty())
+	};
+	let mut behind_proxy =
+		AqaService::new(test_server.db_handle.clone()).with_client_addr((proxy_ip, 4000).into());
+	let response = behind_proxy.call(request()?).await?;
+	assert_eq!(response.headers()[headers::REQUEST_ID], "proxy-request-1");
+	let mut direct = AqaService::new(test_server.db_handle.clone())
+		.with_client_addr(([203, 0, 113, 7], 4000).into());
+	let response = direct.call(request()?).await?;
+	assert_ne!(response.headers()[headers::REQUEST_ID], "proxy-request-1");
+
+	debug!("An expired session is rejected where logging in is required");
+	let expired_cookie = format!("session={}", Uuid::new_v4());
+	for uri in ["/api/whoami", "/api/notifications", "/api/groups"] {
+		let request = Request::builder()
+			.uri(uri)
+			.method(Method::GET)
+			.header("Cookie", &expired_cookie)
+			.body(Body::empty())?;
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+		let cookie = response.headers()[SET_COOKIE].to_str()?;
+		assert!(cookie.starts_with("session=; Max-Age=0;"), "{cookie}");
+	}
+
+	debug!("Public resources are served as to an anonymous user and the cookie is removed");
+	let uuid = test_server
+		.upload("public file", &[(headers::DOWNLOAD_COUNT, "infinite")])
+		.await?;
+	for request in [
+		Request::builder()
+			.uri("/api/list.json")
+			.method(Method::GET)
+			.header("Cookie", &expired_cookie)
+			.body(Body::empty())?,
+		download_request(&uuid, &[("Cookie", &expired_cookie)])?,
+		entry_request(&uuid, &[("Cookie", &expired_cookie)])?,
+		upload_request(
+			"another file",
+			&[(headers::DOWNLOAD_COUNT, "1"), ("Cookie", &expired_cookie)],
+		)?,
+	] {
+		let uri = request.uri().to_string();
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK, "{uri}");
+		let cookie = response.headers()[SET_COOKIE].to_str()?;
+		assert!(cookie.starts_with("session=; Max-Age=0;"), "{cookie}");
+	}
+
+	debug!("Logging in with a stale cookie sets the new session");
+	create_account(
+		test_server.db_handle.clone(),
+		String::from("Ala"),
+		AccountType::User,
+		Zeroizing::new(TEST_PASSWORD.to_string()),
+	)
+	.await?;
+	let request = login_request("Ala", TEST_PASSWORD, &[("Cookie", &expired_cookie)])?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	assert_eq!(response.headers().get_all(SET_COOKIE).iter().count(), 1);
+	let cookie = response.headers()[SET_COOKIE].to_str()?;
+	let (_, cookie) = cookie::parse_set_cookie(cookie).unwrap();
+	assert_ne!(cookie.value, "");
+
+	Ok(())
+}
+
+type WebSocket = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+/// Opens `/api/ws` of the server listening on `addr`, with the given extra headers
+async fn connect_ws(
+	addr: std::net::SocketAddr,
+	headers: &[(&str, &str)],
+) -> Result<WebSocket, tokio_tungstenite::tungstenite::Error> {
+	use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+	let mut request = format!("ws://{addr}/api/ws").into_client_request()?;
+	for (name, value) in headers {
+		request.headers_mut().insert(
+			hyper::header::HeaderName::from_bytes(name.as_bytes()).expect("valid header name"),
+			value.parse().expect("valid header value"),
+		);
+	}
+	let stream = tokio::net::TcpStream::connect(addr).await?;
+	let (ws, _response) = tokio_tungstenite::client_async(request, stream).await?;
+	Ok(ws)
+}
+
+/// Next message sent by the server
+async fn read_ws_message(ws: &mut WebSocket) -> Result<tokio_tungstenite::tungstenite::Message> {
+	use futures::StreamExt;
+
+	let message = tokio::time::timeout(Duration::from_secs(5), ws.next()).await?;
+	Ok(message.expect("WebSocket closed")?)
+}
+
+async fn read_ws_event(ws: &mut WebSocket) -> Result<serde_json::Value> {
+	match read_ws_message(ws).await? {
+		tokio_tungstenite::tungstenite::Message::Text(text) => Ok(serde_json::from_str(&text)?),
+		message => panic!("expected a text message, got {message:?}"),
+	}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn websocket_events() -> Result<()> {
+	use futures::SinkExt;
+	use hyper::server::conn::AddrStream;
+	use hyper::service::make_service_fn;
+	use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+	use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+	use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+	let mut test_server = TestServer::with_settings(&Settings {
+		allowed_origins: vec![String::from("https://app.example.com")],
+		..Default::default()
+	})?;
+	test_server.start_cleanup_task(Duration::from_millis(100));
+
+	let ala_uuid = create_account(
+		test_server.db_handle.clone(),
+		String::from("Ala"),
+		AccountType::User,
+		Zeroizing::new(TEST_PASSWORD.to_string()),
+	)
+	.await?;
+	let session = test_server.db_handle.authorized_users().create_session(
+		ala_uuid,
+		None,
+		None,
+		&test_server.db_handle.config.settings,
+	);
+	let ala_cookie = format!("session={session}");
+	let mut ala_service = AqaService::new(test_server.db_handle.clone());
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+	listener.set_nonblocking(true)?;
+	let addr = listener.local_addr()?;
+	let db = test_server.db_handle.clone();
+	let server = hyper::Server::from_tcp(listener)?.serve(make_service_fn(
+		move |addr_stream: &AddrStream| {
+			std::future::ready(Result::<_, AqaServiceError>::Ok(
+				AqaService::new(db.clone()).with_client_addr(addr_stream.remote_addr()),
+			))
+		},
+	));
+	tokio::spawn(server);
+
+	debug!("Requests without an upgrade are rejected");
+	let request = Request::builder()
+		.uri("/api/ws")
+		.method(Method::GET)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	debug!("Other sites can't open the WebSocket with the session of their visitor");
+	match connect_ws(
+		addr,
+		&[
+			("Origin", "https://evil.example.com"),
+			("Cookie", &ala_cookie),
+		],
+	)
+	.await
+	{
+		Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+		Err(err) => panic!("{err}"),
+		Ok(_) => panic!("WebSocket from another origin was accepted"),
+	}
+	connect_ws(addr, &[("Origin", "https://app.example.com")]).await?;
+
+	let same_origin = format!("http://{addr}");
+	let mut ala_ws = connect_ws(addr, &[("Origin", &same_origin), ("Cookie", &ala_cookie)]).await?;
+	let mut anonymous_ws = connect_ws(addr, &[]).await?;
+
+	let mut uuids = Vec::new();
+	for (visibility, cookie) in [("private", Some(&ala_cookie)), ("public", None)] {
+		let mut upload_headers = vec![
+			(headers::DOWNLOAD_COUNT, "1"),
+			(headers::VISIBILITY, visibility),
+		];
+		if let Some(cookie) = cookie {
+			upload_headers.push(("Cookie", cookie));
+		}
+		let request = upload_request(&random_string(100), &upload_headers)?;
+		let mut response = ala_service.call(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let response_bytes = to_bytes(response.body_mut()).await?;
+		let UploadResponse(uploaded_files) = serde_json::from_slice(&response_bytes)?;
+		uuids.push(uploaded_files[0].uuid);
+	}
+	let [private_uuid, public_uuid] = uuids.as_slice() else {
+		unreachable!()
+	};
+
+	let mut response = test_server
+		.process_request(download_request(public_uuid, &[])?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	to_bytes(response.body_mut()).await?;
+
+	debug!("Ala also gets the events of the private entry");
+	let event = read_ws_event(&mut ala_ws).await?;
+	assert_eq!(event["type"], "created");
+	assert_eq!(event["uuid"], private_uuid.to_string());
+	assert_eq!(event["entry"]["filename"], "sample_file");
+	assert_eq!(event["entry"]["size"], 100);
+
+	for ws in [&mut ala_ws, &mut anonymous_ws] {
+		let event = read_ws_event(ws).await?;
+		assert_eq!(event["type"], "created");
+		assert_eq!(event["uuid"], public_uuid.to_string());
+
+		let event = read_ws_event(ws).await?;
+		assert_eq!(event["type"], "downloaded");
+		assert_eq!(event["entry"]["download_count"], 1);
+
+		debug!("The cleanup task removes the used up entry");
+		let event = read_ws_event(ws).await?;
+		assert_eq!(event["type"], "used_up");
+		assert_eq!(event["uuid"], public_uuid.to_string());
+		assert!(event["entry"].is_null());
+	}
+
+	let request = Request::builder()
+		.uri(format!("/api/delete/{private_uuid}"))
+		.method(Method::DELETE)
+		.header("Cookie", &ala_cookie)
+		.body(Body::empty())?;
+	let response = ala_service.call(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	let event = read_ws_event(&mut ala_ws).await?;
+	assert_eq!(event["type"], "deleted");
+	assert_eq!(event["uuid"], private_uuid.to_string());
+
+	debug!("Pings are answered, nothing about the private entry was queued before the pong");
+	anonymous_ws.send(Message::Ping(b"hello".to_vec())).await?;
+	assert_eq!(
+		read_ws_message(&mut anonymous_ws).await?,
+		Message::Pong(b"hello".to_vec())
+	);
+
+	debug!("Closing handshake");
+	anonymous_ws
+		.close(Some(CloseFrame {
+			code: CloseCode::Normal,
+			reason: "".into(),
+		}))
+		.await?;
+	match read_ws_message(&mut anonymous_ws).await? {
+		Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Normal),
+		message => panic!("expected a close message, got {message:?}"),
+	}
+
+	debug!("Connection of a revoked session is closed before the next event");
+	let request = Request::builder()
+		.uri("/api/sessions")
+		.method(Method::DELETE)
+		.header("Cookie", &ala_cookie)
+		.body(Body::empty())?;
+	let response = ala_service.call(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	test_server
+		.upload(
+			&random_string(100),
+			&[
+				(headers::DOWNLOAD_COUNT, "1"),
+				(headers::VISIBILITY, "public"),
+			],
+		)
+		.await?;
+	match read_ws_message(&mut ala_ws).await? {
+		Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+		message => panic!("expected a close message, got {message:?}"),
+	}
+
+	Ok(())
+}
+
+/// Next chunk of a streamed response body
+async fn next_body_chunk(body: &mut Body) -> Result<String> {
+	use hyper::body::HttpBody;
+
+	let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+		.await?
+		.expect("body ended")?;
+	Ok(String::from_utf8(chunk.to_vec())?)
+}
+
+/// Splits a Server-Sent Events message into its id and JSON data
+fn parse_sse_message(message: &str) -> Result<(u64, serde_json::Value)> {
+	let message = message.strip_suffix("\n\n").expect("message terminator");
+	let (id, data) = message.split_once('\n').expect("id and data lines");
+	let id = id.strip_prefix("id: ").expect("id line").parse()?;
+	let data = data.strip_prefix("data: ").expect("data line");
+	Ok((id, serde_json::from_str(data)?))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_sent_events() -> Result<()> {
+	let mut test_server = TestServer::new()?;
+
+	let (_, ala_cookie) = test_server.create_user("Ala", AccountType::User).await?;
+
+	let events_request = |cookie: Option<&str>, last_event_id: Option<&str>| {
+		let request = Request::builder().uri("/api/events").method(Method::GET);
+		let request = match cookie {
+			Some(cookie) => request.header("Cookie", cookie),
+			None => request,
+		};
+		let request = match last_event_id {
+			Some(last_event_id) => request.header("Last-Event-ID", last_event_id),
+			None => request,
+		};
+		request.body(Body::empty())
+	};
+
+	let mut anonymous_events = test_server
+		.process_request(events_request(None, None)?)
+		.await?;
+	assert_eq!(anonymous_events.status(), StatusCode::OK);
+	assert_eq!(
+		anonymous_events.headers()[CONTENT_TYPE],
+		"text/event-stream"
+	);
+
+	let private_uuid = test_server
+		.upload(
+			&random_string(100),
+			&[
+				(headers::DOWNLOAD_COUNT, "10"),
+				(headers::VISIBILITY, "private"),
+				("Cookie", &ala_cookie),
+			],
+		)
+		.await?;
+	let public_uuid = test_server
+		.upload(&random_string(100), &[(headers::DOWNLOAD_COUNT, "10")])
+		.await?;
+
+	debug!("Anonymous subscribers don't get events of the private entry");
+	let (public_id, event) =
+		parse_sse_message(&next_body_chunk(anonymous_events.body_mut()).await?)?;
+	assert_eq!(event["type"], "created");
+	assert_eq!(event["uuid"], public_uuid.to_string());
+
+	let request = download_request(&public_uuid, &[])?;
+	let mut response = test_server.process_request(request).await?;
+	to_bytes(response.body_mut()).await?;
+	let (downloaded_id, event) =
+		parse_sse_message(&next_body_chunk(anonymous_events.body_mut()).await?)?;
+	assert_eq!(downloaded_id, public_id + 1);
+	assert_eq!(event["type"], "downloaded");
+	assert_eq!(event["entry"]["download_count"], 1);
+	assert_eq!(event["entry"]["downloads_remaining"], 9);
+
+	debug!("Resuming replays the missed events visible to the subscriber");
+	let mut ala_events = test_server
+		.process_request(events_request(Some(&ala_cookie), Some("0"))?)
+		.await?;
+	let mut replayed = Vec::new();
+	for _ in 0..3 {
+		let (_, event) = parse_sse_message(&next_body_chunk(ala_events.body_mut()).await?)?;
+		replayed.push((event["type"].clone(), event["uuid"].clone()));
+	}
+	assert_eq!(
+		replayed,
+		[
+			("created".into(), private_uuid.to_string().into()),
+			("created".into(), public_uuid.to_string().into()),
+			("downloaded".into(), public_uuid.to_string().into()),
+		]
+	);
+
+	let mut resumed = test_server
+		.process_request(events_request(None, Some(&public_id.to_string()))?)
+		.await?;
+	let (id, _) = parse_sse_message(&next_body_chunk(resumed.body_mut()).await?)?;
+	assert_eq!(id, downloaded_id);
+
+	debug!("Events that aren't available anymore");
+	let mut reset = test_server
+		.process_request(events_request(None, Some("1000000"))?)
+		.await?;
+	assert_eq!(
+		next_body_chunk(reset.body_mut()).await?,
+		"event: reset\ndata: \n\n"
+	);
+
+	let response = test_server
+		.process_request(events_request(None, Some("abc"))?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	debug!("Stream of a revoked session ends before the next event");
+	let request = Request::builder()
+		.uri("/api/sessions")
+		.method(Method::DELETE)
+		.header("Cookie", &ala_cookie)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	test_server
+		.upload(
+			&random_string(100),
+			&[
+				(headers::DOWNLOAD_COUNT, "1"),
+				(headers::VISIBILITY, "public"),
+			],
+		)
+		.await?;
+	let (_, event) = parse_sse_message(&next_body_chunk(anonymous_events.body_mut()).await?)?;
+	assert_eq!(event["type"], "created");
+	let end = tokio::time::timeout(
+		Duration::from_secs(5),
+		hyper::body::HttpBody::data(ala_events.body_mut()),
+	)
+	.await?;
+	assert!(end.is_none());
+
+	Ok(())
+}
+
+/// Request received by [start_webhook_receiver]
+struct ReceivedWebhook {
+	path: String,
+	headers: hyper::HeaderMap,
+	body: Vec<u8>,
+}
+
+/// Local HTTP server recording the requests it gets. Requests to `/fail` are answered with `500`.
+fn start_webhook_receiver() -> Result<(
+	std::net::SocketAddr,
+	tokio::sync::mpsc::UnboundedReceiver<ReceivedWebhook>,
+)> {
+	use hyper::server::conn::AddrStream;
+	use hyper::service::{make_service_fn, service_fn};
+
+	let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+	let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+	listener.set_nonblocking(true)?;
+	let addr = listener.local_addr()?;
+	let server = hyper::Server::from_tcp(listener)?.serve(make_service_fn(
+		move |_addr_stream: &AddrStream| {
+			let sender = sender.clone();
+			std::future::ready(Result::<_, hyper::Error>::Ok(service_fn(
+				move |req: Request<Body>| {
+					let sender = sender.clone();
+					async move {
+						let path = req.uri().path().to_owned();
+						let (parts, body) = req.into_parts();
+						let body = to_bytes(body).await?.to_vec();
+						let mut response = Response::new(Body::empty());
+						if path == "/fail" {
+							*response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+						}
+						let _ = sender.send(ReceivedWebhook {
+							path,
+							headers: parts.headers,
+							body,
+						});
+						Result::<_, hyper::Error>::Ok(response)
+					}
+				},
+			)))
+		},
+	));
+	tokio::spawn(server);
+	Ok((addr, receiver))
+}
+
+async fn next_webhook(
+	receiver: &mut tokio::sync::mpsc::UnboundedReceiver<ReceivedWebhook>,
+) -> Result<ReceivedWebhook> {
+	Ok(
+		tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+			.await?
+			.expect("receiver running"),
+	)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn webhooks() -> Result<()> {
+	use aqa_send::webhooks::{self, WebhookModel};
+	use hmac::Mac;
+
+	let mut test_server = TestServer::with_settings(&Settings {
+		webhook_max_attempts: 2,
+		webhook_retry_base_secs: 0,
+		webhook_allow_private_addresses: true,
+		..Default::default()
+	})?;
+	test_server.start_webhook_task();
+	test_server.start_cleanup_task(Duration::from_millis(100));
+	let (receiver_addr, mut received) = start_webhook_receiver()?;
+
+	let (_, ala_cookie) = test_server.create_user("Ala", AccountType::User).await?;
+	let (_, admin_cookie) = test_server.create_user("Admin", AccountType::Admin).await?;
+
+	let register = |cookie: &str, fields: &[(&str, String)]| {
+		let boundary = random_string(50);
+		let mut body = format!("--{boundary}\r\n");
+		for (name, value) in fields {
+			body.push_str(&format!(
+				"Content-Disposition: form-data; name=\"{name}\"\r\n\r\n\
+{value}\r\n\
+--{boundary}--\r\n"
+			));
+		}
+		Request::builder()
+			.uri("/api/webhooks")
+			.method(Method::POST)
+			.header(
+				"Content-Type",
+				format!("multipart/form-data; boundary={boundary}"),
+			)
+			.header("Cookie", cookie)
+			.body(Body::from(body))
+	};
+
+	debug!("Invalid registrations");
+	for fields in [
+		vec![("url", String::from("ftp://127.0.0.1/hook"))],
+		vec![
+			("url", format!("http://{receiver_addr}/ala")),
+			("events", String::from("created,exploded")),
+		],
+	] {
+		let response = test_server
+			.process_request(register(&ala_cookie, &fields)?)
+			.await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	}
+	let response = test_server
+		.process_request(register(
+			&ala_cookie,
+			&[
+				("url", format!("http://{receiver_addr}/global")),
+				("global", String::from("true")),
+			],
+		)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	let mut response = test_server
+		.process_request(register(
+			&ala_cookie,
+			&[
+				("url", format!("http://{receiver_addr}/ala")),
+				("events", String::from("created, downloaded")),
+				("secret", String::from("s3cret")),
+			],
+		)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	let ala_hook: WebhookModel = serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+	assert_eq!(ala_hook.secret.as_deref(), Some("s3cret"));
+	assert!(!ala_hook.global);
+
+	debug!("Global webhook with a generated secret");
+	let mut response = test_server
+		.process_request(register(
+			&admin_cookie,
+			&[
+				("url", format!("http://{receiver_addr}/global")),
+				("events", String::from("used_up")),
+				("global", String::from("true")),
+			],
+		)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	let global_hook: WebhookModel = serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+	assert!(global_hook.global);
+	let global_secret = global_hook.secret.clone().unwrap();
+	assert_eq!(global_secret.len(), 64);
+
+	debug!("Secrets aren't listed, users don't see global webhooks");
+	for (cookie, count) in [(&ala_cookie, 1), (&admin_cookie, 1)] {
+		let request = Request::builder()
+			.uri("/api/webhooks")
+			.method(Method::GET)
+			.header("Cookie", cookie)
+			.body(Body::empty())?;
+		let mut response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let hooks: Vec<WebhookModel> =
+			serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+		assert_eq!(hooks.len(), count);
+		assert!(hooks[0].secret.is_none());
+	}
+
+	let uuid = test_server
+		.upload(
+			&random_string(100),
+			&[(headers::DOWNLOAD_COUNT, "1"), ("Cookie", &ala_cookie)],
+		)
+		.await?;
+
+	debug!("Uploads of other accounts don't reach Ala's webhook");
+	test_server
+		.upload(
+			&random_string(100),
+			&[
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				("Cookie", &admin_cookie),
+			],
+		)
+		.await?;
+
+	let webhook = next_webhook(&mut received).await?;
+	assert_eq!(webhook.path, "/ala");
+	assert_eq!(webhook.headers[headers::WEBHOOK_EVENT], "created");
+	assert_eq!(webhook.headers[CONTENT_TYPE], "application/json");
+	let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"s3cret").unwrap();
+	mac.update(&webhook.body);
+	assert_eq!(
+		webhook.headers[headers::WEBHOOK_SIGNATURE].to_str()?,
+		format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+	);
+	let payload: serde_json::Value = serde_json::from_slice(&webhook.body)?;
+	assert_eq!(
+		payload["delivery"].as_str(),
+		webhook.headers[headers::WEBHOOK_DELIVERY].to_str().ok()
+	);
+	assert_eq!(payload["webhook"], ala_hook.uuid.to_string());
+	assert_eq!(payload["event"]["type"], "created");
+	assert_eq!(payload["event"]["uuid"], uuid.to_string());
+	assert_eq!(payload["event"]["entry"]["filename"], "sample_file");
+
+	let request = download_request(&uuid, &[])?;
+	let mut response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::OK);
+	to_bytes(response.body_mut()).await?;
+
+	let webhook = next_webhook(&mut received).await?;
+	assert_eq!(webhook.path, "/ala");
+	assert_eq!(webhook.headers[headers::WEBHOOK_EVENT], "downloaded");
+
+	debug!("The cleanup task removes the used up entry");
+	let webhook = next_webhook(&mut received).await?;
+	assert_eq!(webhook.path, "/global");
+	assert_eq!(webhook.headers[headers::WEBHOOK_EVENT], "used_up");
+	let payload: serde_json::Value = serde_json::from_slice(&webhook.body)?;
+	assert_eq!(payload["event"]["uuid"], uuid.to_string());
+	assert!(payload["event"]["entry"].is_null());
+	assert_eq!(
+		webhook.headers[headers::WEBHOOK_SIGNATURE].to_str()?,
+		webhooks::sign(&global_secret, &webhook.body)
+	);
+
+	debug!("Failed deliveries are retried and end up in the dead letters");
+	let mut response = test_server
+		.process_request(register(
+			&ala_cookie,
+			&[
+				("url", format!("http://{receiver_addr}/fail")),
+				("events", String::from("deleted")),
+			],
+		)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	let failing_hook: WebhookModel = serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+
+	let uuid = test_server
+		.upload(
+			&random_string(100),
+			&[
+				(headers::DOWNLOAD_COUNT, "infinite"),
+				("Cookie", &ala_cookie),
+			],
+		)
+		.await?;
+	assert_eq!(next_webhook(&mut received).await?.path, "/ala");
+
+	let request = Request::builder()
+		.uri(format!("/api/delete/{uuid}"))
+		.method(Method::DELETE)
+		.header("Cookie", &ala_cookie)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+	let first_attempt = next_webhook(&mut received).await?;
+	let second_attempt = next_webhook(&mut received).await?;
+	assert_eq!(first_attempt.path, "/fail");
+	assert_eq!(
+		first_attempt.headers[headers::WEBHOOK_DELIVERY],
+		second_attempt.headers[headers::WEBHOOK_DELIVERY]
+	);
+
+	let dead_letters = |cookie: &str| {
+		Request::builder()
+			.uri(format!("/api/webhooks/{}/dead_letters", failing_hook.uuid))
+			.method(Method::GET)
+			.header("Cookie", cookie)
+			.body(Body::empty())
+	};
+	let mut dead_letters_list = serde_json::Value::Null;
+	for _ in 0..50 {
+		let mut response = test_server
+			.process_request(dead_letters(&ala_cookie)?)
+			.await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		dead_letters_list = serde_json::from_slice(&to_bytes(response.body_mut()).await?)?;
+		if dead_letters_list
+			.as_array()
+			.is_some_and(|list| !list.is_empty())
+		{
+			break;
+		}
+		tokio::time::sleep(Duration::from_millis(20)).await;
+	}
+	assert_eq!(dead_letters_list[0]["event"], "deleted");
+	assert_eq!(dead_letters_list[0]["file_uuid"], uuid.to_string());
+	assert_eq!(dead_letters_list[0]["attempts"], 2);
+	assert_eq!(
+		dead_letters_list[0]["error"],
+		"Receiver responded with 500 Internal Server Error"
+	);
+
+	debug!("Dead letters are persisted");
+	test_server.db_handle.save().await?;
+	let persisted: serde_json::Value = serde_json::from_slice(&std::fs::read(
+		test_server.db_dir.path().join(DB_DIR).join("webhooks"),
+	)?)?;
+	assert_eq!(
+		persisted["dead_letters"][0]["delivery"],
+		dead_letters_list[0]["delivery"]
+	);
+
+	debug!("Only the owner and admins manage a webhook");
+	let request = Request::builder()
+		.uri(format!("/api/webhooks/{}", global_hook.uuid))
+		.method(Method::DELETE)
+		.header("Cookie", &ala_cookie)
+		.body(Body::empty())?;
+	let response = test_server.process_request(request).await?;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+	for hook in [&ala_hook, &failing_hook] {
+		let request = Request::builder()
+			.uri(format!("/api/webhooks/{}", hook.uuid))
+			.method(Method::DELETE)
+			.header("Cookie", &ala_cookie)
+			.body(Body::empty())?;
+		let response = test_server.process_request(request).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	}
+	let response = test_server
+		.process_request(dead_letters(&admin_cookie)?)
+		.await?;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	Ok(())
+}
@@ -46,3 +46,27 @@ export async function loadUser() {
 		return null;
 	}
 }
+
+/**
+ * Exchanges a file password for a short-lived unlock token
+ *
+ * @param {string} uuid - file uuid
+ * @param {string} password
+ * @returns {Promise<Types.UnlockResponse|Types.ErrorJsonBody>}
+ */
+export async function unlockFile(uuid, password) {
+	const formData = new FormData();
+	formData.append("password", password);
+
+	try {
+		let response = await fetch(`${API_SERVER}/api/download/${uuid}`, {
+			method: "POST",
+			credentials: "include",
+			body: formData,
+		});
+		return await response.json();
+	} catch (ex) {
+		console.error(ex);
+		return {status: 0, message: "Failed to unlock the file"};
+	}
+}
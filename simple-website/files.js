@@ -51,11 +51,17 @@ function displayFiles(files) {
 				PasswordInput.getById("passwordInput").prompt(fileLinkEl);
 			});
 
-			fileLinkEl.addEventListener("passwordInputDone", (/** @type {CustomEvent} */ event) => {
+			fileLinkEl.addEventListener("passwordInputDone", async (/** @type {CustomEvent} */ event) => {
 				let password = event.detail.password;
+				let response = await Api.unlockFile(file.uuid, password);
+				if (!("unlock_token" in response)) {
+					InfoMsgBox.getById("infoBox").displayFailure(response.message);
+					return;
+				}
 				fileLinkEl.href =
-					`${API_SERVER}/api/download/${file.uuid}?password=${encodeURIComponent(password)}`;
+					`${API_SERVER}/api/download/${file.uuid}?unlock_token=${response.unlock_token}`;
 				fileLinkEl.click();
+				fileLinkEl.href = "javascript:void(0);";
 			});
 		}
 
@@ -76,6 +82,8 @@ function displayFiles(files) {
 
 		if (file.visibility === "Private") {
 			fileEntryEl.className += " fileEntryPrivate";
+		} else if (file.visibility === "Unlisted") {
+			fileEntryEl.className += " fileEntryUnlisted";
 		}
 
 		let statsEl = document.createElement("div");
@@ -22,12 +22,18 @@
  * @property {!string} uploader_uuid -
  * 
  * @property {!number} download_count -
+ * @property {?number} download_limit - null if unlimited
+ * @property {?number} downloads_remaining - null if unlimited
+ * @property {?number} size - bytes, null while the file is being uploaded
  *
- * @property {!('Public'|'Private')} visibility -
+ * @property {!('Public'|'Unlisted'|'Private')} visibility -
  * @property {!boolean} has_password -
+ * @property {?string[]} shared_with - account uuids, only present for the uploader
+ * @property {?string[]} shared_with_groups - group uuids, only present for the uploader
  *
  * @property {?Duration} lifetime -
  * @property {!Date} upload_date -
+ * @property {?string} expires_at - RFC 3339 date, null if the lifetime is unlimited
 */
 
 /**
@@ -43,6 +49,13 @@
  * @property {number} nanos -
  */
 
+/**
+ * @typedef UnlockResponse
+ * @type {object}
+ * @property {string} unlock_token - token to pass as the `unlock_token` query parameter
+ * @property {number} valid_for - seconds until the token expires
+ */
+
 /**
  * @typedef CreateAccountResponse
  * @type {object}
@@ -50,6 +50,10 @@ function submitUploadForm(event) {
 	const lifetime = submitFormEl["lifetime-select"].value;
 	/** @type string */
 	const password = submitFormEl["password"].value;
+	/** @type string */
+	const shareWith = submitFormEl["share-with"].value;
+	/** @type string */
+	const shareWithGroups = submitFormEl["share-with-groups"].value;
 
 	const tabsView = TabsView.getById("uploadTypeTabs");
 
@@ -112,6 +116,18 @@ function submitUploadForm(event) {
 	if (password.trim().length !== 0) {
 		request.setRequestHeader("aqa-password", encodeURIComponent(password));
 	}
+	const shareWithAccounts = shareWith.split(",")
+		.map((account) => account.trim())
+		.filter((account) => account.length !== 0);
+	if (shareWithAccounts.length !== 0) {
+		request.setRequestHeader("aqa-share-with", shareWithAccounts.map(encodeURIComponent).join(","));
+	}
+	const shareWithGroupNames = shareWithGroups.split(",")
+		.map((group) => group.trim())
+		.filter((group) => group.length !== 0);
+	if (shareWithGroupNames.length !== 0) {
+		request.setRequestHeader("aqa-share-with-groups", shareWithGroupNames.map(encodeURIComponent).join(","));
+	}
 	resultBox.hide();
 	request.send(formData);
 }